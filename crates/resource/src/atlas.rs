@@ -0,0 +1,493 @@
+// 图集打包
+//
+// 把很多张小贴图打包到若干张大贴图(页)上, 每页是 texture array 的一层.
+// 每张小贴图周围留 padding, 并把边缘像素向外延伸(extrude)到 padding 里,
+// 这样采样和生成 mipmap 时, 不会混合到相邻小贴图的颜色.
+//
+// 打包结果的查找表: 材质 id ---> (层, uv 矩形), 以 toml 保存, 渲染时读取.
+// 查找表里还记着打包时每张贴图的内容哈希, 加载时和注册表比对, 贴图改过就要重新打包.
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::*;
+use image::{imageops, ImageBuffer, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use wgpu::TextureFormat;
+
+use crate::{pack::PackRegistry, tools, ImageMipMap};
+
+pub const TABLE_FILE_NAME: &str = "atlas.toml";
+
+#[derive(Debug, Clone)]
+pub struct AtlasConfig {
+    pub page_size: u32,
+    // 小贴图之间, 以及和页边缘之间的间隔 (单边)
+    pub padding: u32,
+    // 是否把边缘像素延伸到 padding 里, 否则 padding 是透明的
+    pub extrude: bool,
+    // 每页的 mipmap 层数, 含 padding 的格子的位置和大小按 2^(层数-1) 对齐,
+    // 小贴图本身在格子里偏移 padding, 只有 padding 是对齐的倍数时才也是对齐的
+    pub mip_level_count: u32,
+}
+
+impl Default for AtlasConfig {
+    fn default() -> Self {
+        Self {
+            page_size: 2048,
+            padding: 4,
+            extrude: true,
+            mip_level_count: 3,
+        }
+    }
+}
+
+impl AtlasConfig {
+    fn align(&self) -> u32 {
+        1 << self.mip_level_count.saturating_sub(1)
+    }
+}
+
+// 像素坐标, 不含 padding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtlasRect {
+    pub layer: u32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl AtlasRect {
+    // [u0, v0, u1, v1]
+    pub fn uv(&self, page_size: u32) -> [f32; 4] {
+        let s = page_size as f32;
+        [
+            self.x as f32 / s,
+            self.y as f32 / s,
+            (self.x + self.width) as f32 / s,
+            (self.y + self.height) as f32 / s,
+        ]
+    }
+}
+
+#[derive(Debug)]
+pub struct Atlas {
+    pub config: AtlasConfig,
+    pub pages: Vec<RgbaImage>,
+    // 和输入的顺序一致
+    pub rects: Vec<AtlasRect>,
+}
+
+struct Shelf {
+    y: u32,
+    height: u32,
+    cursor: u32,
+}
+
+#[derive(Default)]
+struct PageSpace {
+    shelves: Vec<Shelf>,
+    bottom: u32,
+}
+
+fn align_up(v: u32, align: u32) -> u32 {
+    v.div_ceil(align) * align
+}
+
+// 货架算法, 返回每个 (宽, 高) 的位置, 位置是含 padding 的格子的左上角
+pub fn pack_rects(config: &AtlasConfig, sizes: &[(u32, u32)]) -> Result<Vec<AtlasRect>> {
+    let align = config.align();
+    let cell = |(w, h): (u32, u32)| {
+        (
+            align_up(w + config.padding * 2, align),
+            align_up(h + config.padding * 2, align),
+        )
+    };
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| {
+        let (w, h) = cell(sizes[i]);
+        (std::cmp::Reverse(h), std::cmp::Reverse(w), i)
+    });
+
+    let mut pages: Vec<PageSpace> = Vec::new();
+    let mut rects = vec![None; sizes.len()];
+    for i in order {
+        let (cw, ch) = cell(sizes[i]);
+        if cw > config.page_size || ch > config.page_size {
+            bail!(
+                "第 {} 张贴图 {:?} 加上 padding 后比页({})还大",
+                i,
+                sizes[i],
+                config.page_size
+            );
+        }
+        let mut placed = None;
+        'pages: for (layer, page) in pages.iter_mut().enumerate() {
+            for shelf in page.shelves.iter_mut() {
+                if ch <= shelf.height && shelf.cursor + cw <= config.page_size {
+                    placed = Some((layer, shelf.cursor, shelf.y));
+                    shelf.cursor += cw;
+                    break 'pages;
+                }
+            }
+            if page.bottom + ch <= config.page_size {
+                placed = Some((layer, 0, page.bottom));
+                page.shelves.push(Shelf {
+                    y: page.bottom,
+                    height: ch,
+                    cursor: cw,
+                });
+                page.bottom += ch;
+                break;
+            }
+        }
+        let (layer, x, y) = match placed {
+            Some(p) => p,
+            None => {
+                let mut page = PageSpace::default();
+                page.shelves.push(Shelf {
+                    y: 0,
+                    height: ch,
+                    cursor: cw,
+                });
+                page.bottom = ch;
+                pages.push(page);
+                (pages.len() - 1, 0, 0)
+            }
+        };
+        rects[i] = Some(AtlasRect {
+            layer: layer as u32,
+            x: x + config.padding,
+            y: y + config.padding,
+            width: sizes[i].0,
+            height: sizes[i].1,
+        });
+    }
+    Ok(rects.into_iter().map(|r| r.unwrap()).collect())
+}
+
+impl Atlas {
+    pub fn build(config: AtlasConfig, images: &[RgbaImage]) -> Result<Self> {
+        let sizes: Vec<_> = images.iter().map(|i| i.dimensions()).collect();
+        let rects = pack_rects(&config, &sizes)?;
+        let page_count = rects.iter().map(|r| r.layer + 1).max().unwrap_or(0);
+        let mut pages: Vec<RgbaImage> = (0..page_count)
+            .map(|_| ImageBuffer::new(config.page_size, config.page_size))
+            .collect();
+        for (img, rect) in images.iter().zip(rects.iter()) {
+            let page = &mut pages[rect.layer as usize];
+            imageops::replace(page, img, rect.x as i64, rect.y as i64);
+            if config.extrude {
+                extrude(page, rect, config.padding);
+            }
+        }
+        Ok(Self {
+            config,
+            pages,
+            rects,
+        })
+    }
+
+    pub fn page_mip_maps(&self, is_srgb: bool) -> Result<Vec<ImageMipMap>> {
+        let format = if is_srgb {
+            TextureFormat::Rgba8UnormSrgb
+        } else {
+            TextureFormat::Rgba8Unorm
+        };
+        self.pages
+            .iter()
+            .map(|p| {
+                let img = crate::Image {
+                    data: p.as_raw().clone(),
+                    width: p.width(),
+                    height: p.height(),
+                    format,
+                };
+                let config = tools::MipMapConfig {
+                    mip_level_count: Some(self.config.mip_level_count),
+                    ..Default::default()
+                };
+                tools::generate_rgba8_mip_map(img, &config)
+            })
+            .collect()
+    }
+}
+
+// 把 rect 的边缘像素向外复制 padding 个像素, 包括四个角
+fn extrude(page: &mut RgbaImage, rect: &AtlasRect, padding: u32) {
+    let x0 = rect.x as i64;
+    let y0 = rect.y as i64;
+    let x1 = x0 + rect.width as i64 - 1;
+    let y1 = y0 + rect.height as i64 - 1;
+    let p = padding as i64;
+    for y in (y0 - p)..=(y1 + p) {
+        for x in (x0 - p)..=(x1 + p) {
+            if (x0..=x1).contains(&x) && (y0..=y1).contains(&y) {
+                continue;
+            }
+            let sx = x.clamp(x0, x1) as u32;
+            let sy = y.clamp(y0, y1) as u32;
+            let c: Rgba<u8> = *page.get_pixel(sx, sy);
+            page.put_pixel(x as u32, y as u32, c);
+        }
+    }
+}
+
+// 保存到文件的查找表
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AtlasTable {
+    pub page_size: u32,
+    pub page_count: u32,
+    pub mip_level_count: u32,
+    // 完整的材质 id ---> 位置
+    pub materials: BTreeMap<String, AtlasTableEntry>,
+    // 完整的贴图 id ---> 源文件内容的哈希 (16 位十六进制), 旧的查找表没有, 读出来是空的
+    #[serde(default)]
+    pub textures: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AtlasTableEntry {
+    pub layer: u32,
+    // [u0, v0, u1, v1]
+    pub uv: [f32; 4],
+    // 用的贴图 id
+    #[serde(default)]
+    pub texture: String,
+}
+
+impl AtlasTable {
+    pub fn page_dir(dir: impl AsRef<Path>, layer: u32) -> PathBuf {
+        dir.as_ref().join(format!("page_{}", layer))
+    }
+
+    pub fn from_path(dir: impl AsRef<Path>) -> Result<Self> {
+        let path = dir.as_ref().join(TABLE_FILE_NAME);
+        let text = fs::read_to_string(&path).with_context(|| format!("读取 {:?} 失败", path))?;
+        let table = toml::from_str(&text).with_context(|| format!("解析 {:?} 失败", path))?;
+        Ok(table)
+    }
+
+    pub fn write_to_path(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let path = dir.as_ref().join(TABLE_FILE_NAME);
+        fs::write(&path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // 检查图集是不是按注册表现在的贴图和材质打包的, 不一致时报错.
+    // 资源包里的贴图打包时已经转换过, 和源文件的内容对不上, 只比较列表
+    pub fn check_registry(&self, registry: &PackRegistry) -> Result<()> {
+        for t in registry.textures.iter() {
+            let hash = self
+                .textures
+                .get(&t.id)
+                .ok_or_else(|| anyhow!("图集里没有贴图 {:?}", t.id))?;
+            if registry.bundle_key(&t.path).is_none() && *hash != source_hash(&t.path)? {
+                bail!("贴图 {:?} 在打包图集后改过", t.id);
+            }
+        }
+        if self.textures.len() != registry.textures.len() {
+            bail!("图集里有注册表中没有的贴图");
+        }
+        for m in registry.materials.iter() {
+            let e = self
+                .materials
+                .get(&m.id)
+                .ok_or_else(|| anyhow!("图集里没有材质 {:?}", m.id))?;
+            let texture = &registry.textures.get(m.texture).unwrap().id;
+            if e.texture != *texture {
+                bail!("材质 {:?} 现在用贴图 {:?}, 图集里是 {:?}", m.id, texture, e.texture);
+            }
+        }
+        Ok(())
+    }
+}
+
+// 贴图源文件内容的哈希, 目录形式的 mipmap 按文件名顺序把所有文件算进去.
+// 用 FNV-1a, 不依赖 std 的哈希算法, 换了编译器结果也不变
+pub fn source_hash(path: impl AsRef<Path>) -> Result<String> {
+    fn fnv1a(hash: &mut u64, bytes: &[u8]) {
+        for b in bytes {
+            *hash ^= *b as u64;
+            *hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    let path = path.as_ref();
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    if path.is_dir() {
+        let mut files = Vec::new();
+        for e in fs::read_dir(path).with_context(|| format!("读取 {:?} 失败", path))? {
+            files.push(e?.path());
+        }
+        files.sort();
+        for p in files {
+            let name = p.file_name().unwrap().to_string_lossy();
+            fnv1a(&mut hash, name.as_bytes());
+            fnv1a(&mut hash, &fs::read(&p).with_context(|| format!("读取 {:?} 失败", p))?);
+        }
+    } else {
+        fnv1a(&mut hash, &fs::read(path).with_context(|| format!("读取 {:?} 失败", path))?);
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+// 按注册表打包所有材质用到的贴图, 写到 dir 下: 每页一个 mipmap 目录 + 查找表
+pub fn build_from_registry(
+    registry: &PackRegistry,
+    config: AtlasConfig,
+    dir: impl AsRef<Path>,
+) -> Result<AtlasTable> {
+    let dir = dir.as_ref();
+    if registry.textures.is_empty() {
+        bail!("没有任何贴图");
+    }
+    let is_srgb = registry.textures.get(0).unwrap().srgb;
+    let mut images = Vec::new();
+    let mut textures = BTreeMap::new();
+    for t in registry.textures.iter() {
+        if t.srgb != is_srgb {
+            bail!("贴图 {:?} 的 srgb 设置与其他贴图不一致", t.id);
+        }
+        // 图集按 rgba8 拼, 压缩过的先解压
+        let mip = ImageMipMap::load(&t.path, t.srgb)
+            .and_then(|m| m.decompress())
+            .with_context(|| format!("读取贴图 {:?} 失败", t.id))?;
+        let level0 = mip.data.into_iter().next().unwrap();
+        images.push(ImageBuffer::from_raw(mip.width, mip.height, level0).unwrap());
+        textures.insert(t.id.clone(), source_hash(&t.path)?);
+    }
+    let atlas = Atlas::build(config, &images)?;
+
+    fs::create_dir_all(dir)?;
+    for (i, page) in atlas.page_mip_maps(is_srgb)?.into_iter().enumerate() {
+        page.write_to_path(AtlasTable::page_dir(dir, i as u32))?;
+    }
+    let mut materials = BTreeMap::new();
+    for m in registry.materials.iter() {
+        let rect = atlas.rects[m.texture as usize];
+        let entry = AtlasTableEntry {
+            layer: rect.layer,
+            uv: rect.uv(atlas.config.page_size),
+            texture: registry.textures.get(m.texture).unwrap().id.clone(),
+        };
+        materials.insert(m.id.clone(), entry);
+    }
+    let table = AtlasTable {
+        page_size: atlas.config.page_size,
+        page_count: atlas.pages.len() as u32,
+        mip_level_count: atlas.config.mip_level_count,
+        materials,
+        textures,
+    };
+    table.write_to_path(dir)?;
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlaps(a: &AtlasRect, b: &AtlasRect, pad: u32) -> bool {
+        a.layer == b.layer
+            && a.x < b.x + b.width + pad * 2
+            && b.x < a.x + a.width + pad * 2
+            && a.y < b.y + b.height + pad * 2
+            && b.y < a.y + a.height + pad * 2
+    }
+
+    #[test]
+    fn pack_no_overlap() {
+        let config = AtlasConfig {
+            page_size: 256,
+            padding: 2,
+            extrude: true,
+            mip_level_count: 3,
+        };
+        let sizes: Vec<_> = (0..80)
+            .map(|i| (16 + (i % 3) * 16, 16 + (i % 4) * 8))
+            .collect();
+        let rects = pack_rects(&config, &sizes).unwrap();
+        assert!(rects.iter().any(|r| r.layer > 0));
+        for (i, a) in rects.iter().enumerate() {
+            assert_eq!((a.width, a.height), sizes[i]);
+            assert!(a.x >= 2 && a.x + a.width + 2 <= 256);
+            assert!(a.y >= 2 && a.y + a.height + 2 <= 256);
+            for b in rects[i + 1..].iter() {
+                assert!(!overlaps(a, b, 1));
+            }
+        }
+    }
+
+    #[test]
+    fn too_large() {
+        let config = AtlasConfig {
+            page_size: 64,
+            ..Default::default()
+        };
+        assert!(pack_rects(&config, &[(64, 64)]).is_err());
+    }
+
+    #[test]
+    fn extrude_edges() {
+        let config = AtlasConfig {
+            page_size: 32,
+            padding: 2,
+            extrude: true,
+            mip_level_count: 1,
+        };
+        let mut img = RgbaImage::new(4, 4);
+        for (x, y, p) in img.enumerate_pixels_mut() {
+            *p = Rgba([x as u8 * 10, y as u8 * 10, 0, 255]);
+        }
+        let atlas = Atlas::build(config, &[img]).unwrap();
+        let r = atlas.rects[0];
+        let page = &atlas.pages[0];
+        assert_eq!(page.get_pixel(r.x - 2, r.y - 2), &Rgba([0, 0, 0, 255]));
+        assert_eq!(page.get_pixel(r.x + 5, r.y + 1), &Rgba([30, 10, 0, 255]));
+        assert_eq!(page.get_pixel(r.x + 1, r.y + 5), &Rgba([10, 30, 0, 255]));
+    }
+
+    #[test]
+    fn stale_table() {
+        let root = std::env::temp_dir()
+            .join(format!("resource_atlas_test_{}", std::process::id()))
+            .join("stale");
+        let _ = fs::remove_dir_all(&root);
+        let pack = root.join("packs/base");
+        fs::create_dir_all(pack.join("stone")).unwrap();
+        fs::create_dir_all(pack.join("dirt")).unwrap();
+        fs::write(
+            pack.join("manifest.toml"),
+            r#"
+            [pack]
+            id = "base"
+            [textures]
+            stone = { path = "stone" }
+            dirt = { path = "dirt" }
+            [materials]
+            stone = { texture = "stone" }
+            "#,
+        )
+        .unwrap();
+        RgbaImage::new(16, 16).save(pack.join("stone/0.png")).unwrap();
+        RgbaImage::new(16, 16).save(pack.join("dirt/0.png")).unwrap();
+        let out = root.join("atlas");
+        let registry = PackRegistry::load_dir(root.join("packs")).unwrap();
+        let config = AtlasConfig {
+            page_size: 64,
+            ..Default::default()
+        };
+        build_from_registry(&registry, config, &out).unwrap();
+        let table = AtlasTable::from_path(&out).unwrap();
+        table.check_registry(&registry).unwrap();
+
+        // 贴图内容变了
+        RgbaImage::from_pixel(16, 16, Rgba([255; 4]))
+            .save(pack.join("dirt/0.png"))
+            .unwrap();
+        assert!(table.check_registry(&registry).is_err());
+    }
+}
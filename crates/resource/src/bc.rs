@@ -0,0 +1,633 @@
+// BC 块压缩 (BC1, BC3, BC7) 的 CPU 编码和解码
+//
+// 编码是离线用的, 追求简单而不是最好的质量:
+// - BC1/BC3 的颜色: 主成分方向上取端点, 再选最近的调色板颜色.
+// - BC3 的 alpha: 最大最小值做端点, 8 个插值.
+// - BC7: 只用 mode 6 (单分区, RGBA 7777 + p-bit 端点, 4 bit 索引).
+// 解码用于显卡不支持 BC 时退回 RGBA8, BC7 只能解码 mode 6, 也就是只能解码我们自己编码的.
+// 其他工具编码的 BC7 一般会用到别的 mode, 解压前整份数据先检查一遍, 有别的 mode 就直接报错,
+// 这种贴图要在不支持 BC 的设备上用, 得先用 `resource compress` 从 mipmap 目录重新编码.
+use anyhow::*;
+use wgpu::TextureFormat;
+
+use crate::ImageMipMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BcFormat {
+    Bc1,
+    Bc3,
+    Bc7,
+}
+
+impl BcFormat {
+    pub fn texture_format(&self, is_srgb: bool) -> TextureFormat {
+        match (self, is_srgb) {
+            (BcFormat::Bc1, false) => TextureFormat::Bc1RgbaUnorm,
+            (BcFormat::Bc1, true) => TextureFormat::Bc1RgbaUnormSrgb,
+            (BcFormat::Bc3, false) => TextureFormat::Bc3RgbaUnorm,
+            (BcFormat::Bc3, true) => TextureFormat::Bc3RgbaUnormSrgb,
+            (BcFormat::Bc7, false) => TextureFormat::Bc7RgbaUnorm,
+            (BcFormat::Bc7, true) => TextureFormat::Bc7RgbaUnormSrgb,
+        }
+    }
+
+    pub fn from_texture_format(format: TextureFormat) -> Option<Self> {
+        match format {
+            TextureFormat::Bc1RgbaUnorm | TextureFormat::Bc1RgbaUnormSrgb => Some(BcFormat::Bc1),
+            TextureFormat::Bc3RgbaUnorm | TextureFormat::Bc3RgbaUnormSrgb => Some(BcFormat::Bc3),
+            TextureFormat::Bc7RgbaUnorm | TextureFormat::Bc7RgbaUnormSrgb => Some(BcFormat::Bc7),
+            _ => None,
+        }
+    }
+
+    // 每个 4x4 块的字节数
+    pub fn block_bytes(&self) -> usize {
+        match self {
+            BcFormat::Bc1 => 8,
+            BcFormat::Bc3 | BcFormat::Bc7 => 16,
+        }
+    }
+}
+
+impl std::str::FromStr for BcFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bc1" => Ok(BcFormat::Bc1),
+            "bc3" => Ok(BcFormat::Bc3),
+            "bc7" => Ok(BcFormat::Bc7),
+            _ => bail!("未知的压缩格式 {:?}, 可选: bc1, bc3, bc7", s),
+        }
+    }
+}
+
+type Block = [[u8; 4]; 16];
+
+// 取出 (bx, by) 处的 4x4 块, 超出图片的部分按边缘像素填充
+fn read_block(data: &[u8], width: u32, height: u32, bx: u32, by: u32) -> Block {
+    let mut block = [[0u8; 4]; 16];
+    for (i, p) in block.iter_mut().enumerate() {
+        let x = (bx * 4 + i as u32 % 4).min(width - 1);
+        let y = (by * 4 + i as u32 / 4).min(height - 1);
+        let o = ((y * width + x) * 4) as usize;
+        p.copy_from_slice(&data[o..o + 4]);
+    }
+    block
+}
+
+fn write_block(data: &mut [u8], width: u32, height: u32, bx: u32, by: u32, block: &Block) {
+    for (i, p) in block.iter().enumerate() {
+        let x = bx * 4 + i as u32 % 4;
+        let y = by * 4 + i as u32 / 4;
+        if x < width && y < height {
+            let o = ((y * width + x) * 4) as usize;
+            data[o..o + 4].copy_from_slice(p);
+        }
+    }
+}
+
+fn blocks(width: u32, height: u32) -> (u32, u32) {
+    (width.div_ceil(4), height.div_ceil(4))
+}
+
+pub fn compress(data: &[u8], width: u32, height: u32, format: BcFormat) -> Vec<u8> {
+    let (bw, bh) = blocks(width, height);
+    let mut out = Vec::with_capacity((bw * bh) as usize * format.block_bytes());
+    for by in 0..bh {
+        for bx in 0..bw {
+            let block = read_block(data, width, height, bx, by);
+            match format {
+                BcFormat::Bc1 => out.extend_from_slice(&encode_bc1(&block)),
+                BcFormat::Bc3 => {
+                    out.extend_from_slice(&encode_alpha(&block));
+                    out.extend_from_slice(&encode_bc1(&block));
+                }
+                BcFormat::Bc7 => out.extend_from_slice(&encode_bc7_mode6(&block)),
+            }
+        }
+    }
+    out
+}
+
+pub fn decompress(data: &[u8], width: u32, height: u32, format: BcFormat) -> Result<Vec<u8>> {
+    let (bw, bh) = blocks(width, height);
+    let size = format.block_bytes();
+    if data.len() != (bw * bh) as usize * size {
+        bail!(
+            "{}x{} 的 {:?} 数据应该是 {} 字节, 实际是 {}",
+            width,
+            height,
+            format,
+            (bw * bh) as usize * size,
+            data.len()
+        );
+    }
+    if format == BcFormat::Bc7 {
+        check_bc7_modes(data)?;
+    }
+    let mut out = vec![0u8; (width * height * 4) as usize];
+    for (i, src) in data.chunks_exact(size).enumerate() {
+        let block = match format {
+            BcFormat::Bc1 => decode_bc1(src[..8].try_into().unwrap(), false),
+            BcFormat::Bc3 => {
+                let mut b = decode_bc1(src[8..].try_into().unwrap(), true);
+                let alpha = decode_alpha(src[..8].try_into().unwrap());
+                for (p, a) in b.iter_mut().zip(alpha) {
+                    p[3] = a;
+                }
+                b
+            }
+            BcFormat::Bc7 => decode_bc7(src.try_into().unwrap()),
+        };
+        let i = i as u32;
+        write_block(&mut out, width, height, i % bw, i / bw, &block);
+    }
+    Ok(out)
+}
+
+// 主成分方向, 幂迭代几次就够了
+fn principal_axis<const N: usize>(points: &[[f32; N]; 16]) -> ([f32; N], [f32; N]) {
+    let mut mean = [0f32; N];
+    for p in points {
+        for c in 0..N {
+            mean[c] += p[c] / 16.0;
+        }
+    }
+    let mut cov = [[0f32; N]; N];
+    for p in points {
+        for i in 0..N {
+            for j in 0..N {
+                cov[i][j] += (p[i] - mean[i]) * (p[j] - mean[j]);
+            }
+        }
+    }
+    let mut axis = [1f32; N];
+    for _ in 0..8 {
+        let mut next = [0f32; N];
+        for i in 0..N {
+            for j in 0..N {
+                next[i] += cov[i][j] * axis[j];
+            }
+        }
+        let len = next.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if len < 1e-6 {
+            break;
+        }
+        axis = next.map(|v| v / len);
+    }
+    (mean, axis)
+}
+
+// 沿主成分方向的两个端点
+fn endpoints<const N: usize>(points: &[[f32; N]; 16]) -> ([f32; N], [f32; N]) {
+    let (mean, axis) = principal_axis(points);
+    let proj = |p: &[f32; N]| (0..N).map(|c| (p[c] - mean[c]) * axis[c]).sum::<f32>();
+    let (mut lo, mut hi) = (f32::MAX, f32::MIN);
+    for p in points {
+        let t = proj(p);
+        lo = lo.min(t);
+        hi = hi.max(t);
+    }
+    let at = |t: f32| {
+        let mut e = [0f32; N];
+        for c in 0..N {
+            e[c] = (mean[c] + axis[c] * t).clamp(0.0, 255.0);
+        }
+        e
+    };
+    (at(lo), at(hi))
+}
+
+fn nearest<const N: usize>(p: &[u8; 4], palette: &[[u8; 4]]) -> usize {
+    let mut best = (u32::MAX, 0);
+    for (i, c) in palette.iter().enumerate() {
+        let d: u32 = (0..N)
+            .map(|k| (p[k] as i32 - c[k] as i32).pow(2) as u32)
+            .sum();
+        if d < best.0 {
+            best = (d, i);
+        }
+    }
+    best.1
+}
+
+fn to_565(c: [f32; 3]) -> u16 {
+    let r = (c[0] * 31.0 / 255.0).round() as u16;
+    let g = (c[1] * 63.0 / 255.0).round() as u16;
+    let b = (c[2] * 31.0 / 255.0).round() as u16;
+    (r << 11) | (g << 5) | b
+}
+
+fn from_565(c: u16) -> [u8; 4] {
+    let r = ((c >> 11) & 31) as u32;
+    let g = ((c >> 5) & 63) as u32;
+    let b = (c & 31) as u32;
+    [
+        (r * 255 / 31) as u8,
+        (g * 255 / 63) as u8,
+        (b * 255 / 31) as u8,
+        255,
+    ]
+}
+
+fn bc1_palette(c0: u16, c1: u16, four_color: bool) -> [[u8; 4]; 4] {
+    let a = from_565(c0);
+    let b = from_565(c1);
+    let mix = |wa: u32, wb: u32, d: u32| {
+        let mut c = [0u8; 4];
+        for i in 0..3 {
+            c[i] = ((a[i] as u32 * wa + b[i] as u32 * wb) / d) as u8;
+        }
+        c[3] = 255;
+        c
+    };
+    if four_color {
+        [a, b, mix(2, 1, 3), mix(1, 2, 3)]
+    } else {
+        [a, b, mix(1, 1, 2), [0, 0, 0, 0]]
+    }
+}
+
+// 总是用 4 色模式, 这样 BC3 里也能直接用
+fn encode_bc1(block: &Block) -> [u8; 8] {
+    let points = block.map(|p| [p[0] as f32, p[1] as f32, p[2] as f32]);
+    let (e0, e1) = endpoints(&points);
+    let (mut c0, mut c1) = (to_565(e1), to_565(e0));
+    if c0 < c1 {
+        std::mem::swap(&mut c0, &mut c1);
+    }
+    let mut indices = 0u32;
+    if c0 != c1 {
+        let palette = bc1_palette(c0, c1, true);
+        for (i, p) in block.iter().enumerate() {
+            indices |= (nearest::<3>(p, &palette) as u32) << (i * 2);
+        }
+    }
+    let mut out = [0u8; 8];
+    out[0..2].copy_from_slice(&c0.to_le_bytes());
+    out[2..4].copy_from_slice(&c1.to_le_bytes());
+    out[4..8].copy_from_slice(&indices.to_le_bytes());
+    out
+}
+
+// is_bc3 时颜色块总是 4 色模式
+fn decode_bc1(src: &[u8; 8], is_bc3: bool) -> Block {
+    let c0 = u16::from_le_bytes([src[0], src[1]]);
+    let c1 = u16::from_le_bytes([src[2], src[3]]);
+    let indices = u32::from_le_bytes([src[4], src[5], src[6], src[7]]);
+    let palette = bc1_palette(c0, c1, is_bc3 || c0 > c1);
+    let mut block = [[0u8; 4]; 16];
+    for (i, p) in block.iter_mut().enumerate() {
+        *p = palette[((indices >> (i * 2)) & 3) as usize];
+    }
+    block
+}
+
+fn alpha_palette(a0: u8, a1: u8) -> [u8; 8] {
+    let (a, b) = (a0 as u32, a1 as u32);
+    let mut p = [a0, a1, 0, 0, 0, 0, 0, 0];
+    if a0 > a1 {
+        for i in 1..7u32 {
+            p[i as usize + 1] = (((7 - i) * a + i * b) / 7) as u8;
+        }
+    } else {
+        for i in 1..5u32 {
+            p[i as usize + 1] = (((5 - i) * a + i * b) / 5) as u8;
+        }
+        p[6] = 0;
+        p[7] = 255;
+    }
+    p
+}
+
+fn encode_alpha(block: &Block) -> [u8; 8] {
+    let a0 = block.iter().map(|p| p[3]).max().unwrap();
+    let a1 = block.iter().map(|p| p[3]).min().unwrap();
+    let palette = alpha_palette(a0, a1);
+    let mut bits = 0u64;
+    for (i, p) in block.iter().enumerate() {
+        let idx = (0..8)
+            .min_by_key(|&k| (palette[k] as i32 - p[3] as i32).abs())
+            .unwrap() as u64;
+        bits |= idx << (i * 3);
+    }
+    let mut out = [0u8; 8];
+    out[0] = a0;
+    out[1] = a1;
+    out[2..8].copy_from_slice(&bits.to_le_bytes()[..6]);
+    out
+}
+
+fn decode_alpha(src: &[u8; 8]) -> [u8; 16] {
+    let palette = alpha_palette(src[0], src[1]);
+    let mut raw = [0u8; 8];
+    raw[..6].copy_from_slice(&src[2..8]);
+    let bits = u64::from_le_bytes(raw);
+    let mut out = [0u8; 16];
+    for (i, a) in out.iter_mut().enumerate() {
+        *a = palette[((bits >> (i * 3)) & 7) as usize];
+    }
+    out
+}
+
+const BC7_WEIGHTS4: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+struct BitWriter {
+    bits: u128,
+    pos: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, n: u32) {
+        self.bits |= ((value & ((1 << n) - 1)) as u128) << self.pos;
+        self.pos += n;
+    }
+}
+
+struct BitReader {
+    bits: u128,
+    pos: u32,
+}
+
+impl BitReader {
+    fn read(&mut self, n: u32) -> u32 {
+        let v = ((self.bits >> self.pos) & ((1 << n) - 1)) as u32;
+        self.pos += n;
+        v
+    }
+}
+
+// 7 bit + p-bit 的端点, 返回 (7 bit 值, p-bit)
+fn quantize_mode6(e: [f32; 4]) -> ([u32; 4], u32) {
+    let mut best = ([0u32; 4], 0u32, f32::MAX);
+    for p in 0..2u32 {
+        let mut q = [0u32; 4];
+        let mut err = 0.0;
+        for c in 0..4 {
+            q[c] = ((e[c] - p as f32) / 2.0).round().clamp(0.0, 127.0) as u32;
+            let v = (q[c] << 1 | p) as f32;
+            err += (v - e[c]).powi(2);
+        }
+        if err < best.2 {
+            best = (q, p, err);
+        }
+    }
+    (best.0, best.1)
+}
+
+fn mode6_palette(q0: [u32; 4], p0: u32, q1: [u32; 4], p1: u32) -> [[u8; 4]; 16] {
+    let mut palette = [[0u8; 4]; 16];
+    for (i, w) in BC7_WEIGHTS4.iter().enumerate() {
+        for c in 0..4 {
+            let a = q0[c] << 1 | p0;
+            let b = q1[c] << 1 | p1;
+            palette[i][c] = (((64 - w) * a + w * b + 32) >> 6) as u8;
+        }
+    }
+    palette
+}
+
+fn encode_bc7_mode6(block: &Block) -> [u8; 16] {
+    let points = block.map(|p| p.map(|c| c as f32));
+    let (e0, e1) = endpoints(&points);
+    let (mut q0, mut p0) = quantize_mode6(e0);
+    let (mut q1, mut p1) = quantize_mode6(e1);
+    let palette = mode6_palette(q0, p0, q1, p1);
+    let mut indices: Vec<u32> = block
+        .iter()
+        .map(|p| nearest::<4>(p, &palette) as u32)
+        .collect();
+    // 第 0 个像素的索引最高位必须是 0, 否则交换端点
+    if indices[0] >= 8 {
+        std::mem::swap(&mut q0, &mut q1);
+        std::mem::swap(&mut p0, &mut p1);
+        indices.iter_mut().for_each(|i| *i = 15 - *i);
+    }
+
+    let mut w = BitWriter { bits: 0, pos: 0 };
+    w.write(1 << 6, 7);
+    for c in 0..4 {
+        w.write(q0[c], 7);
+        w.write(q1[c], 7);
+    }
+    w.write(p0, 1);
+    w.write(p1, 1);
+    for (i, idx) in indices.iter().enumerate() {
+        w.write(*idx, if i == 0 { 3 } else { 4 });
+    }
+    w.bits.to_le_bytes()
+}
+
+// 第一个字节最低的 1 是第几位就是 mode, 全 0 是保留的, 记为 8
+fn bc7_mode(src: &[u8]) -> u32 {
+    src[0].trailing_zeros()
+}
+
+fn check_bc7_modes(data: &[u8]) -> Result<()> {
+    let mut others = data.chunks_exact(16).enumerate().filter(|(_, b)| bc7_mode(b) != 6);
+    if let Some((i, b)) = others.next() {
+        bail!(
+            "BC7 数据里有 {} 个块不是 mode 6 (第 {} 块是 mode {}), CPU 只能解码 mode 6",
+            others.count() + 1,
+            i,
+            bc7_mode(b)
+        );
+    }
+    Ok(())
+}
+
+// 调用前要用 check_bc7_modes 检查过
+fn decode_bc7(src: &[u8; 16]) -> Block {
+    let mut r = BitReader {
+        bits: u128::from_le_bytes(*src),
+        pos: 0,
+    };
+    debug_assert_eq!(bc7_mode(src), 6);
+    r.read(7);
+    let (mut q0, mut q1) = ([0u32; 4], [0u32; 4]);
+    for c in 0..4 {
+        q0[c] = r.read(7);
+        q1[c] = r.read(7);
+    }
+    let p0 = r.read(1);
+    let p1 = r.read(1);
+    let palette = mode6_palette(q0, p0, q1, p1);
+    let mut block = [[0u8; 4]; 16];
+    for (i, p) in block.iter_mut().enumerate() {
+        let idx = r.read(if i == 0 { 3 } else { 4 });
+        *p = palette[idx as usize];
+    }
+    block
+}
+
+impl ImageMipMap {
+    pub fn is_compressed(&self) -> bool {
+        BcFormat::from_texture_format(self.format).is_some()
+    }
+
+    // 只能压缩 rgba8, 第 0 层的宽高必须是 4 的倍数 (wgpu 的要求)
+    pub fn compress(&self, format: BcFormat) -> Result<ImageMipMap> {
+        let is_srgb = match self.format {
+            TextureFormat::Rgba8Unorm => false,
+            TextureFormat::Rgba8UnormSrgb => true,
+            _ => bail!("只能压缩 rgba8 的贴图, 这张是 {:?}", self.format),
+        };
+        if !self.width.is_multiple_of(4) || !self.height.is_multiple_of(4) {
+            bail!("{}x{} 不是 4 的倍数, 不能块压缩", self.width, self.height);
+        }
+        let data = self
+            .data
+            .iter()
+            .enumerate()
+            .map(|(i, d)| {
+                let e = self.extent_3d(i as u32);
+                compress(d, e.width, e.height, format)
+            })
+            .collect();
+        Ok(ImageMipMap {
+            data,
+            width: self.width,
+            height: self.height,
+            format: format.texture_format(is_srgb),
+        })
+    }
+
+    // 解压回 rgba8, 不是压缩格式就原样复制
+    pub fn decompress(&self) -> Result<ImageMipMap> {
+        let bc = match BcFormat::from_texture_format(self.format) {
+            Some(bc) => bc,
+            None => {
+                return Ok(ImageMipMap {
+                    data: self.data.clone(),
+                    width: self.width,
+                    height: self.height,
+                    format: self.format,
+                })
+            }
+        };
+        // 先检查所有层, 不要解到一半才失败
+        if bc == BcFormat::Bc7 {
+            for d in &self.data {
+                check_bc7_modes(d)?;
+            }
+        }
+        let mut data = Vec::new();
+        for (i, d) in self.data.iter().enumerate() {
+            let e = self.extent_3d(i as u32);
+            data.push(decompress(d, e.width, e.height, bc)?);
+        }
+        Ok(ImageMipMap {
+            data,
+            width: self.width,
+            height: self.height,
+            format: if self.format.describe().srgb {
+                TextureFormat::Rgba8UnormSrgb
+            } else {
+                TextureFormat::Rgba8Unorm
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 对角方向的渐变, 每个块内的颜色都在一条线上
+    fn gradient(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let t = (x + y) * 12;
+                data.extend_from_slice(&[t as u8, (t / 2 + 64) as u8, 128, (255 - t) as u8]);
+            }
+        }
+        data
+    }
+
+    fn max_error(a: &[u8], b: &[u8], channels: usize) -> i32 {
+        a.chunks(4)
+            .zip(b.chunks(4))
+            .flat_map(|(a, b)| (0..channels).map(move |c| (a[c] as i32 - b[c] as i32).abs()))
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (w, h) = (8, 6);
+        let data = gradient(w, h);
+        for (format, channels, tolerance) in [
+            (BcFormat::Bc1, 3, 16),
+            (BcFormat::Bc3, 4, 16),
+            (BcFormat::Bc7, 4, 12),
+        ] {
+            let c = compress(&data, w, h, format);
+            assert_eq!(c.len(), 2 * 2 * format.block_bytes());
+            let d = decompress(&c, w, h, format).unwrap();
+            let err = max_error(&data, &d, channels);
+            assert!(err <= tolerance, "{:?} 误差 {}", format, err);
+        }
+    }
+
+    #[test]
+    fn solid_block_exact() {
+        let data: Vec<u8> = [10u8, 200, 30, 255].repeat(16);
+        for format in [BcFormat::Bc3, BcFormat::Bc7] {
+            let d = decompress(&compress(&data, 4, 4, format), 4, 4, format).unwrap();
+            assert!(max_error(&data, &d, 4) <= 4, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn bc7_other_modes_rejected() {
+        let data = gradient(8, 4);
+        let mut c = compress(&data, 8, 4, BcFormat::Bc7);
+        // 第二块改成 mode 1 和全 0 的保留 mode
+        c[16] = 0b10;
+        let e = decompress(&c, 8, 4, BcFormat::Bc7).unwrap_err();
+        assert!(e.to_string().contains("mode 1"), "{}", e);
+        c[16] = 0;
+        let e = decompress(&c, 8, 4, BcFormat::Bc7).unwrap_err();
+        assert!(e.to_string().contains("mode 8"), "{}", e);
+
+        let mip = ImageMipMap {
+            data: vec![c[..16].to_vec(), vec![0u8; 16]],
+            width: 4,
+            height: 4,
+            format: TextureFormat::Bc7RgbaUnorm,
+        };
+        assert!(mip.decompress().is_err());
+    }
+
+    #[test]
+    fn mip_map_compress() {
+        let mip = ImageMipMap {
+            data: vec![
+                gradient(8, 8),
+                gradient(4, 4),
+                gradient(2, 2),
+                gradient(1, 1),
+            ],
+            width: 8,
+            height: 8,
+            format: TextureFormat::Rgba8UnormSrgb,
+        };
+        let c = mip.compress(BcFormat::Bc7).unwrap();
+        assert_eq!(c.format, TextureFormat::Bc7RgbaUnormSrgb);
+        // 小于 4x4 的层也占一个块
+        assert_eq!(c.data[3].len(), 16);
+        let d = c.decompress().unwrap();
+        assert_eq!(d.format, TextureFormat::Rgba8UnormSrgb);
+        assert_eq!(d.data[2].len(), 2 * 2 * 4);
+
+        let path =
+            std::env::temp_dir().join(format!("resource_bc_test_{}.mip", std::process::id()));
+        c.write_to_file(&path).unwrap();
+        let r = ImageMipMap::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(r.format, TextureFormat::Bc7RgbaUnormSrgb);
+        assert_eq!(r.data, c.data);
+        assert!(ImageMipMap::from_bytes(b"CSMP").is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use anyhow::*;
+use resource::{atlas::*, pack::PackRegistry};
+use std::env;
+
+const USAGE: &str =
+    "用法: gen_atlas <包目录> <输出目录> [--page-size N] [--padding N] [--mips N] [--no-extrude]";
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut config = AtlasConfig::default();
+    while let Some(a) = args.next() {
+        let mut value = |name: &str| -> Result<u32> {
+            let v = args.next().ok_or(anyhow!("{} 缺少参数\n{}", name, USAGE))?;
+            v.parse()
+                .with_context(|| format!("{} 的参数 {:?} 不是数字", name, v))
+        };
+        match a.as_str() {
+            "--page-size" => config.page_size = value("--page-size")?,
+            "--padding" => config.padding = value("--padding")?,
+            "--mips" => config.mip_level_count = value("--mips")?,
+            "--no-extrude" => config.extrude = false,
+            _ if a.starts_with("--") => bail!("未知参数 {:?}\n{}", a, USAGE),
+            _ => positional.push(a),
+        }
+    }
+    let [src, dst] = <[String; 2]>::try_from(positional).map_err(|_| anyhow!(USAGE))?;
+
+    let registry = PackRegistry::load_dir(&src)?;
+    let table = build_from_registry(&registry, config, &dst)?;
+    println!(
+        "{} 个材质, {} 页, 输出到 {:?}",
+        table.materials.len(),
+        table.page_count,
+        dst
+    );
+    Ok(())
+}
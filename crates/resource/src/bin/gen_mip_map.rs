@@ -1,26 +1,59 @@
-use resource::*;
-use std::{fs::*, path::PathBuf, env};
 use anyhow::*;
+use resource::{tools::*, *};
+use std::{env, fs::*, path::PathBuf};
 
-const SRC: &'static str = "../../image_raw/";
-const DST: &'static str = "../../image/";
+const USAGE: &str = "用法: gen_mip_map <源图片或目录> <输出目录> \
+[--filter box|triangle|lanczos] [--alpha straight|premultiplied|coverage] \
+[--cutoff 0.5] [--levels N] [--linear]";
 
 fn main() -> Result<()> {
-    let cur_path = env::current_dir()?;
-    let src = cur_path.clone().join(SRC);
-    let dst = cur_path.clone().join(DST);
-    dbg!(&src,&dst);
-    let mip_map_count = 3;
-    for e in read_dir(src.clone())?{
-        let e = e?;
-        let path = e.path();
-        let m = metadata(path.clone())?;
-        if m.is_file() {
-            let img = Image::from_path(path.clone(), true)?;
-            let mipmap = tools::generate_rgba8_mip_map(img, mip_map_count)?;
-            let path = PathBuf::from(dst.clone()).join(path.file_stem().unwrap());
-            mipmap.write_to_path(path);
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut config = MipMapConfig::default();
+    let mut alpha = "premultiplied".to_string();
+    let mut cutoff = 0.5f32;
+    let mut is_srgb = true;
+    while let Some(a) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(anyhow!("{} 缺少参数\n{}", name, USAGE));
+        match a.as_str() {
+            "--filter" => config.filter = value("--filter")?.parse()?,
+            "--alpha" => alpha = value("--alpha")?,
+            "--cutoff" => cutoff = value("--cutoff")?.parse()?,
+            "--levels" => config.mip_level_count = Some(value("--levels")?.parse()?),
+            "--linear" => is_srgb = false,
+            _ if a.starts_with("--") => bail!("未知参数 {:?}\n{}", a, USAGE),
+            _ => positional.push(PathBuf::from(a)),
         }
     }
+    config.alpha = match alpha.as_str() {
+        "straight" => MipAlpha::Straight,
+        "premultiplied" => MipAlpha::Premultiplied,
+        "coverage" => MipAlpha::Coverage { cutoff },
+        _ => bail!("未知的 alpha 模式 {:?}\n{}", alpha, USAGE),
+    };
+    let [src, dst] = <[PathBuf; 2]>::try_from(positional).map_err(|_| anyhow!(USAGE))?;
+
+    let files = if src.is_dir() {
+        let mut files = Vec::new();
+        for e in read_dir(&src)? {
+            let path = e?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        files
+    } else {
+        vec![src]
+    };
+    create_dir_all(&dst)?;
+    for path in files {
+        let img =
+            Image::from_path(&path, is_srgb).with_context(|| format!("读取 {:?} 失败", path))?;
+        let mipmap = generate_rgba8_mip_map(img, &config)
+            .with_context(|| format!("{:?} 生成 mipmap 失败", path))?;
+        let out = dst.join(path.file_stem().unwrap());
+        println!("{:?} -> {:?}, {} 层", path, out, mipmap.mip_map_count());
+        mipmap.write_to_path(out)?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,125 @@
+use anyhow::*;
+use resource::{bc::BcFormat, bundle, pack::PackRegistry, shader_check, validate, ImageMipMap};
+use std::env;
+
+const USAGE: &str = "用法: resource <子命令> ...
+
+子命令:
+    validate <目录>...    检查资源目录 (包目录, 或者 mipmap 目录树)
+    compress <mipmap 目录> <输出.mip> [--format bc1|bc3|bc7] [--linear]
+                          把 mipmap 目录压缩成单个 .mip 文件, 默认 bc7
+    bundle <包目录> <输出.bundle> [--compress bc1|bc3|bc7]
+                          把包目录下的所有包打成单个资源包文件
+    shader <包目录>...    用 naga 检查包里声明的 shader 的每个变体, 以及入口函数";
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let cmd = args.next().ok_or(anyhow!(USAGE))?;
+    let rest: Vec<String> = args.collect();
+    match cmd.as_str() {
+        "validate" => validate(&rest),
+        "compress" => compress(&rest),
+        "bundle" => bundle(&rest),
+        "shader" => shader(&rest),
+        _ => bail!("未知子命令 {:?}\n{}", cmd, USAGE),
+    }
+}
+
+fn validate(dirs: &[String]) -> Result<()> {
+    if dirs.is_empty() {
+        bail!(USAGE);
+    }
+    let mut failed = 0;
+    for dir in dirs {
+        let report = validate::validate_tree(dir)?;
+        for (path, e) in report.errors.iter() {
+            eprintln!("错误 {:?}: {:#}", path, e);
+        }
+        println!(
+            "{}: 检查了 {} 个 mipmap 目录, {} 个错误",
+            dir,
+            report.checked,
+            report.errors.len()
+        );
+        failed += report.errors.len();
+    }
+    if failed > 0 {
+        bail!("共 {} 个错误", failed);
+    }
+    Ok(())
+}
+
+fn compress(args: &[String]) -> Result<()> {
+    let mut paths = Vec::new();
+    let mut format = BcFormat::Bc7;
+    let mut is_srgb = true;
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        let mut value = || it.next().ok_or(anyhow!("{} 缺少参数\n{}", a, USAGE));
+        match a.as_str() {
+            "--format" => format = value()?.parse()?,
+            "--linear" => is_srgb = false,
+            _ => paths.push(a),
+        }
+    }
+    if paths.len() != 2 {
+        bail!(USAGE);
+    }
+    let mip = ImageMipMap::from_path(paths[0], is_srgb)?.compress(format)?;
+    mip.write_to_file(paths[1])?;
+    println!(
+        "{} -> {}: {:?}, {} 层",
+        paths[0],
+        paths[1],
+        mip.format,
+        mip.mip_map_count()
+    );
+    Ok(())
+}
+
+fn bundle(args: &[String]) -> Result<()> {
+    let mut paths = Vec::new();
+    let mut compress = None;
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        let mut value = || it.next().ok_or(anyhow!("{} 缺少参数\n{}", a, USAGE));
+        match a.as_str() {
+            "--compress" => compress = Some(value()?.parse()?),
+            _ => paths.push(a),
+        }
+    }
+    if paths.len() != 2 {
+        bail!(USAGE);
+    }
+    let stats = bundle::write_bundle(paths[0], paths[1], compress)?;
+    println!(
+        "{} -> {}: {} 个包, {} 张贴图, {} 个 shader",
+        paths[0], paths[1], stats.packs, stats.textures, stats.shaders
+    );
+    Ok(())
+}
+
+fn shader(dirs: &[String]) -> Result<()> {
+    if dirs.is_empty() {
+        bail!(USAGE);
+    }
+    let mut failed = 0;
+    for dir in dirs {
+        let registry = PackRegistry::load_dir(dir)?;
+        let report = shader_check::check_registry(&registry);
+        for (path, e) in report.errors.iter() {
+            eprintln!("错误 {:?}: {:#}", path, e);
+        }
+        println!(
+            "{}: 检查了 {} 个 shader 变体, {} 个错误",
+            dir,
+            report.checked,
+            report.errors.len()
+        );
+        failed += report.errors.len();
+    }
+    if failed > 0 {
+        bail!("共 {} 个错误", failed);
+    }
+    Ok(())
+}
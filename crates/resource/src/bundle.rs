@@ -0,0 +1,315 @@
+// 把包目录打成单个文件, 运行时 mmap 进来, 按索引取需要的条目, 不用再逐个解码 png
+//
+// 文件布局 (小端):
+//   magic "CSBD", 版本 u32, 索引偏移 u64, 条目数 u32
+//   各条目的数据
+//   索引: 每条是 key 长度 u32, key (utf8), 偏移 u64, 长度 u64
+// key 是相对包根目录的路径, 用 '/' 分隔, 例如 base/manifest.toml, base/image/cube_test.
+// 贴图条目是 .mip 文件的内容, 见 ImageMipMap::to_bytes.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::*;
+use memmap2::Mmap;
+
+use crate::{
+    bc::BcFormat,
+    pack::{Pack, MANIFEST_FILE_NAME},
+    ImageMipMap,
+};
+
+pub const BUNDLE_FILE_EXTENSION: &str = "bundle";
+const BUNDLE_MAGIC: &[u8; 4] = b"CSBD";
+const BUNDLE_VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 20;
+
+// 路径转成 key, 只允许普通的路径分量
+pub fn bundle_key(path: impl AsRef<Path>) -> Result<String> {
+    let path = path.as_ref();
+    let mut parts = Vec::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(s) => parts.push(
+                s.to_str()
+                    .ok_or_else(|| anyhow!("{:?} 不是 utf8", path))?
+                    .to_string(),
+            ),
+            Component::CurDir => {}
+            _ => bail!("{:?} 不能放进资源包, 只能是包内的相对路径", path),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+fn take<'a>(r: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if r.len() < n {
+        bail!("文件不完整");
+    }
+    let (a, b) = r.split_at(n);
+    *r = b;
+    Ok(a)
+}
+
+fn read_u32(r: &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_le_bytes(take(r, 4)?.try_into()?))
+}
+
+fn read_u64(r: &mut &[u8]) -> Result<u64> {
+    Ok(u64::from_le_bytes(take(r, 8)?.try_into()?))
+}
+
+#[derive(Debug)]
+pub struct Bundle {
+    map: Mmap,
+    index: BTreeMap<String, (usize, usize)>,
+}
+
+impl Bundle {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let f = File::open(path).with_context(|| format!("打开 {:?} 失败", path))?;
+        // 文件在使用期间不应被修改, 和其他 mmap 的用法一样
+        let map = unsafe { Mmap::map(&f) }.with_context(|| format!("mmap {:?} 失败", path))?;
+        let index = Self::read_index(&map).with_context(|| format!("解析 {:?} 失败", path))?;
+        Ok(Self { map, index })
+    }
+
+    fn read_index(bytes: &[u8]) -> Result<BTreeMap<String, (usize, usize)>> {
+        if bytes.len() < HEADER_SIZE as usize || &bytes[..4] != BUNDLE_MAGIC {
+            bail!("不是资源包文件");
+        }
+        let mut r = &bytes[4..];
+        let version = read_u32(&mut r)?;
+        if version != BUNDLE_VERSION {
+            bail!("不支持的资源包版本 {}", version);
+        }
+        let index_offset = read_u64(&mut r)? as usize;
+        let count = read_u32(&mut r)?;
+        let mut r = bytes.get(index_offset..).ok_or(anyhow!("文件不完整"))?;
+        let mut index = BTreeMap::new();
+        for _ in 0..count {
+            let len = read_u32(&mut r)? as usize;
+            let key = String::from_utf8(take(&mut r, len)?.to_vec())?;
+            let offset = read_u64(&mut r)? as usize;
+            let size = read_u64(&mut r)? as usize;
+            if offset
+                .checked_add(size)
+                .is_none_or(|end| end > index_offset)
+            {
+                bail!("条目 {:?} 超出了数据区", key);
+            }
+            index.insert(key, (offset, size));
+        }
+        Ok(index)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        let &(offset, size) = self.index.get(key)?;
+        Some(&self.map[offset..offset + size])
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.index.keys().map(|k| k.as_str())
+    }
+
+    // 根下的包, 即 <目录名>/manifest.toml 形式的条目
+    pub fn pack_dirs(&self) -> Vec<&str> {
+        self.keys()
+            .filter_map(|k| k.strip_suffix(MANIFEST_FILE_NAME)?.strip_suffix('/'))
+            .filter(|d| !d.contains('/'))
+            .collect()
+    }
+
+    // 打包时已经按声明的 srgb 转成了 .mip, 格式以文件里的为准
+    pub fn load_texture(&self, key: &str) -> Result<ImageMipMap> {
+        let bytes = self
+            .get(key)
+            .ok_or_else(|| anyhow!("资源包里没有贴图 {:?}", key))?;
+        ImageMipMap::from_bytes(bytes).with_context(|| format!("解析贴图 {:?} 失败", key))
+    }
+}
+
+pub struct BundleWriter {
+    out: BufWriter<File>,
+    offset: u64,
+    index: Vec<(String, u64, u64)>,
+}
+
+impl BundleWriter {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let f = File::create(path).with_context(|| format!("创建 {:?} 失败", path))?;
+        let mut out = BufWriter::new(f);
+        // 头部最后再填
+        out.write_all(&[0u8; HEADER_SIZE as usize])?;
+        Ok(Self {
+            out,
+            offset: HEADER_SIZE,
+            index: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, key: String, data: &[u8]) -> Result<()> {
+        if self.index.iter().any(|(k, _, _)| *k == key) {
+            bail!("资源包条目 {:?} 重复", key);
+        }
+        self.out.write_all(data)?;
+        self.index.push((key, self.offset, data.len() as u64));
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        let index_offset = self.offset;
+        for (key, offset, size) in self.index.iter() {
+            self.out.write_all(&(key.len() as u32).to_le_bytes())?;
+            self.out.write_all(key.as_bytes())?;
+            self.out.write_all(&offset.to_le_bytes())?;
+            self.out.write_all(&size.to_le_bytes())?;
+        }
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(BUNDLE_MAGIC)?;
+        self.out.write_all(&BUNDLE_VERSION.to_le_bytes())?;
+        self.out.write_all(&index_offset.to_le_bytes())?;
+        self.out
+            .write_all(&(self.index.len() as u32).to_le_bytes())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BundleStats {
+    pub packs: usize,
+    pub textures: usize,
+    pub shaders: usize,
+}
+
+// 把 root 下的所有包打进 out, compress 不为空时贴图顺便做块压缩
+pub fn write_bundle(
+    root: impl AsRef<Path>,
+    out: impl AsRef<Path>,
+    compress: Option<BcFormat>,
+) -> Result<BundleStats> {
+    let root = root.as_ref();
+    let mut packs = Vec::new();
+    for e in fs::read_dir(root).with_context(|| format!("读取包目录 {:?} 失败", root))? {
+        let p = e?.path();
+        if p.is_dir() && p.join(MANIFEST_FILE_NAME).is_file() {
+            packs.push(Pack::from_dir(p)?);
+        }
+    }
+    let mut w = BundleWriter::create(out)?;
+    let mut stats = BundleStats::default();
+    for pack in packs.iter() {
+        let dir = pack.dir.strip_prefix(root)?;
+        let text = fs::read(pack.dir.join(MANIFEST_FILE_NAME))?;
+        w.add(bundle_key(dir.join(MANIFEST_FILE_NAME))?, &text)?;
+        stats.packs += 1;
+        // 包括被覆盖的贴图, 加载时每个包的声明都要能找到
+        for (key, decl) in pack.manifest.textures.iter() {
+            let path = pack.dir.join(&decl.path);
+            let mut mip = ImageMipMap::load(&path, decl.srgb)
+                .with_context(|| format!("读取贴图 {:?} 失败", key))?;
+            if let Some(format) = compress {
+                if !mip.is_compressed() {
+                    mip = mip.compress(format)?;
+                }
+            }
+            w.add(bundle_key(dir.join(&decl.path))?, &mip.to_bytes()?)?;
+            stats.textures += 1;
+        }
+        // 声明的 shader 之外, 还有被 #include 的 WGSL, 所以包里所有的 .wgsl 都要打进去
+        let mut shaders = Vec::new();
+        find_wgsl(&pack.dir, &mut shaders)?;
+        for decl in pack.manifest.shaders.values() {
+            let path = pack.dir.join(&decl.path);
+            if !shaders.contains(&path) {
+                shaders.push(path);
+            }
+        }
+        for path in shaders {
+            let data = fs::read(&path).with_context(|| format!("读取 shader {:?} 失败", path))?;
+            w.add(bundle_key(path.strip_prefix(root)?)?, &data)?;
+            stats.shaders += 1;
+        }
+    }
+    w.finish()?;
+    Ok(stats)
+}
+
+fn find_wgsl(dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    for e in fs::read_dir(dir).with_context(|| format!("读取 {:?} 失败", dir))? {
+        let p = e?.path();
+        if p.is_dir() {
+            find_wgsl(&p, out)?;
+        } else if p.extension().is_some_and(|e| e == "wgsl") {
+            out.push(p);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::PackRegistry;
+    use image::RgbaImage;
+
+    #[test]
+    fn bundle_round_trip() {
+        let root =
+            std::env::temp_dir().join(format!("resource_bundle_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let tex = root.join("base/image/a");
+        fs::create_dir_all(&tex).unwrap();
+        RgbaImage::new(8, 8).save(tex.join("0.png")).unwrap();
+        RgbaImage::new(4, 4).save(tex.join("1.png")).unwrap();
+        fs::write(root.join("base/s.wgsl"), "// shader").unwrap();
+        fs::write(
+            root.join("base").join(MANIFEST_FILE_NAME),
+            r#"
+[pack]
+id = "base"
+name = "base"
+version = "0.1.0"
+[textures]
+a = { path = "image/a" }
+[materials]
+a = { texture = "a" }
+[blocks]
+a = { material = "a" }
+[shaders]
+s = { path = "s.wgsl" }
+"#,
+        )
+        .unwrap();
+
+        let file = std::env::temp_dir().join(format!(
+            "resource_bundle_test_{}.{}",
+            std::process::id(),
+            BUNDLE_FILE_EXTENSION
+        ));
+        let stats = write_bundle(&root, &file, Some(BcFormat::Bc1)).unwrap();
+        assert_eq!((stats.packs, stats.textures, stats.shaders), (1, 1, 1));
+        // 打包之后不再需要原目录
+        fs::remove_dir_all(&root).unwrap();
+
+        let reg = PackRegistry::load_bundle(&file).unwrap();
+        let t = reg.textures.get(0).unwrap();
+        let mip = reg.load_texture(&t.path, t.srgb).unwrap();
+        assert_eq!(mip.format, wgpu::TextureFormat::Bc1RgbaUnormSrgb);
+        assert_eq!(mip.mip_map_count(), 2);
+        let shader = reg.read(reg.shader_path("base:s").unwrap()).unwrap();
+        assert_eq!(&shader[..], b"// shader");
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+// 资源加载时的错误, 需要区分错误类型的地方可以用 anyhow::Error::downcast_ref 拿到
+use std::{fmt, path::PathBuf};
+
+#[derive(Debug)]
+pub enum MipMapError {
+    // 文件名不是数字
+    NonNumericName {
+        path: PathBuf,
+    },
+    // 同一层有多个文件, 例如 0.png 和 0.jpg
+    DuplicateLevel {
+        dir: PathBuf,
+        level: u32,
+    },
+    // 目录里没有任何层
+    Empty {
+        dir: PathBuf,
+    },
+    // 中间缺了某一层, 包括缺第 0 层
+    MissingLevel {
+        dir: PathBuf,
+        level: u32,
+    },
+    // 层号超出了第 0 层的尺寸能有的层数
+    LevelOutOfRange {
+        path: PathBuf,
+        level: u32,
+        max_level_count: u32,
+    },
+    // 某层的尺寸不是第 0 层按 mipmap 规则缩小后的尺寸
+    WrongSize {
+        path: PathBuf,
+        level: u32,
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    // 同一个目录里, 各层图片的颜色类型不同
+    LevelColorMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    // 文件里有多层 (texture array 或 cubemap), 但这里只能用单层的贴图
+    LayeredTexture {
+        path: PathBuf,
+        layers: usize,
+    },
+    // texture array 的各层之间不一致
+    LayerMismatch {
+        layer: usize,
+        what: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for MipMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MipMapError::NonNumericName { path } => {
+                write!(f, "{:?}: 文件名必须是 mipmap 的层号, 例如 0.png", path)
+            }
+            MipMapError::DuplicateLevel { dir, level } => {
+                write!(f, "{:?}: 第 {} 层有多个文件", dir, level)
+            }
+            MipMapError::Empty { dir } => write!(f, "{:?}: 没有任何 mipmap", dir),
+            MipMapError::MissingLevel { dir, level } => {
+                write!(f, "{:?}: 缺少第 {} 层", dir, level)
+            }
+            MipMapError::LevelOutOfRange {
+                path,
+                level,
+                max_level_count,
+            } => write!(
+                f,
+                "{:?}: 第 0 层的尺寸最多只有 {} 层, 不能有第 {} 层",
+                path, max_level_count, level
+            ),
+            MipMapError::WrongSize {
+                path,
+                level,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{:?}: 第 {} 层应该是 {}x{}, 实际是 {}x{}",
+                path, level, expected.0, expected.1, actual.0, actual.1
+            ),
+            MipMapError::LevelColorMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{:?}: 颜色类型是 {}, 与第 0 层的 {} 不一致",
+                path, actual, expected
+            ),
+            MipMapError::LayeredTexture { path, layers } => write!(
+                f,
+                "{:?}: 有 {} 层, 这里只能用单层的贴图, 多层的要拆成多张贴图分别声明",
+                path, layers
+            ),
+            MipMapError::LayerMismatch {
+                layer,
+                what,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "texture array 第 {} 层的{}是 {}, 与第 0 层的 {} 不一致",
+                layer, what, actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MipMapError {}
+
+impl MipMapError {
+    pub fn layer_mismatch(
+        layer: usize,
+        what: &'static str,
+        expected: impl fmt::Debug,
+        actual: impl fmt::Debug,
+    ) -> Self {
+        MipMapError::LayerMismatch {
+            layer,
+            what,
+            expected: format!("{:?}", expected),
+            actual: format!("{:?}", actual),
+        }
+    }
+}
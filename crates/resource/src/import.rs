@@ -0,0 +1,294 @@
+// 导入外部工具导出的 KTX2 / DDS 文件, 保留文件里的 mipmap 和 array 的各层
+//
+// 只支持 rgba8, bgra8 (导入时换成 rgba8), 以及 BC1/BC3/BC7, 和 .mip 文件能存的格式一致.
+// cubemap 的 6 个面当作 6 层.
+// 文件里记录了是否 srgb 的以文件为准, 只有老格式的 dds 没有记录, 才按调用者的 is_srgb.
+use std::path::Path;
+
+use anyhow::*;
+use ddsfile::{D3DFormat, Dds, DxgiFormat};
+use ktx2::Format;
+use wgpu::TextureFormat;
+
+use crate::ImageMipMap;
+
+pub const KTX2_EXTENSION: &str = "ktx2";
+pub const DDS_EXTENSION: &str = "dds";
+
+pub fn is_importable(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| {
+            let e = e.to_ascii_lowercase();
+            e == KTX2_EXTENSION || e == DDS_EXTENSION
+        })
+        .unwrap_or(false)
+}
+
+// 第二个值表示是否是 bgra, 需要换成 rgba, ktx2 的格式都带有是否 srgb
+fn ktx2_format(format: Format) -> Option<(TextureFormat, bool)> {
+    Some(match format {
+        Format::R8G8B8A8_UNORM => (TextureFormat::Rgba8Unorm, false),
+        Format::R8G8B8A8_SRGB => (TextureFormat::Rgba8UnormSrgb, false),
+        Format::B8G8R8A8_UNORM => (TextureFormat::Rgba8Unorm, true),
+        Format::B8G8R8A8_SRGB => (TextureFormat::Rgba8UnormSrgb, true),
+        Format::BC1_RGBA_UNORM_BLOCK => (TextureFormat::Bc1RgbaUnorm, false),
+        Format::BC1_RGBA_SRGB_BLOCK => (TextureFormat::Bc1RgbaUnormSrgb, false),
+        Format::BC3_UNORM_BLOCK => (TextureFormat::Bc3RgbaUnorm, false),
+        Format::BC3_SRGB_BLOCK => (TextureFormat::Bc3RgbaUnormSrgb, false),
+        Format::BC7_UNORM_BLOCK => (TextureFormat::Bc7RgbaUnorm, false),
+        Format::BC7_SRGB_BLOCK => (TextureFormat::Bc7RgbaUnormSrgb, false),
+        _ => return None,
+    })
+}
+
+fn dds_format(dds: &Dds, is_srgb: bool) -> Option<(TextureFormat, bool)> {
+    if let Some(f) = dds.get_dxgi_format() {
+        return Some(match f {
+            DxgiFormat::R8G8B8A8_UNorm => (TextureFormat::Rgba8Unorm, false),
+            DxgiFormat::R8G8B8A8_UNorm_sRGB => (TextureFormat::Rgba8UnormSrgb, false),
+            DxgiFormat::B8G8R8A8_UNorm => (TextureFormat::Rgba8Unorm, true),
+            DxgiFormat::B8G8R8A8_UNorm_sRGB => (TextureFormat::Rgba8UnormSrgb, true),
+            DxgiFormat::BC1_UNorm => (TextureFormat::Bc1RgbaUnorm, false),
+            DxgiFormat::BC1_UNorm_sRGB => (TextureFormat::Bc1RgbaUnormSrgb, false),
+            DxgiFormat::BC3_UNorm => (TextureFormat::Bc3RgbaUnorm, false),
+            DxgiFormat::BC3_UNorm_sRGB => (TextureFormat::Bc3RgbaUnormSrgb, false),
+            DxgiFormat::BC7_UNorm => (TextureFormat::Bc7RgbaUnorm, false),
+            DxgiFormat::BC7_UNorm_sRGB => (TextureFormat::Bc7RgbaUnormSrgb, false),
+            _ => return None,
+        });
+    }
+    // 老格式的文件没有 srgb 信息, 由调用者的 is_srgb 决定
+    let (format, is_bgra) = match dds.get_d3d_format()? {
+        D3DFormat::A8B8G8R8 => (TextureFormat::Rgba8Unorm, false),
+        D3DFormat::A8R8G8B8 => (TextureFormat::Rgba8Unorm, true),
+        D3DFormat::DXT1 => (TextureFormat::Bc1RgbaUnorm, false),
+        D3DFormat::DXT5 => (TextureFormat::Bc3RgbaUnorm, false),
+        _ => return None,
+    };
+    Some((super::with_srgb(format, is_srgb), is_bgra))
+}
+
+fn bgra_to_rgba(data: &mut [u8]) {
+    for p in data.chunks_exact_mut(4) {
+        p.swap(0, 2);
+    }
+}
+
+impl ImageMipMap {
+    // 按扩展名导入, 返回 array 的每一层
+    pub fn import(path: impl AsRef<Path>, is_srgb: bool) -> Result<Vec<ImageMipMap>> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).with_context(|| format!("读取 {:?} 失败", path))?;
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        let layers = match ext.as_deref() {
+            Some(KTX2_EXTENSION) => Self::from_ktx2(&bytes),
+            Some(DDS_EXTENSION) => Self::from_dds(&bytes, is_srgb),
+            _ => bail!("{:?} 不是 ktx2 或 dds 文件", path),
+        };
+        layers.with_context(|| format!("导入 {:?} 失败", path))
+    }
+
+    pub fn from_ktx2(bytes: &[u8]) -> Result<Vec<ImageMipMap>> {
+        let reader = ktx2::Reader::new(bytes).map_err(|e| anyhow!("ktx2 解析失败: {:?}", e))?;
+        let header = reader.header();
+        if let Some(s) = header.supercompression_scheme {
+            bail!("不支持超压缩 {:?}", s);
+        }
+        if header.pixel_depth > 1 {
+            bail!("不支持 3D 贴图");
+        }
+        let format = header.format.ok_or(anyhow!("ktx2 没有指定格式"))?;
+        let (format, is_bgra) =
+            ktx2_format(format).ok_or_else(|| anyhow!("不支持的 ktx2 格式 {:?}", format))?;
+        let layer_count = (header.layer_count.max(1) * header.face_count.max(1)) as usize;
+        let mut layers: Vec<ImageMipMap> = (0..layer_count)
+            .map(|_| ImageMipMap {
+                data: Vec::new(),
+                width: header.pixel_width,
+                height: header.pixel_height.max(1),
+                format,
+            })
+            .collect();
+        // 每个 level 里依次是各层 (每层的各个面) 的数据
+        for (i, level) in reader.levels().enumerate() {
+            let size = layers[0].level_bytes(i as u32);
+            if level.data.len() != size * layer_count {
+                bail!(
+                    "第 {} 层应该是 {} 字节, 实际是 {}",
+                    i,
+                    size * layer_count,
+                    level.data.len()
+                );
+            }
+            for (l, d) in layers.iter_mut().zip(level.data.chunks_exact(size)) {
+                let mut d = d.to_vec();
+                if is_bgra {
+                    bgra_to_rgba(&mut d);
+                }
+                l.data.push(d);
+            }
+        }
+        Ok(layers)
+    }
+
+    pub fn from_dds(bytes: &[u8], is_srgb: bool) -> Result<Vec<ImageMipMap>> {
+        let dds = Dds::read(bytes).map_err(|e| anyhow!("dds 解析失败: {}", e))?;
+        if dds.get_depth() > 1 {
+            bail!("不支持 3D 贴图");
+        }
+        let (format, is_bgra) = dds_format(&dds, is_srgb).ok_or_else(|| {
+            anyhow!(
+                "不支持的 dds 格式 {:?}",
+                dds.get_dxgi_format()
+                    .map(|f| format!("{:?}", f))
+                    .or(dds.get_d3d_format().map(|f| format!("{:?}", f)))
+            )
+        })?;
+        let mut layers = Vec::new();
+        // dds 里每层的各级 mipmap 是连续存放的
+        for layer in 0..dds.get_num_array_layers() {
+            let mut data = dds
+                .get_data(layer)
+                .map_err(|e| anyhow!("读取第 {} 层失败: {}", layer, e))?;
+            let mut mip = ImageMipMap {
+                data: Vec::new(),
+                width: dds.get_width(),
+                height: dds.get_height(),
+                format,
+            };
+            for i in 0..dds.get_num_mipmap_levels() {
+                let size = mip.level_bytes(i);
+                if data.len() < size {
+                    bail!("第 {} 层的第 {} 级 mipmap 数据不完整", layer, i);
+                }
+                let (d, rest) = data.split_at(size);
+                data = rest;
+                let mut d = d.to_vec();
+                if is_bgra {
+                    bgra_to_rgba(&mut d);
+                }
+                mip.data.push(d);
+            }
+            layers.push(mip);
+        }
+        Ok(layers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ddsfile::{AlphaMode, D3D10ResourceDimension, NewDxgiParams};
+
+    // 最小的 ktx2 文件: 没有 kvd 和 sgd, dfd 只有长度
+    fn ktx2_bytes(
+        format: u32,
+        width: u32,
+        height: u32,
+        layers: u32,
+        levels: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let mut out = b"\xABKTX 20\xBB\r\n\x1A\n".to_vec();
+        let level_index_end = 80 + 24 * levels.len() as u32;
+        for v in [
+            format,
+            1,
+            width,
+            height,
+            0,
+            layers,
+            1,
+            levels.len() as u32,
+            0,
+        ] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        for v in [level_index_end, 4, 0, 0] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&[0u8; 16]);
+        let mut offset = level_index_end as u64 + 4;
+        for l in levels {
+            for v in [offset, l.len() as u64, l.len() as u64] {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            offset += l.len() as u64;
+        }
+        out.extend_from_slice(&4u32.to_le_bytes());
+        for l in levels {
+            out.extend_from_slice(l);
+        }
+        out
+    }
+
+    #[test]
+    fn import_ktx2_bgra_array() {
+        // 2 层, 4x4 和 2x2 两级 mipmap, 第 0 层是蓝色, 第 1 层是红色 (bgra)
+        let level = |size: usize| {
+            [
+                [255u8, 0, 0, 255].repeat(size),
+                [0, 0, 255, 255].repeat(size),
+            ]
+            .concat()
+        };
+        let bytes = ktx2_bytes(50, 4, 4, 2, &[level(16), level(4)]);
+        let layers = ImageMipMap::from_ktx2(&bytes).unwrap();
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].format, TextureFormat::Rgba8UnormSrgb);
+        assert_eq!(layers[1].mip_map_count(), 2);
+        assert_eq!(&layers[0].data[1][..4], &[0, 0, 255, 255]);
+        assert_eq!(&layers[1].data[0][..4], &[255, 0, 0, 255]);
+        ImageMipMap::validate_array(&layers).unwrap();
+
+        // 多层的不能当作单张贴图
+        let path =
+            std::env::temp_dir().join(format!("resource_import_test_{}.ktx2", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let e = ImageMipMap::load(&path, true).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            e.downcast::<crate::MipMapError>(),
+            std::result::Result::Ok(crate::MipMapError::LayeredTexture { layers: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn import_dds_bc7() {
+        let mut dds = Dds::new_dxgi(NewDxgiParams {
+            height: 8,
+            width: 8,
+            depth: None,
+            format: DxgiFormat::BC7_UNorm_sRGB,
+            mipmap_levels: Some(4),
+            array_layers: None,
+            caps2: None,
+            is_cubemap: false,
+            resource_dimension: D3D10ResourceDimension::Texture2D,
+            alpha_mode: AlphaMode::Straight,
+        })
+        .unwrap();
+        // 8x8, 4x4, 2x2, 1x1 分别是 4, 1, 1, 1 个块
+        assert_eq!(dds.get_data(0).unwrap().len(), 7 * 16);
+        // 全是 mode 6 的空块, 这样才能解压
+        for b in dds.get_mut_data(0).unwrap().chunks_exact_mut(16) {
+            b[0] = 1 << 6;
+        }
+        let mut bytes = Vec::new();
+        dds.write(&mut bytes).unwrap();
+
+        // 文件里是 srgb, 调用者的 is_srgb 不起作用
+        let layers = ImageMipMap::from_dds(&bytes, false).unwrap();
+        assert_eq!(layers.len(), 1);
+        let mip = &layers[0];
+        assert_eq!(mip.format, TextureFormat::Bc7RgbaUnormSrgb);
+        assert_eq!(
+            mip.data.iter().map(|d| d.len()).collect::<Vec<_>>(),
+            vec![64, 16, 16, 16]
+        );
+        assert_eq!(mip.decompress().unwrap().data[3].len(), 4);
+    }
+}
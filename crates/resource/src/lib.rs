@@ -1,42 +1,111 @@
 use std::{
-    collections::HashMap,
     fs::{self, File},
     io::Read,
     mem,
     num::NonZeroU32,
-    path::Path, env,
+    path::{Path, PathBuf},
 };
 
 use anyhow::*;
 use image::{ImageBuffer, Rgba};
 use wgpu::*;
 
+pub mod atlas;
+pub mod bc;
+pub mod bundle;
+pub mod error;
+pub mod import;
+pub mod pack;
+pub mod preprocess;
+pub mod shader_check;
 pub mod tools;
+pub mod validate;
 
+pub use error::MipMapError;
+
+// 压缩好的 mipmap 存成单个文件, 扩展名 .mip
+pub const MIP_FILE_EXTENSION: &str = "mip";
+const MIP_FILE_MAGIC: &[u8; 4] = b"CSMP";
+const MIP_FILE_VERSION: u32 = 1;
+// 文件里记录的格式编号
+const MIP_FILE_FORMATS: [TextureFormat; 8] = [
+    TextureFormat::Rgba8Unorm,
+    TextureFormat::Rgba8UnormSrgb,
+    TextureFormat::Bc1RgbaUnorm,
+    TextureFormat::Bc1RgbaUnormSrgb,
+    TextureFormat::Bc3RgbaUnorm,
+    TextureFormat::Bc3RgbaUnormSrgb,
+    TextureFormat::Bc7RgbaUnorm,
+    TextureFormat::Bc7RgbaUnormSrgb,
+];
+
+#[derive(Debug, Clone)]
 pub enum ShaderData {
     Wgsl(String),
     SpirV(Vec<u32>),
 }
 
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
 impl ShaderData {
-    pub fn as_shader_source(&self) -> ShaderSource {
+    // SpirV 只能走 passthrough, 不能转成 ShaderSource
+    pub fn as_shader_source(&self) -> Result<ShaderSource<'_>> {
         match self {
-            ShaderData::Wgsl(d) => ShaderSource::Wgsl(d.into()),
-            _ => {
-                panic!("{}", UNSUPPORTED)
-            }
+            ShaderData::Wgsl(d) => Ok(ShaderSource::Wgsl(d.into())),
+            ShaderData::SpirV(_) => bail!("SPIR-V 需要用 passthrough 创建 shader module"),
+        }
+    }
+
+    pub fn from_bytes(ty: ShaderType, bytes: Vec<u8>) -> Result<Self> {
+        Ok(match ty {
+            ShaderType::Wgsl => ShaderData::Wgsl(String::from_utf8(bytes).context("WGSL 不是 utf8")?),
+            ShaderType::SpirV => ShaderData::SpirV(Self::spirv_words(&bytes)?),
+        })
+    }
+
+    // 按 4 字节的 word 读, 第一个 word 必须是 magic number, 字节序和本机相反的会转过来
+    pub fn spirv_words(bytes: &[u8]) -> Result<Vec<u32>> {
+        if bytes.is_empty() || !bytes.len().is_multiple_of(4) {
+            bail!("SPIR-V 的长度 {} 不是 4 的倍数", bytes.len());
+        }
+        let mut words: Vec<u32> = bytes
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect();
+        if words[0] == SPIRV_MAGIC.swap_bytes() {
+            words.iter_mut().for_each(|w| *w = w.swap_bytes());
+        }
+        if words[0] != SPIRV_MAGIC {
+            bail!("不是 SPIR-V, magic number 是 {:#010x}", words[0]);
         }
+        Ok(words)
     }
 }
 
 pub struct Shader {
     pub data: ShaderData,
     enter_point: String,
+    // 经过预处理的 WGSL 才有, 用来把报错的行号换回原文件
+    pub source_map: Option<preprocess::SourceMap>,
 }
 
 impl Shader {
     pub const VS_FUNC_NAME: &str = "vertex_main";
     pub const FS_FUNC_NAME: &str = "fragment_main";
+    pub fn new(data: ShaderData, enter_point: String) -> Self {
+        Self {
+            data,
+            enter_point,
+            source_map: None,
+        }
+    }
+    pub fn from_preprocessed(p: preprocess::Preprocessed, enter_point: String) -> Self {
+        Self {
+            data: ShaderData::Wgsl(p.code),
+            enter_point,
+            source_map: Some(p.map),
+        }
+    }
     pub fn enter_point(&self) -> &str {
         &self.enter_point
     }
@@ -44,30 +113,32 @@ impl Shader {
 
 impl Shader {
     pub fn from_path(path:impl AsRef<Path>, ty: ShaderType, enter_point:String) -> Result<Self> {
-        dbg!(path.as_ref());
-        let mut f = File::open(path)?;
-        let data = match ty {
-            ShaderType::Wgsl => {
-                let mut buf = String::new();
-                f.read_to_string(&mut buf)?;
-                ShaderData::Wgsl(buf)
-            }
-            ShaderType::SpirV => {
-                panic!("{}", UNSUPPORTED)
-            }
-        };
-        Ok(Self {
-            data,
-            enter_point,
-        })
+        let path = path.as_ref();
+        let mut buf = Vec::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut buf))
+            .with_context(|| format!("读取 {:?} 失败", path))?;
+        let data = ShaderData::from_bytes(ty, buf).with_context(|| format!("{:?}", path))?;
+        Ok(Self::new(data, enter_point))
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ShaderType {
     Wgsl,
     SpirV,
 }
 
+impl ShaderType {
+    // .spv 是 SPIR-V, 其他都当作 WGSL
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension() {
+            Some(e) if e == "spv" => ShaderType::SpirV,
+            _ => ShaderType::Wgsl,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Image {
     pub data: Vec<u8>,
@@ -114,40 +185,109 @@ impl ImageMipMap {
         self.data.len() as u32
     }
 
+    // 目录里是 0.png, 1.png, ... 每个文件是一层, 以 "." 开头的文件忽略
     pub fn from_path(path: impl AsRef<Path>, is_srgb: bool) -> Result<ImageMipMap> {
+        let dir = path.as_ref();
+        let mut files = Vec::new();
+        for e in fs::read_dir(dir).with_context(|| format!("读取 {:?} 失败", dir))? {
+            let p = e?.path();
+            let stem = match p.file_stem().and_then(|s| s.to_str()) {
+                Some(s) if !s.starts_with('.') => s,
+                _ => continue,
+            };
+            if !p.is_file() {
+                continue;
+            }
+            let level = stem
+                .parse::<u32>()
+                .map_err(|_| MipMapError::NonNumericName { path: p.clone() })?;
+            files.push((level, p));
+        }
+        // 层号的上限由第 0 层的尺寸决定, 先只读它的文件头
+        let max_level_count = match files.iter().find(|(level, _)| *level == 0) {
+            Some((_, p)) => {
+                let (w, h) =
+                    image::image_dimensions(p).with_context(|| format!("读取 {:?} 失败", p))?;
+                tools::full_mip_level_count(w, h)
+            }
+            // 没有第 0 层就没法知道上限, 先报缺少第 0 层
+            None if !files.is_empty() => bail!(MipMapError::MissingLevel {
+                dir: dir.to_path_buf(),
+                level: 0,
+            }),
+            // 空目录, 后面报 Empty
+            None => 0,
+        };
+        let mut levels: Vec<Option<PathBuf>> = Vec::new();
+        for (level, p) in files {
+            if level >= max_level_count {
+                bail!(MipMapError::LevelOutOfRange {
+                    path: p,
+                    level,
+                    max_level_count,
+                });
+            }
+            let level = level as usize;
+            if levels.len() < level + 1 {
+                levels.resize(level + 1, None);
+            }
+            if levels[level].is_some() {
+                bail!(MipMapError::DuplicateLevel {
+                    dir: dir.to_path_buf(),
+                    level: level as u32,
+                });
+            }
+            levels[level] = Some(p);
+        }
+        if levels.is_empty() {
+            bail!(MipMapError::Empty {
+                dir: dir.to_path_buf()
+            });
+        }
+
         let mut data = Vec::new();
-        let mut width = None;
-        let mut height = None;
-        for e in fs::read_dir(path)? {
-            let e = e?;
-            let p = e.path();
-            if p.is_file() {
-                let level = p
-                    .file_stem()
-                    .unwrap()
-                    .to_os_string()
-                    .into_string()
-                    .unwrap()
-                    .parse::<usize>()?;
-                let img = image::open(p.clone())?;
-                if level == 0 {
-                    width = Some(img.width());
-                    height = Some(img.height());
-                };
-                let buf = img.into_rgba8().into_raw();
-                if data.len() < level + 1 {
-                    for i in data.len()..level + 1 {
-                        data.push(None);
-                    }
-                }
-                data[level] = Some(buf);
+        let mut width = 0;
+        let mut height = 0;
+        let mut color = None;
+        for (level, p) in levels.into_iter().enumerate() {
+            let p = p.ok_or_else(|| MipMapError::MissingLevel {
+                dir: dir.to_path_buf(),
+                level: level as u32,
+            })?;
+            let img = image::open(&p).with_context(|| format!("读取 {:?} 失败", p))?;
+            if level == 0 {
+                width = img.width();
+                height = img.height();
+                color = Some(img.color());
+            }
+            let expected = Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
             }
+            .mip_level_size(level as u32, false);
+            let expected = (expected.width, expected.height);
+            if (img.width(), img.height()) != expected {
+                bail!(MipMapError::WrongSize {
+                    path: p,
+                    level: level as u32,
+                    expected,
+                    actual: (img.width(), img.height()),
+                });
+            }
+            if Some(img.color()) != color {
+                bail!(MipMapError::LevelColorMismatch {
+                    path: p,
+                    expected: format!("{:?}", color.unwrap()),
+                    actual: format!("{:?}", img.color()),
+                });
+            }
+            data.push(img.into_rgba8().into_raw());
         }
-        let data = data.into_iter().map(|d| d.unwrap()).collect();
         Ok(ImageMipMap {
             data,
-            width: width.unwrap(),
-            height: height.unwrap(),
+            width,
+            height,
             format: if is_srgb {
                 TextureFormat::Rgba8UnormSrgb
             } else {
@@ -156,6 +296,148 @@ impl ImageMipMap {
         })
     }
 
+    // 目录按 from_path 读, ktx2 和 dds 按 import 读, 其他文件按 .mip 文件读
+    // 只能是单层的贴图, 多层的 ktx2 和 dds 返回 MipMapError::LayeredTexture, 要读各层用 load_layers
+    // is_srgb 只在文件本身没有记录是否 srgb 时起作用, 例如 mipmap 目录里的图片
+    pub fn load(path: impl AsRef<Path>, is_srgb: bool) -> Result<ImageMipMap> {
+        let path = path.as_ref();
+        let mut layers = Self::load_layers(path, is_srgb)?;
+        if layers.len() != 1 {
+            bail!(MipMapError::LayeredTexture {
+                path: path.to_path_buf(),
+                layers: layers.len(),
+            });
+        }
+        Ok(layers.pop().unwrap())
+    }
+
+    pub fn load_layers(path: impl AsRef<Path>, is_srgb: bool) -> Result<Vec<ImageMipMap>> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            Ok(vec![Self::from_path(path, is_srgb)?])
+        } else if import::is_importable(path) {
+            Self::import(path, is_srgb)
+        } else {
+            Ok(vec![Self::from_file(path)?])
+        }
+    }
+
+    // 格式按文件里记录的, 包括是否 srgb
+    pub fn from_file(path: impl AsRef<Path>) -> Result<ImageMipMap> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).with_context(|| format!("读取 {:?} 失败", path))?;
+        Self::from_bytes(&bytes).with_context(|| format!("解析 {:?} 失败", path))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ImageMipMap> {
+        let mut r = bytes;
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic).context("文件不完整")?;
+        if &magic != MIP_FILE_MAGIC {
+            bail!("不是 mip 文件");
+        }
+        let read_u32 = |r: &mut &[u8]| -> Result<u32> {
+            let mut b = [0u8; 4];
+            r.read_exact(&mut b).context("文件不完整")?;
+            Ok(u32::from_le_bytes(b))
+        };
+        let version = read_u32(&mut r)?;
+        if version != MIP_FILE_VERSION {
+            bail!("不支持的 mip 文件版本 {}", version);
+        }
+        let code = read_u32(&mut r)? as usize;
+        let format = *MIP_FILE_FORMATS
+            .get(code)
+            .ok_or_else(|| anyhow!("未知的格式编号 {}", code))?;
+        let width = read_u32(&mut r)?;
+        let height = read_u32(&mut r)?;
+        let count = read_u32(&mut r)?;
+        let mut mip = ImageMipMap {
+            data: Vec::new(),
+            width,
+            height,
+            format,
+        };
+        for level in 0..count {
+            let len = read_u32(&mut r)? as usize;
+            let expected = mip.level_bytes(level);
+            if len != expected {
+                bail!("第 {} 层应该是 {} 字节, 实际是 {}", level, expected, len);
+            }
+            let mut d = vec![0u8; len];
+            r.read_exact(&mut d).context("文件不完整")?;
+            mip.data.push(d);
+        }
+        if mip.data.is_empty() {
+            bail!("没有任何 mipmap");
+        }
+        Ok(mip)
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path.as_ref(), self.to_bytes()?)
+            .with_context(|| format!("写入 {:?} 失败", path.as_ref()))
+    }
+
+    // .mip 文件的内容
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let code = MIP_FILE_FORMATS
+            .iter()
+            .position(|f| *f == self.format)
+            .ok_or_else(|| anyhow!("{:?} 不能写进 mip 文件", self.format))?;
+        let mut out = MIP_FILE_MAGIC.to_vec();
+        for v in [
+            MIP_FILE_VERSION,
+            code as u32,
+            self.width,
+            self.height,
+            self.mip_map_count(),
+        ] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        for d in &self.data {
+            out.extend_from_slice(&(d.len() as u32).to_le_bytes());
+            out.extend_from_slice(d);
+        }
+        Ok(out)
+    }
+
+    // 某层按格式应有的字节数, 压缩格式按块算
+    pub fn level_bytes(&self, mip_level: u32) -> usize {
+        let (row_bytes, rows) = block_layout(self.format, self.extent_3d(mip_level));
+        row_bytes as usize * rows as usize
+    }
+
+    // texture array 的各层, 格式, 尺寸, mipmap 层数必须一致
+    pub fn validate_array(layers: &[ImageMipMap]) -> Result<()> {
+        let first = match layers.first() {
+            Some(f) => f,
+            None => bail!("texture array 没有任何层"),
+        };
+        for (i, l) in layers.iter().enumerate().skip(1) {
+            if l.format != first.format {
+                bail!(MipMapError::layer_mismatch(i, "格式", first.format, l.format));
+            }
+            if (l.width, l.height) != (first.width, first.height) {
+                bail!(MipMapError::layer_mismatch(
+                    i,
+                    "尺寸",
+                    (first.width, first.height),
+                    (l.width, l.height)
+                ));
+            }
+            if l.mip_map_count() != first.mip_map_count() {
+                bail!(MipMapError::layer_mismatch(
+                    i,
+                    "mipmap 层数",
+                    first.mip_map_count(),
+                    l.mip_map_count()
+                ));
+            }
+        }
+        Ok(())
+    }
+
     pub fn write_to_path(mut self, path: impl AsRef<Path>) -> Result<()> {
         let path = if path.as_ref().exists() {
             if path.as_ref().is_dir() {
@@ -167,9 +449,10 @@ impl ImageMipMap {
             fs::create_dir(path.as_ref())?;
             path
         };
+        let mip_map_count = self.mip_map_count();
         let data = mem::replace(&mut self.data, Vec::new());
         let mut data: Vec<_> = data.into_iter().map(|x| Some(x)).collect();
-        for i in 0..self.mip_map_count() {
+        for i in 0..mip_map_count {
             let size = self.extent_3d(i);
             let mut mip_path = path.as_ref().clone().join(i.to_string());
             mip_path.set_extension("png");
@@ -190,11 +473,7 @@ impl ImageMipMap {
             // 参考 device.create_texture_with_data
             let e = self.extent_3d(mip_level);
             let phy_size = e.physical_size(self.format);
-            let info = self.format.describe();
-
-            let columns = phy_size.width / info.block_dimensions.0 as u32;
-            let rows = phy_size.height / info.block_dimensions.1 as u32;
-            let row_bytes = info.block_size as u32 * columns;
+            let (row_bytes, rows) = block_layout(self.format, e);
 
             let data_layout = ImageDataLayout {
                 offset: 0,
@@ -215,16 +494,57 @@ impl ImageMipMap {
                 },
                 &d[..],
                 data_layout,
-                Extent3d {
-                    width: columns,
-                    height: rows,
-                    depth_or_array_layers: 1,
-                },
+                // 大小按像素算, 压缩格式要用补齐到整块的大小
+                phy_size,
             );
             mip_level += 1;
         }
     }
 }
 
-const UNSUPPORTED: &'static str = "目前不支持";
+// 一层的每行字节数和行数, 压缩格式一行是一排块, 不足一块的补齐到整块
+fn block_layout(format: TextureFormat, extent: Extent3d) -> (u32, u32) {
+    let e = extent.physical_size(format);
+    let info = format.describe();
+    let columns = e.width / info.block_dimensions.0 as u32;
+    let rows = e.height / info.block_dimensions.1 as u32;
+    (columns * info.block_size as u32, rows)
+}
+
+fn with_srgb(format: TextureFormat, is_srgb: bool) -> TextureFormat {
+    let i = MIP_FILE_FORMATS.iter().position(|f| *f == format).unwrap();
+    MIP_FILE_FORMATS[i / 2 * 2 + is_srgb as usize]
+}
+
 const RESOURCE_UNLOADED: &'static str = "资源未加载";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_layouts() {
+        let size = |width, height| Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        assert_eq!(block_layout(TextureFormat::Rgba8Unorm, size(5, 3)), (20, 3));
+        assert_eq!(block_layout(TextureFormat::Bc1RgbaUnorm, size(8, 8)), (16, 2));
+        assert_eq!(block_layout(TextureFormat::Bc7RgbaUnorm, size(8, 4)), (32, 1));
+        // 比一块小的层也占一整块
+        assert_eq!(block_layout(TextureFormat::Bc3RgbaUnorm, size(2, 1)), (16, 1));
+    }
+
+    #[test]
+    fn spirv_words() {
+        let words = [SPIRV_MAGIC, 0x0001_0000, 7];
+        let le: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let be: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        assert_eq!(ShaderData::spirv_words(&le).unwrap(), words);
+        assert_eq!(ShaderData::spirv_words(&be).unwrap(), words);
+        assert!(ShaderData::spirv_words(&le[..10]).is_err());
+        assert!(ShaderData::spirv_words(&[0u8; 8]).is_err());
+        assert!(ShaderData::SpirV(words.to_vec()).as_shader_source().is_err());
+    }
+}
@@ -0,0 +1,639 @@
+// 内容包(mod)
+//
+// 一个内容包是一个目录, 根下有 manifest.toml, 里面声明贴图, 材质, 方块类型, 以及可选的 shader.
+// 包内的路径都是相对于包目录的.
+//
+// 加载顺序: 按 priority 从小到大, priority 相同的按 id 排序. 后加载的优先级高.
+// id 规则:
+// - 包内声明的条目, 不带 ":" 的 key 属于本包的命名空间, 即 "包id:key".
+// - 带 ":" 的 key 表示覆盖别的包的同名条目, 被覆盖的包必须已经先加载, 且条目必须存在.
+// - 引用(材质引用贴图, 方块引用材质)时, 不带 ":" 的先找本包, 再找 "base" 包.
+// 覆盖不改变数字 id, 只替换内容, 这样已经分配出去的 id 保持稳定.
+//
+// 包也可以从资源包文件 (见 bundle 模块) 加载, 这时条目的 path 是包文件里的 key.
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::*;
+use serde::Deserialize;
+
+use crate::{
+    bundle::{self, Bundle},
+    ImageMipMap,
+};
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.toml";
+pub const BASE_PACK_ID: &str = "base";
+pub const ID_SEPARATOR: char = ':';
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub pack: PackInfo,
+    #[serde(default)]
+    pub textures: BTreeMap<String, TextureDecl>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialDecl>,
+    #[serde(default)]
+    pub blocks: BTreeMap<String, BlockDecl>,
+    #[serde(default)]
+    pub shaders: BTreeMap<String, ShaderDecl>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackInfo {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub depends: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TextureDecl {
+    // mipmap 目录, .mip, .ktx2 或 .dds 文件, 见 ImageMipMap::load
+    // 只能是单层的, ktx2 和 dds 的 array 或 cubemap 不支持
+    pub path: PathBuf,
+    // 文件本身记录了是否 srgb 时 (.mip, .ktx2, 新格式的 .dds) 以文件为准
+    #[serde(default = "default_true")]
+    pub srgb: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialDecl {
+    pub texture: String,
+    #[serde(default)]
+    pub blend: BlendMode,
+    // 发光强度 0..=MAX_LIGHT, 用这个材质的方块会照亮周围
+    #[serde(default)]
+    pub light: u8,
+}
+
+// 方块光照的最大等级, 每隔一格减一
+pub const MAX_LIGHT: u8 = 15;
+
+// 材质的混合方式, 数值和 shader 里的 BLEND_* 对应
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode {
+    #[default]
+    Opaque = 0,
+    // alpha 小于一半的像素丢掉, 和不透明的一起画
+    Cutout = 1,
+    // 半透明, 在不透明的之后从远到近画, 不写深度
+    Translucent = 2,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockDecl {
+    // 六个面的默认材质
+    #[serde(default)]
+    pub material: Option<String>,
+    // 单独指定某个面的材质, key 见 FACE_NAMES
+    #[serde(default)]
+    pub faces: BTreeMap<String, String>,
+}
+
+// 方块局部坐标系下的六个面, BlockEntry::faces 也是这个顺序
+pub const FACE_NAMES: [&str; 6] = ["px", "nx", "py", "ny", "pz", "nz"];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShaderDecl {
+    pub path: PathBuf,
+    // WGSL 的变体, 每个是一个 #define, 离线检查时每个变体都预处理一遍
+    #[serde(default)]
+    pub variants: Vec<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Manifest {
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).with_context(|| format!("读取 {:?} 失败", path))?;
+        Self::parse(&text, path)
+    }
+
+    // path 只用于错误信息
+    pub fn parse(text: &str, path: &Path) -> Result<Self> {
+        let manifest: Manifest =
+            toml::from_str(text).with_context(|| format!("解析 {:?} 失败", path))?;
+        manifest.validate().with_context(|| format!("{:?} 不合法", path))?;
+        Ok(manifest)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !is_valid_name(&self.pack.id) {
+            bail!("包 id {:?} 只能由小写字母, 数字, '_' 组成", self.pack.id);
+        }
+        let keys = self
+            .textures
+            .keys()
+            .chain(self.materials.keys())
+            .chain(self.blocks.keys())
+            .chain(self.shaders.keys());
+        if let Some((key, m)) = self.materials.iter().find(|(_, m)| m.light > MAX_LIGHT) {
+            bail!("材质 {:?} 的 light {} 超过了 {}", key, m.light, MAX_LIGHT);
+        }
+        for (key, b) in self.blocks.iter() {
+            if let Some(face) = b.faces.keys().find(|f| !FACE_NAMES.contains(&f.as_str())) {
+                bail!("方块 {:?} 的面 {:?} 不存在, 只能是 {:?}", key, face, FACE_NAMES);
+            }
+            if b.material.is_none() && b.faces.len() < FACE_NAMES.len() {
+                bail!("方块 {:?} 没有 material, 就必须指定全部六个面", key);
+            }
+        }
+        for key in keys {
+            let name = match key.split_once(ID_SEPARATOR) {
+                Some((pack, name)) => {
+                    if !is_valid_name(pack) {
+                        bail!("key {:?} 的包 id 不合法", key);
+                    }
+                    name
+                }
+                None => key,
+            };
+            if !is_valid_name(name) {
+                bail!("key {:?} 只能由小写字母, 数字, '_' 组成", key);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_valid_name(s: &str) -> bool {
+    !s.is_empty()
+        && s
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[derive(Debug)]
+pub struct Pack {
+    pub dir: PathBuf,
+    pub manifest: Manifest,
+}
+
+impl Pack {
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let manifest = Manifest::from_path(dir.join(MANIFEST_FILE_NAME))?;
+        Ok(Self { dir, manifest })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.manifest.pack.id
+    }
+}
+
+pub type TextureId = u32;
+pub type MaterialId = u32;
+pub type BlockId = u32;
+
+#[derive(Debug, Clone)]
+pub struct TextureEntry {
+    pub id: String,
+    pub pack: String,
+    pub path: PathBuf,
+    pub srgb: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaterialEntry {
+    pub id: String,
+    pub pack: String,
+    pub texture: TextureId,
+    pub blend: BlendMode,
+    pub light: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockEntry {
+    pub id: String,
+    pub pack: String,
+    // 顺序见 FACE_NAMES
+    pub faces: [MaterialId; 6],
+}
+
+#[derive(Debug, Clone)]
+pub struct ShaderEntry {
+    pub id: String,
+    pub pack: String,
+    pub path: PathBuf,
+    pub variants: Vec<String>,
+}
+
+// 按 id 查找用的表, 数字 id 就是 Vec 的下标
+#[derive(Debug)]
+pub struct Table<T> {
+    pub entries: Vec<T>,
+    index: HashMap<String, u32>,
+}
+
+impl<T> Default for Table<T> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Table<T> {
+    pub fn get(&self, id: u32) -> Option<&T> {
+        self.entries.get(id as usize)
+    }
+
+    pub fn id_of(&self, key: &str) -> Option<u32> {
+        self.index.get(key).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter()
+    }
+
+    fn insert_or_override(&mut self, key: String, is_override: bool, entry: T) -> Result<u32> {
+        match (self.index.get(&key), is_override) {
+            (Some(&id), true) => {
+                self.entries[id as usize] = entry;
+                Ok(id)
+            }
+            (Some(_), false) => bail!("{:?} 重复定义", key),
+            (None, true) => bail!("要覆盖的 {:?} 不存在", key),
+            (None, false) => {
+                let id = self.entries.len() as u32;
+                self.entries.push(entry);
+                self.index.insert(key, id);
+                Ok(id)
+            }
+        }
+    }
+}
+
+// 所有包合并后的结果
+#[derive(Debug, Default)]
+pub struct PackRegistry {
+    // 加载顺序
+    pub packs: Vec<String>,
+    pub textures: Table<TextureEntry>,
+    pub materials: Table<MaterialEntry>,
+    pub blocks: Table<BlockEntry>,
+    pub shaders: Table<ShaderEntry>,
+    // 从资源包文件加载时才有
+    pub bundle: Option<Arc<Bundle>>,
+}
+
+impl PackRegistry {
+    // 从资源包文件加载, 包内的目录名作为包的路径
+    pub fn load_bundle(path: impl AsRef<Path>) -> Result<Self> {
+        let bundle = Bundle::open(path)?;
+        let mut packs = Vec::new();
+        for dir in bundle.pack_dirs() {
+            let key = format!("{}/{}", dir, MANIFEST_FILE_NAME);
+            let text = std::str::from_utf8(bundle.get(&key).unwrap())
+                .with_context(|| format!("{:?} 不是 utf8", key))?;
+            let manifest = Manifest::parse(text, Path::new(&key))?;
+            packs.push(Pack {
+                dir: PathBuf::from(dir),
+                manifest,
+            });
+        }
+        Self::load_with(packs, Some(Arc::new(bundle)))
+    }
+
+    // 加载 dir 下的所有子目录作为包
+    pub fn load_dir(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut packs = Vec::new();
+        for e in fs::read_dir(dir).with_context(|| format!("读取包目录 {:?} 失败", dir))? {
+            let p = e?.path();
+            if p.is_dir() && p.join(MANIFEST_FILE_NAME).is_file() {
+                packs.push(Pack::from_dir(p)?);
+            }
+        }
+        Self::load(packs)
+    }
+
+    pub fn load(packs: Vec<Pack>) -> Result<Self> {
+        Self::load_with(packs, None)
+    }
+
+    fn load_with(mut packs: Vec<Pack>, bundle: Option<Arc<Bundle>>) -> Result<Self> {
+        packs.sort_by(|a, b| {
+            (a.manifest.pack.priority, a.id()).cmp(&(b.manifest.pack.priority, b.id()))
+        });
+        let mut reg = Self {
+            bundle,
+            ..Default::default()
+        };
+        for pack in packs.iter() {
+            reg.add_pack(pack)
+                .with_context(|| format!("加载包 {:?} ({:?}) 失败", pack.id(), pack.dir))?;
+        }
+        Ok(reg)
+    }
+
+    fn add_pack(&mut self, pack: &Pack) -> Result<()> {
+        let pack_id = pack.id().to_string();
+        if self.packs.contains(&pack_id) {
+            bail!("包 id 重复");
+        }
+        for dep in pack.manifest.pack.depends.iter() {
+            if !self.packs.contains(dep) {
+                bail!("依赖的包 {:?} 不存在, 或者 priority 不比本包小", dep);
+            }
+        }
+        self.packs.push(pack_id.clone());
+
+        let m = &pack.manifest;
+        for (key, decl) in m.textures.iter() {
+            let path = pack.dir.join(&decl.path);
+            if !self.exists(&path) {
+                bail!("贴图 {:?} 的路径 {:?} 不存在", key, path);
+            }
+            let (id, is_override) = self.qualify(&pack_id, key)?;
+            let entry = TextureEntry {
+                id: id.clone(),
+                pack: pack_id.clone(),
+                path,
+                srgb: decl.srgb,
+            };
+            self.textures.insert_or_override(id, is_override, entry)?;
+        }
+        for (key, decl) in m.materials.iter() {
+            let texture = self
+                .resolve(&self.textures, &pack_id, &decl.texture)
+                .with_context(|| format!("材质 {:?} 引用的贴图不存在", key))?;
+            let (id, is_override) = self.qualify(&pack_id, key)?;
+            let entry = MaterialEntry {
+                id: id.clone(),
+                pack: pack_id.clone(),
+                texture,
+                blend: decl.blend,
+                light: decl.light,
+            };
+            self.materials.insert_or_override(id, is_override, entry)?;
+        }
+        for (key, decl) in m.blocks.iter() {
+            let mut faces = [0; 6];
+            for (i, face) in FACE_NAMES.iter().enumerate() {
+                // validate 保证了两者至少有一个
+                let material = decl.faces.get(*face).or(decl.material.as_ref()).unwrap();
+                faces[i] = self
+                    .resolve(&self.materials, &pack_id, material)
+                    .with_context(|| format!("方块 {:?} 的 {} 面引用的材质不存在", key, face))?;
+            }
+            let (id, is_override) = self.qualify(&pack_id, key)?;
+            let entry = BlockEntry {
+                id: id.clone(),
+                pack: pack_id.clone(),
+                faces,
+            };
+            self.blocks.insert_or_override(id, is_override, entry)?;
+        }
+        for (key, decl) in m.shaders.iter() {
+            let path = pack.dir.join(&decl.path);
+            if !self.exists(&path) {
+                bail!("shader {:?} 的文件 {:?} 不存在", key, path);
+            }
+            let (id, is_override) = self.qualify(&pack_id, key)?;
+            let entry = ShaderEntry {
+                id: id.clone(),
+                pack: pack_id.clone(),
+                path,
+                variants: decl.variants.clone(),
+            };
+            self.shaders.insert_or_override(id, is_override, entry)?;
+        }
+        Ok(())
+    }
+
+    // 返回 (完整 id, 是否是覆盖)
+    fn qualify(&self, pack_id: &str, key: &str) -> Result<(String, bool)> {
+        match key.split_once(ID_SEPARATOR) {
+            Some((owner, _)) if owner == pack_id => bail!("{:?}: 不能覆盖本包的条目", key),
+            Some((owner, _)) => {
+                if !self.packs.iter().any(|p| p == owner) {
+                    bail!("{:?}: 要覆盖的包 {:?} 未先加载", key, owner);
+                }
+                Ok((key.to_string(), true))
+            }
+            None => Ok((format!("{}{}{}", pack_id, ID_SEPARATOR, key), false)),
+        }
+    }
+
+    fn resolve<T>(&self, table: &Table<T>, pack_id: &str, key: &str) -> Result<u32> {
+        let candidates = if key.contains(ID_SEPARATOR) {
+            vec![key.to_string()]
+        } else {
+            vec![
+                format!("{}{}{}", pack_id, ID_SEPARATOR, key),
+                format!("{}{}{}", BASE_PACK_ID, ID_SEPARATOR, key),
+            ]
+        };
+        candidates
+            .iter()
+            .find_map(|k| table.id_of(k))
+            .ok_or_else(|| anyhow!("找不到 {:?}", key))
+    }
+
+    pub fn shader_path(&self, id: &str) -> Option<&Path> {
+        let id = self.shaders.id_of(id)?;
+        Some(&self.shaders.get(id)?.path)
+    }
+
+    // 资源包里没有的路径 (例如图集) 从磁盘找
+    pub(crate) fn bundle_key(&self, path: &Path) -> Option<String> {
+        let bundle = self.bundle.as_ref()?;
+        let key = bundle::bundle_key(path).ok()?;
+        bundle.contains(&key).then_some(key)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.bundle_key(path).is_some() || path.exists()
+    }
+
+    pub fn read(&self, path: &Path) -> Result<Cow<'_, [u8]>> {
+        match self.bundle_key(path) {
+            Some(key) => Ok(Cow::Borrowed(self.bundle.as_ref().unwrap().get(&key).unwrap())),
+            None => Ok(Cow::Owned(
+                fs::read(path).with_context(|| format!("读取 {:?} 失败", path))?,
+            )),
+        }
+    }
+
+    pub fn load_texture(&self, path: &Path, is_srgb: bool) -> Result<ImageMipMap> {
+        match self.bundle_key(path) {
+            Some(key) => self.bundle.as_ref().unwrap().load_texture(&key),
+            None => ImageMipMap::load(path, is_srgb),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_pack(name: &str, manifest: &str, dirs: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("resource_pack_test_{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for d in dirs {
+            fs::create_dir_all(dir.join(d)).unwrap();
+        }
+        fs::write(dir.join(MANIFEST_FILE_NAME), manifest).unwrap();
+        dir
+    }
+
+    const BASE: &str = r#"
+        [pack]
+        id = "base"
+        [textures]
+        stone = { path = "image/stone" }
+        dirt = { path = "image/dirt" }
+        [materials]
+        stone = { texture = "stone" }
+        [blocks]
+        stone = { material = "stone" }
+    "#;
+
+    #[test]
+    fn load_and_override() {
+        let base = temp_pack("override_base", BASE, &["image/stone", "image/dirt"]);
+        let hd = temp_pack(
+            "override_hd",
+            r#"
+            [pack]
+            id = "hd"
+            priority = 10
+            depends = ["base"]
+            [textures]
+            "base:stone" = { path = "image/stone" }
+            [materials]
+            marble = { texture = "dirt", blend = "translucent", light = 12 }
+            "#,
+            &["image/stone"],
+        );
+        let packs = vec![Pack::from_dir(hd).unwrap(), Pack::from_dir(base).unwrap()];
+        let reg = PackRegistry::load(packs).unwrap();
+        assert_eq!(reg.packs, vec!["base", "hd"]);
+
+        let stone = reg.textures.id_of("base:stone").unwrap();
+        assert_eq!(reg.textures.len(), 2);
+        assert_eq!(reg.textures.get(stone).unwrap().pack, "hd");
+        let dirt = reg.textures.id_of("base:dirt").unwrap();
+        let marble = reg.materials.id_of("hd:marble").unwrap();
+        assert_eq!(reg.materials.get(marble).unwrap().texture, dirt);
+        assert_eq!(reg.materials.get(marble).unwrap().blend, BlendMode::Translucent);
+        assert_eq!(reg.materials.get(marble).unwrap().light, 12);
+        let stone = reg.materials.id_of("base:stone").unwrap();
+        assert_eq!(reg.materials.get(stone).unwrap().blend, BlendMode::Opaque);
+    }
+
+    #[test]
+    fn block_faces() {
+        let base = temp_pack(
+            "block_faces",
+            r#"
+            [pack]
+            id = "base"
+            [textures]
+            log = { path = "image/log" }
+            [materials]
+            bark = { texture = "log" }
+            ring = { texture = "log" }
+            [blocks]
+            log = { material = "bark", faces = { py = "ring", ny = "ring" } }
+            "#,
+            &["image/log"],
+        );
+        let reg = PackRegistry::load(vec![Pack::from_dir(base).unwrap()]).unwrap();
+        let bark = reg.materials.id_of("base:bark").unwrap();
+        let ring = reg.materials.id_of("base:ring").unwrap();
+        let log = reg.blocks.get(reg.blocks.id_of("base:log").unwrap()).unwrap();
+        assert_eq!(log.faces, [bark, bark, ring, ring, bark, bark]);
+
+        let bad = temp_pack(
+            "block_faces_bad",
+            r#"
+            [pack]
+            id = "base"
+            [blocks]
+            log = { faces = { top = "ring" } }
+            "#,
+            &[],
+        );
+        assert!(Pack::from_dir(bad).is_err());
+        let bad = temp_pack(
+            "material_light_bad",
+            r#"
+            [pack]
+            id = "base"
+            [materials]
+            lamp = { texture = "lamp", light = 16 }
+            "#,
+            &[],
+        );
+        assert!(Pack::from_dir(bad).is_err());
+    }
+
+    #[test]
+    fn reject_bad_reference() {
+        let base = temp_pack(
+            "bad_ref",
+            r#"
+            [pack]
+            id = "base"
+            [materials]
+            stone = { texture = "missing" }
+            "#,
+            &[],
+        );
+        let err = PackRegistry::load(vec![Pack::from_dir(base).unwrap()]).unwrap_err();
+        assert!(format!("{:#}", err).contains("missing"));
+    }
+
+    #[test]
+    fn reject_override_before_load() {
+        let base = temp_pack("early_base", BASE, &["image/stone", "image/dirt"]);
+        let early = temp_pack(
+            "early_mod",
+            r#"
+            [pack]
+            id = "early"
+            priority = -1
+            [materials]
+            "base:stone" = { texture = "base:dirt" }
+            "#,
+            &[],
+        );
+        let packs = vec![Pack::from_dir(base).unwrap(), Pack::from_dir(early).unwrap()];
+        assert!(PackRegistry::load(packs).is_err());
+    }
+}
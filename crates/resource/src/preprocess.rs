@@ -0,0 +1,283 @@
+// WGSL 的预处理: #include, #define, #ifdef
+//
+// 指令必须单独占一行, 行首可以有空白:
+//   #include "相对当前文件的路径"   同一个文件只会被包含一次
+//   #define NAME [值]              有值时, 后面代码里整词出现的 NAME 替换成值
+//   #undef NAME
+//   #ifdef NAME / #ifndef NAME / #else / #endif
+// 输出的每一行都记录了来自哪个文件的哪一行, 用来把 naga 报错里的 wgsl:行:列 换回原文件的位置.
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::*;
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    pub files: Vec<PathBuf>,
+    // 输出的第 i 行 (从 0 开始) 对应 (files 的下标, 原文件的行号, 从 1 开始)
+    lines: Vec<(usize, u32)>,
+}
+
+impl SourceMap {
+    // line 从 1 开始
+    pub fn source_of(&self, line: u32) -> Option<(&Path, u32)> {
+        let &(file, line) = self.lines.get(line.checked_sub(1)? as usize)?;
+        Some((&self.files[file], line))
+    }
+
+    // 把 "wgsl:行:列" 换成 "文件:行:列"
+    pub fn map_message(&self, msg: &str) -> String {
+        const TAG: &str = "wgsl:";
+        let mut out = String::new();
+        let mut rest = msg;
+        while let Some(i) = rest.find(TAG) {
+            out.push_str(&rest[..i]);
+            let after = &rest[i + TAG.len()..];
+            let digits = after
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(after.len());
+            match after[..digits].parse().ok().and_then(|l| self.source_of(l)) {
+                Some((path, line)) => {
+                    out.push_str(&format!("{}:{}", path.display(), line));
+                    rest = &after[digits..];
+                }
+                None => {
+                    out.push_str(TAG);
+                    rest = after;
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Preprocessed {
+    pub code: String,
+    pub map: SourceMap,
+}
+
+// read 负责读文件, 这样包目录和资源包都能用
+pub fn preprocess(
+    path: impl AsRef<Path>,
+    defines: &[(&str, &str)],
+    read: impl FnMut(&Path) -> Result<String>,
+) -> Result<Preprocessed> {
+    let mut p = Preprocessor {
+        defines: defines
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        included: HashSet::new(),
+        read: Box::new(read),
+        out: Preprocessed {
+            code: String::new(),
+            map: SourceMap::default(),
+        },
+    };
+    p.include(&normalize(path.as_ref()))?;
+    Ok(p.out)
+}
+
+type ReadFn<'a> = Box<dyn FnMut(&Path) -> Result<String> + 'a>;
+
+struct Preprocessor<'a> {
+    defines: BTreeMap<String, String>,
+    included: HashSet<PathBuf>,
+    read: ReadFn<'a>,
+    out: Preprocessed,
+}
+
+impl<'a> Preprocessor<'a> {
+    fn include(&mut self, path: &Path) -> Result<()> {
+        if !self.included.insert(path.to_path_buf()) {
+            return Ok(());
+        }
+        let text = (self.read)(path).with_context(|| format!("读取 {:?} 失败", path))?;
+        let file = self.out.map.files.len();
+        self.out.map.files.push(path.to_path_buf());
+        // 每层 #ifdef 是 (本层是否启用, 外层是否启用, 是否已经有 #else)
+        let mut stack: Vec<(bool, bool, bool)> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i as u32 + 1;
+            let active = stack.last().is_none_or(|s| s.0);
+            let err = |msg: String| anyhow!("{}:{}: {}", path.display(), line_no, msg);
+            let trimmed = line.trim_start();
+            if let Some(directive) = trimmed.strip_prefix('#') {
+                let mut parts = directive.split_whitespace();
+                let name = parts.next().unwrap_or("");
+                let arg = parts.next();
+                match name {
+                    "ifdef" | "ifndef" => {
+                        let arg = arg.ok_or_else(|| err(format!("#{} 缺少名字", name)))?;
+                        let defined = self.defines.contains_key(arg);
+                        stack.push((active && defined == (name == "ifdef"), active, false));
+                    }
+                    "else" => {
+                        let top = stack
+                            .last_mut()
+                            .ok_or_else(|| err("#else 没有对应的 #ifdef".to_string()))?;
+                        if top.2 {
+                            bail!(err("重复的 #else".to_string()));
+                        }
+                        *top = (top.1 && !top.0, top.1, true);
+                    }
+                    "endif" => {
+                        stack
+                            .pop()
+                            .ok_or_else(|| err("#endif 没有对应的 #ifdef".to_string()))?;
+                    }
+                    _ if !active => {}
+                    "define" => {
+                        let arg = arg.ok_or_else(|| err("#define 缺少名字".to_string()))?;
+                        let value = parts.collect::<Vec<_>>().join(" ");
+                        self.defines.insert(arg.to_string(), value);
+                    }
+                    "undef" => {
+                        let arg = arg.ok_or_else(|| err("#undef 缺少名字".to_string()))?;
+                        self.defines.remove(arg);
+                    }
+                    "include" => {
+                        let rest = directive["include".len()..].trim();
+                        let target = rest
+                            .strip_prefix('"')
+                            .and_then(|r| r.strip_suffix('"'))
+                            .ok_or_else(|| err("#include 的路径要用双引号".to_string()))?;
+                        let target =
+                            normalize(&path.parent().unwrap_or(Path::new("")).join(target));
+                        self.include(&target)
+                            .with_context(|| format!("{}:{}", path.display(), line_no))?;
+                    }
+                    _ => bail!(err(format!("未知的指令 #{}", name))),
+                }
+                continue;
+            }
+            if active {
+                let line = self.substitute(line);
+                self.out.code.push_str(&line);
+                self.out.code.push('\n');
+                self.out.map.lines.push((file, line_no));
+            }
+        }
+        if !stack.is_empty() {
+            bail!(
+                "{}: 有 {} 个 #ifdef 没有 #endif",
+                path.display(),
+                stack.len()
+            );
+        }
+        Ok(())
+    }
+
+    // 整词替换有值的 define, 注释里的不管
+    fn substitute(&self, line: &str) -> String {
+        if self.defines.values().all(|v| v.is_empty()) {
+            return line.to_string();
+        }
+        let (code, comment) = match line.find("//") {
+            Some(i) => line.split_at(i),
+            None => (line, ""),
+        };
+        let mut out = String::new();
+        let mut word = String::new();
+        let flush = |word: &mut String, out: &mut String| {
+            match self.defines.get(word.as_str()) {
+                Some(v) if !v.is_empty() => out.push_str(v),
+                _ => out.push_str(word),
+            }
+            word.clear();
+        };
+        for c in code.chars() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                word.push(c);
+            } else {
+                flush(&mut word, &mut out);
+                out.push(c);
+            }
+        }
+        flush(&mut word, &mut out);
+        out.push_str(comment);
+        out
+    }
+}
+
+// 去掉路径里的 . 和 .., 资源包的 key 和 registry 里的路径都是这种形式
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            // 开头的 .. 要保留, 不能互相抵消
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    out.pop();
+                }
+                _ => out.push(".."),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn run(files: &[(&str, &str)], defines: &[(&str, &str)]) -> Result<Preprocessed> {
+        let files: HashMap<PathBuf, String> = files
+            .iter()
+            .map(|(k, v)| (PathBuf::from(k), v.to_string()))
+            .collect();
+        preprocess("shader/main.wgsl", defines, |p| {
+            files.get(p).cloned().ok_or_else(|| anyhow!("没有 {:?}", p))
+        })
+    }
+
+    #[test]
+    fn include_once_and_line_map() {
+        let out = run(
+            &[
+                (
+                    "shader/main.wgsl",
+                    "#include \"common/a.wgsl\"\n#include \"common/a.wgsl\"\nfn main() {}",
+                ),
+                (
+                    "shader/common/a.wgsl",
+                    "// a\n#include \"../b.wgsl\"\nfn a() {}",
+                ),
+                ("shader/b.wgsl", "fn b() {}"),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(out.code, "// a\nfn b() {}\nfn a() {}\nfn main() {}\n");
+        let (path, line) = out.map.source_of(3).unwrap();
+        assert_eq!((path, line), (Path::new("shader/common/a.wgsl"), 3));
+        assert_eq!(
+            out.map.map_message("error ┌─ wgsl:4:1"),
+            "error ┌─ shader/main.wgsl:3:1"
+        );
+        assert_eq!(
+            normalize(Path::new("../../a/./b/../c")),
+            Path::new("../../a/c")
+        );
+    }
+
+    #[test]
+    fn defines_and_branches() {
+        let src = "#define N 4\n#ifdef OPAQUE\nlet a = N;\n#else\nlet a = 0;\n#endif\n#ifndef OPAQUE\nlet b = N_1; // N\n#endif";
+        let out = run(&[("shader/main.wgsl", src)], &[("OPAQUE", "")]).unwrap();
+        assert_eq!(out.code, "let a = 4;\n");
+        let out = run(&[("shader/main.wgsl", src)], &[]).unwrap();
+        assert_eq!(out.code, "let a = 0;\nlet b = N_1; // N\n");
+
+        assert!(run(&[("shader/main.wgsl", "#ifdef A\n")], &[]).is_err());
+        assert!(run(&[("shader/main.wgsl", "#endif\n")], &[]).is_err());
+        assert!(run(&[("shader/main.wgsl", "#pragma x\n")], &[]).is_err());
+    }
+}
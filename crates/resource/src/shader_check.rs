@@ -0,0 +1,290 @@
+// 用 naga 离线检查 shader, 不用等到开窗口后 wgpu panic 才发现写错了
+//
+// 检查内容: 语法和验证, 入口函数的名字和阶段, 以及 vertex 输入和 Rust 里的顶点布局是否一致.
+// 顶点布局只有渲染代码知道, 所以 resource 命令只检查前两项, 第三项在创建管线前检查.
+use std::path::Path;
+
+use anyhow::*;
+use naga::{
+    valid::{Capabilities, ValidationFlags, Validator},
+    Binding, Module, ScalarKind, ShaderStage, TypeInner, VectorSize,
+};
+use wgpu::{VertexAttribute, VertexFormat};
+
+use crate::{
+    pack::PackRegistry,
+    preprocess::{preprocess, Preprocessed, SourceMap},
+    validate::Report,
+    Shader, ShaderData, ShaderType,
+};
+
+// 普通的渲染 shader 都要有的入口
+pub const RENDER_ENTRY_POINTS: [(&str, ShaderStage); 2] = [
+    (Shader::VS_FUNC_NAME, ShaderStage::Vertex),
+    (Shader::FS_FUNC_NAME, ShaderStage::Fragment),
+];
+
+fn validate(
+    module: &Module,
+) -> std::result::Result<(), naga::WithSpan<naga::valid::ValidationError>> {
+    Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(module)
+        .map(|_| ())
+}
+
+// 报错信息里的行号会按 map 换回原文件
+pub fn check_wgsl(code: &str, map: Option<&SourceMap>) -> Result<Module> {
+    let fix = |msg: String| match map {
+        Some(map) => map.map_message(&msg),
+        None => msg,
+    };
+    let module = naga::front::wgsl::parse_str(code)
+        .map_err(|e| anyhow!("WGSL 解析失败:\n{}", fix(e.emit_to_string(code))))?;
+    validate(&module).map_err(|e| anyhow!("WGSL 验证失败:\n{}", fix(e.emit_to_string(code))))?;
+    Ok(module)
+}
+
+pub fn check_spirv(words: &[u32]) -> Result<Module> {
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    let module = naga::front::spv::parse_u8_slice(&bytes, &Default::default())
+        .map_err(|e| anyhow!("SPIR-V 解析失败: {}", e))?;
+    validate(&module).map_err(|e| anyhow!("SPIR-V 验证失败: {}", e))?;
+    Ok(module)
+}
+
+pub fn check_entry_points(module: &Module, entries: &[(&str, ShaderStage)]) -> Result<()> {
+    for (name, stage) in entries {
+        match module.entry_points.iter().find(|e| e.name == *name) {
+            Some(e) if e.stage == *stage => {}
+            Some(e) => bail!("入口 {:?} 应该是 {:?}, 实际是 {:?}", name, stage, e.stage),
+            None => bail!(
+                "缺少入口 {:?} ({:?}), 现有的入口: {:?}",
+                name,
+                stage,
+                module
+                    .entry_points
+                    .iter()
+                    .map(|e| &e.name)
+                    .collect::<Vec<_>>()
+            ),
+        }
+    }
+    Ok(())
+}
+
+// (标量类型, 分量数)
+type InputType = (ScalarKind, u32);
+
+// 顶点格式在 shader 里对应的类型
+fn vertex_format_type(format: VertexFormat) -> InputType {
+    use ScalarKind::*;
+    use VertexFormat::*;
+    match format {
+        Uint8x2 | Uint16x2 | Uint32x2 => (Uint, 2),
+        Uint8x4 | Uint16x4 | Uint32x4 => (Uint, 4),
+        Uint32 => (Uint, 1),
+        Uint32x3 => (Uint, 3),
+        Sint8x2 | Sint16x2 | Sint32x2 => (Sint, 2),
+        Sint8x4 | Sint16x4 | Sint32x4 => (Sint, 4),
+        Sint32 => (Sint, 1),
+        Sint32x3 => (Sint, 3),
+        Float32 | Float64 => (Float, 1),
+        Float32x3 | Float64x3 => (Float, 3),
+        Unorm8x2 | Snorm8x2 | Unorm16x2 | Snorm16x2 | Float16x2 | Float32x2 | Float64x2 => {
+            (Float, 2)
+        }
+        Unorm8x4 | Snorm8x4 | Unorm16x4 | Snorm16x4 | Float16x4 | Float32x4 | Float64x4 => {
+            (Float, 4)
+        }
+    }
+}
+
+fn type_name(kind: ScalarKind, count: u32) -> String {
+    let s = match kind {
+        ScalarKind::Uint => "u32",
+        ScalarKind::Sint => "i32",
+        ScalarKind::Float => "f32",
+        ScalarKind::Bool => "bool",
+    };
+    match count {
+        1 => s.to_string(),
+        n => format!("vec{}<{}>", n, s),
+    }
+}
+
+// entry 的所有 @location 输入, 包括结构体参数里的
+fn vertex_inputs(module: &Module, entry: &str) -> Result<Vec<(u32, Option<InputType>)>> {
+    let ep = module
+        .entry_points
+        .iter()
+        .find(|e| e.name == entry && e.stage == ShaderStage::Vertex)
+        .ok_or_else(|| anyhow!("缺少 vertex 入口 {:?}", entry))?;
+    let ty_of = |ty| match module.types[ty].inner {
+        TypeInner::Scalar { kind, .. } => Some((kind, 1)),
+        TypeInner::Vector { size, kind, .. } => Some((
+            kind,
+            match size {
+                VectorSize::Bi => 2,
+                VectorSize::Tri => 3,
+                VectorSize::Quad => 4,
+            },
+        )),
+        _ => None,
+    };
+    let mut inputs = Vec::new();
+    for arg in ep.function.arguments.iter() {
+        match &arg.binding {
+            Some(Binding::Location { location, .. }) => inputs.push((*location, ty_of(arg.ty))),
+            Some(Binding::BuiltIn(_)) => {}
+            None => {
+                if let TypeInner::Struct { members, .. } = &module.types[arg.ty].inner {
+                    for m in members {
+                        if let Some(Binding::Location { location, .. }) = m.binding {
+                            inputs.push((location, ty_of(m.ty)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(inputs)
+}
+
+// shader 的每个输入都要在布局里有同样类型的属性, 布局里多出来的属性可以不用
+pub fn check_vertex_inputs(module: &Module, entry: &str, attrs: &[VertexAttribute]) -> Result<()> {
+    let mut errors = Vec::new();
+    for (location, ty) in vertex_inputs(module, entry)? {
+        let attr = match attrs.iter().find(|a| a.shader_location == location) {
+            Some(a) => a,
+            None => {
+                errors.push(format!("location {}: Rust 的顶点布局里没有", location));
+                continue;
+            }
+        };
+        let expected = vertex_format_type(attr.format);
+        match ty {
+            Some(ty) if ty == expected => {}
+            Some(ty) => errors.push(format!(
+                "location {}: shader 里是 {}, Rust 里是 {:?}, 对应 {}",
+                location,
+                type_name(ty.0, ty.1),
+                attr.format,
+                type_name(expected.0, expected.1)
+            )),
+            None => errors.push(format!("location {}: 不是标量或向量", location)),
+        }
+    }
+    if !errors.is_empty() {
+        bail!("vertex 输入与顶点布局不一致:\n{}", errors.join("\n"));
+    }
+    Ok(())
+}
+
+// 创建管线前用: 检查预处理后的 WGSL, 入口, 以及 vertex 输入和 attrs 是否一致
+pub fn check_render_wgsl(p: &Preprocessed, attrs: &[VertexAttribute]) -> Result<()> {
+    let module = check_wgsl(&p.code, Some(&p.map))?;
+    check_entry_points(&module, &RENDER_ENTRY_POINTS)?;
+    check_vertex_inputs(&module, Shader::VS_FUNC_NAME, attrs)
+}
+
+// 检查注册表里所有的 shader, WGSL 的每个变体都检查一遍
+pub fn check_registry(registry: &PackRegistry) -> Report {
+    let mut report = Report::default();
+    for s in registry.shaders.iter() {
+        match ShaderType::from_path(&s.path) {
+            ShaderType::Wgsl => {
+                let variants: Vec<Option<&str>> = if s.variants.is_empty() {
+                    vec![None]
+                } else {
+                    s.variants.iter().map(|v| Some(v.as_str())).collect()
+                };
+                for v in variants {
+                    report.checked += 1;
+                    report.check(&s.path, || {
+                        check_wgsl_file(registry, &s.path, v)
+                            .with_context(|| format!("shader {:?} 变体 {:?}", s.id, v))
+                    });
+                }
+            }
+            ShaderType::SpirV => {
+                report.checked += 1;
+                report.check(&s.path, || {
+                    let data = ShaderData::from_bytes(
+                        ShaderType::SpirV,
+                        registry.read(&s.path)?.into_owned(),
+                    )?;
+                    if let ShaderData::SpirV(words) = data {
+                        check_entry_points(&check_spirv(&words)?, &RENDER_ENTRY_POINTS)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+    }
+    report
+}
+
+fn check_wgsl_file(registry: &PackRegistry, path: &Path, variant: Option<&str>) -> Result<()> {
+    let defines: Vec<(&str, &str)> = variant.into_iter().map(|v| (v, "")).collect();
+    let p = preprocess(path, &defines, |p| {
+        Ok(String::from_utf8(registry.read(p)?.into_owned())?)
+    })?;
+    let module = check_wgsl(&p.code, Some(&p.map))?;
+    check_entry_points(&module, &RENDER_ENTRY_POINTS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = "
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+};
+
+@vertex
+fn vertex_main(model: VertexInput, @location(2) info: vec4<u32>) -> @builtin(position) vec4<f32> {
+    return vec4<f32>(model.position, f32(info.x));
+}
+
+@fragment
+fn fragment_main() -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0);
+}
+";
+
+    fn attr(location: u32, format: VertexFormat) -> VertexAttribute {
+        VertexAttribute {
+            format,
+            offset: 0,
+            shader_location: location,
+        }
+    }
+
+    #[test]
+    fn entry_points_and_inputs() {
+        let module = check_wgsl(SRC, None).unwrap();
+        check_entry_points(&module, &RENDER_ENTRY_POINTS).unwrap();
+        assert!(check_entry_points(&module, &[("main", ShaderStage::Compute)]).is_err());
+
+        let mut attrs = vec![
+            attr(0, VertexFormat::Float32x3),
+            attr(1, VertexFormat::Float32x2),
+            attr(2, VertexFormat::Uint8x4),
+            attr(5, VertexFormat::Float32),
+        ];
+        check_vertex_inputs(&module, Shader::VS_FUNC_NAME, &attrs).unwrap();
+        attrs[2].format = VertexFormat::Unorm8x4;
+        let e = check_vertex_inputs(&module, Shader::VS_FUNC_NAME, &attrs).unwrap_err();
+        assert!(e.to_string().contains("location 2"), "{}", e);
+        attrs.remove(0);
+        assert!(check_vertex_inputs(&module, Shader::VS_FUNC_NAME, &attrs).is_err());
+    }
+
+    #[test]
+    fn syntax_error_line() {
+        let e = check_wgsl("fn f() {\n    let x = ;\n}\n", None).unwrap_err();
+        assert!(e.to_string().contains("wgsl:2:"), "{}", e);
+    }
+}
@@ -1,44 +1,337 @@
 use crate::*;
-use image::*;
 
-pub fn generate_rgba8_mip_map(image: Image, mip_map_count: u32) -> Result<ImageMipMap> {
+// 生成 mipmap 时的降采样滤波器
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MipFilter {
+    Box,
+    Triangle,
+    Lanczos3,
+}
+
+impl MipFilter {
+    // 采样半径, 单位是目标像素
+    fn support(&self) -> f32 {
+        match self {
+            MipFilter::Box => 0.5,
+            MipFilter::Triangle => 1.0,
+            MipFilter::Lanczos3 => 3.0,
+        }
+    }
+
+    fn weight(&self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            MipFilter::Box => {
+                if x <= 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            MipFilter::Triangle => (1.0 - x).max(0.0),
+            MipFilter::Lanczos3 => {
+                if x < 3.0 {
+                    sinc(x) * sinc(x / 3.0)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for MipFilter {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "box" => Ok(MipFilter::Box),
+            "triangle" => Ok(MipFilter::Triangle),
+            "lanczos" | "lanczos3" => Ok(MipFilter::Lanczos3),
+            _ => bail!("未知的滤波器 {:?}, 可选: box, triangle, lanczos", s),
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-6 {
+        1.0
+    } else {
+        let x = x * std::f32::consts::PI;
+        x.sin() / x
+    }
+}
+
+// alpha 的处理方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MipAlpha {
+    // alpha 和颜色各自独立滤波
+    Straight,
+    // 颜色先乘 alpha 再滤波, 避免透明像素的颜色渗到边缘
+    Premultiplied,
+    // 在 Premultiplied 的基础上, 调整每层的 alpha, 使 alpha >= cutoff 的比例和第 0 层一致.
+    // 用于 alpha test 的贴图(叶子, 栅栏), 否则远处会越来越透明.
+    Coverage { cutoff: f32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct MipMapConfig {
+    pub filter: MipFilter,
+    pub alpha: MipAlpha,
+    // None 表示生成到 1x1 为止
+    pub mip_level_count: Option<u32>,
+}
+
+impl Default for MipMapConfig {
+    fn default() -> Self {
+        Self {
+            filter: MipFilter::Triangle,
+            alpha: MipAlpha::Premultiplied,
+            mip_level_count: None,
+        }
+    }
+}
+
+// 生成到 1x1 需要的层数, 非 2 的幂时每层向下取整, 与 Extent3d::mip_level_size 一致
+pub fn full_mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// 线性空间的 rgba 浮点图
+struct LinearImage {
+    width: u32,
+    height: u32,
+    data: Vec<[f32; 4]>,
+}
+
+impl LinearImage {
+    fn from_rgba8(data: &[u8], width: u32, height: u32, is_srgb: bool) -> Self {
+        let data = data
+            .chunks_exact(4)
+            .map(|p| {
+                let mut c = [0f32; 4];
+                for (i, (c, p)) in c.iter_mut().zip(p).enumerate() {
+                    *c = *p as f32 / 255.0;
+                    if is_srgb && i < 3 {
+                        *c = srgb_to_linear(*c);
+                    }
+                }
+                c
+            })
+            .collect();
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    fn to_rgba8(&self, is_srgb: bool) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.data.len() * 4);
+        for p in self.data.iter() {
+            for (i, c) in p.iter().enumerate() {
+                let mut c = c.clamp(0.0, 1.0);
+                if is_srgb && i < 3 {
+                    c = linear_to_srgb(c);
+                }
+                out.push((c * 255.0).round() as u8);
+            }
+        }
+        out
+    }
+
+    fn premultiply(&mut self) {
+        for p in self.data.iter_mut() {
+            let a = p[3];
+            p[..3].iter_mut().for_each(|c| *c *= a);
+        }
+    }
+
+    fn unpremultiply(&mut self) {
+        for p in self.data.iter_mut() {
+            let a = p[3];
+            if a > 1e-6 {
+                p[..3].iter_mut().for_each(|c| *c /= a);
+            }
+        }
+    }
+
+    fn coverage(&self, cutoff: f32, scale: f32) -> f32 {
+        let n = self.data.iter().filter(|p| p[3] * scale >= cutoff).count();
+        n as f32 / self.data.len() as f32
+    }
+
+    // 二分查找 alpha 的缩放, 使覆盖率接近 target
+    fn preserve_coverage(&mut self, cutoff: f32, target: f32) {
+        let (mut lo, mut hi) = (0f32, 4f32);
+        for _ in 0..16 {
+            let mid = (lo + hi) / 2.0;
+            if self.coverage(cutoff, mid) < target {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        // 取 hi, 保证覆盖率不低于 target
+        let scale = hi;
+        for p in self.data.iter_mut() {
+            p[3] = (p[3] * scale).min(1.0);
+        }
+    }
+
+    // 可分离的重采样, 先横后竖
+    fn resample(&self, width: u32, height: u32, filter: MipFilter) -> Self {
+        let tmp = resample_axis(&self.data, self.width, self.height, width, true, filter);
+        let data = resample_axis(&tmp, width, self.height, height, false, filter);
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+// 沿一个轴把长度 src_len 缩到 dst_len, 边缘按 clamp 处理
+fn resample_axis(
+    src: &[[f32; 4]],
+    width: u32,
+    height: u32,
+    dst_len: u32,
+    horizontal: bool,
+    filter: MipFilter,
+) -> Vec<[f32; 4]> {
+    let (src_len, other) = if horizontal {
+        (width, height)
+    } else {
+        (height, width)
+    };
+    let scale = src_len as f32 / dst_len as f32;
+    let radius = filter.support() * scale.max(1.0);
+
+    // 每个目标像素的 (起点, 权重)
+    let kernels: Vec<(i64, Vec<f32>)> = (0..dst_len)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * scale;
+            let start = (center - radius).floor() as i64;
+            let end = (center + radius).ceil() as i64;
+            let mut weights: Vec<f32> = (start..end)
+                .map(|j| filter.weight((j as f32 + 0.5 - center) / scale.max(1.0)))
+                .collect();
+            let sum: f32 = weights.iter().sum();
+            if sum.abs() > 1e-6 {
+                weights.iter_mut().for_each(|w| *w /= sum);
+            }
+            (start, weights)
+        })
+        .collect();
+
+    let (dst_w, dst_h) = if horizontal {
+        (dst_len, height)
+    } else {
+        (width, dst_len)
+    };
+    let mut dst = vec![[0f32; 4]; (dst_w * dst_h) as usize];
+    for o in 0..other {
+        for (i, (start, weights)) in kernels.iter().enumerate() {
+            let mut acc = [0f32; 4];
+            for (k, w) in weights.iter().enumerate() {
+                let j = (start + k as i64).clamp(0, src_len as i64 - 1) as u32;
+                let idx = if horizontal {
+                    o * width + j
+                } else {
+                    j * width + o
+                };
+                let p = src[idx as usize];
+                acc.iter_mut().zip(p).for_each(|(a, p)| *a += p * w);
+            }
+            let idx = if horizontal {
+                o * dst_w + i as u32
+            } else {
+                i as u32 * dst_w + o
+            };
+            dst[idx as usize] = acc;
+        }
+    }
+    dst
+}
+
+fn is_srgb_format(format: TextureFormat) -> bool {
+    matches!(
+        format,
+        TextureFormat::Rgba8UnormSrgb | TextureFormat::Bgra8UnormSrgb
+    )
+}
+
+pub fn generate_rgba8_mip_map(image: Image, config: &MipMapConfig) -> Result<ImageMipMap> {
     match image.format {
-        TextureFormat::Rgba8Unorm
-        | TextureFormat::Rgba8UnormSrgb
-        | TextureFormat::Rgba8Snorm
-        | TextureFormat::Rgba8Uint
-        | TextureFormat::Rgba8Sint => {}
-        _ => panic!("这是rgba8专用的生成mipmap的方法, 否则得做映射很麻烦, 先这样"),
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {}
+        _ => bail!(
+            "这是 rgba8 unorm 专用的生成 mipmap 的方法, 不支持 {:?}",
+            image.format
+        ),
     };
     let format = image.format;
+    let is_srgb = is_srgb_format(format);
     let size = Extent3d {
         width: image.width,
         height: image.height,
         depth_or_array_layers: 1,
     };
-    let mut mips = vec![ImageBuffer::from_vec(image.width, image.height, image.data).unwrap()];
+    let full = full_mip_level_count(image.width, image.height);
+    let mip_map_count = config.mip_level_count.unwrap_or(full);
+    if mip_map_count == 0 || mip_map_count > full {
+        bail!(
+            "{}x{} 的图最多 {} 层 mipmap, 要求的是 {}",
+            image.width,
+            image.height,
+            full,
+            mip_map_count
+        );
+    }
+
+    let mut base = LinearImage::from_rgba8(&image.data, image.width, image.height, is_srgb);
+    let coverage = match config.alpha {
+        MipAlpha::Coverage { cutoff } => Some((cutoff, base.coverage(cutoff, 1.0))),
+        _ => None,
+    };
+    if config.alpha != MipAlpha::Straight {
+        base.premultiply();
+    }
+
+    let mut mips = vec![image.data];
+    let mut prev = base;
     for mip_i in 1..mip_map_count {
         let mip_size = size.mip_level_size(mip_i, false);
-        let width = mip_size.width;
-        let height = mip_size.height;
-        let mut mip = ImageBuffer::new(width, height);
-        for x in 0..width {
-            for y in 0..height {
-                let mut sum = Rgba([0u8; 4]);
-                for dx in 0..2 {
-                    for dy in 0..2 {
-                        let p: &Rgba<u8> = mips[mip_i as usize - 1].get_pixel(x * 2 + dx, y * 2 + dy);
-                        for c in 0..4 {
-                            sum.0[c] += p[c] / 4;
-                        }
-                    }
-                }
-                mip.put_pixel(x, y, sum);
-            }
+        let next = prev.resample(mip_size.width, mip_size.height, config.filter);
+        let mut out = LinearImage {
+            width: next.width,
+            height: next.height,
+            data: next.data.clone(),
+        };
+        if config.alpha != MipAlpha::Straight {
+            out.unpremultiply();
+        }
+        if let Some((cutoff, target)) = coverage {
+            out.preserve_coverage(cutoff, target);
         }
-        mips.push(mip);
+        mips.push(out.to_rgba8(is_srgb));
+        prev = next;
     }
-    let mips: Vec<_> = mips.into_iter().map(|mip| mip.into_raw()).collect();
     Ok(ImageMipMap {
         data: mips,
         width: image.width,
@@ -46,3 +339,119 @@ pub fn generate_rgba8_mip_map(image: Image, mip_map_count: u32) -> Result<ImageM
         format,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32, srgb: bool, f: impl Fn(u32, u32) -> [u8; 4]) -> Image {
+        let mut data = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                data.extend_from_slice(&f(x, y));
+            }
+        }
+        Image {
+            data,
+            width,
+            height,
+            format: if srgb {
+                TextureFormat::Rgba8UnormSrgb
+            } else {
+                TextureFormat::Rgba8Unorm
+            },
+        }
+    }
+
+    fn checker(x: u32, y: u32) -> [u8; 4] {
+        let v = if (x + y).is_multiple_of(2) { 255 } else { 0 };
+        [v, v, v, 255]
+    }
+
+    #[test]
+    fn srgb_average_in_linear_space() {
+        let config = MipMapConfig {
+            filter: MipFilter::Box,
+            ..Default::default()
+        };
+        let srgb = generate_rgba8_mip_map(image(2, 2, true, checker), &config).unwrap();
+        // 线性空间的 0.5 对应 sRGB 的 188
+        assert_eq!(&srgb.data[1], &[188, 188, 188, 255]);
+        let linear = generate_rgba8_mip_map(image(2, 2, false, checker), &config).unwrap();
+        assert_eq!(&linear.data[1], &[128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn full_chain_npot() {
+        let config = MipMapConfig::default();
+        let mip = generate_rgba8_mip_map(image(20, 6, true, checker), &config).unwrap();
+        assert_eq!(mip.mip_map_count(), 5);
+        for (i, d) in mip.data.iter().enumerate() {
+            let e = mip.extent_3d(i as u32);
+            assert_eq!(d.len() as u32, e.width * e.height * 4);
+        }
+        assert_eq!(mip.extent_3d(4).width, 1);
+        for filter in [MipFilter::Box, MipFilter::Triangle, MipFilter::Lanczos3] {
+            let config = MipMapConfig {
+                filter,
+                ..Default::default()
+            };
+            assert!(generate_rgba8_mip_map(image(7, 5, false, checker), &config).is_ok());
+        }
+    }
+
+    #[test]
+    fn premultiplied_hides_transparent_color() {
+        // 透明的红色 + 不透明的绿色, 颜色不应该混进红
+        let f = |x: u32, _| {
+            if x == 0 {
+                [255, 0, 0, 0]
+            } else {
+                [0, 255, 0, 255]
+            }
+        };
+        let config = MipMapConfig {
+            filter: MipFilter::Box,
+            alpha: MipAlpha::Premultiplied,
+            mip_level_count: Some(2),
+        };
+        let mip = generate_rgba8_mip_map(image(2, 2, false, f), &config).unwrap();
+        assert_eq!(&mip.data[1], &[0, 255, 0, 128]);
+    }
+
+    #[test]
+    fn coverage_preserved() {
+        // 几个软边的圆点, 缩小以后边缘被糊开, 不调整的话覆盖率会变
+        let f = |x: u32, y: u32| {
+            let d = |cx: f32, cy: f32, r: f32| {
+                let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+                1.0 - (dx * dx + dy * dy).sqrt() / r
+            };
+            let a = d(11.0, 12.0, 14.0).max(d(23.0, 22.0, 10.0)).clamp(0.0, 1.0);
+            [255, 255, 255, (a * 255.0) as u8]
+        };
+        let cutoff = 0.5;
+        let config = MipMapConfig {
+            filter: MipFilter::Box,
+            alpha: MipAlpha::Coverage { cutoff },
+            mip_level_count: Some(4),
+        };
+        let mip = generate_rgba8_mip_map(image(32, 32, false, f), &config).unwrap();
+        let coverage = |level: &Vec<u8>| {
+            let opaque = level
+                .chunks(4)
+                .filter(|p| p[3] as f32 / 255.0 >= cutoff)
+                .count();
+            opaque as f32 / (level.len() / 4) as f32
+        };
+        let base = coverage(&mip.data[0]);
+        assert!(base > 0.1 && base < 0.5);
+        for (i, level) in mip.data.iter().enumerate().skip(1) {
+            // 像素越少, 覆盖率能取的值越离散, 最多差一个像素
+            let pixels = (level.len() / 4) as f32;
+            let tolerance = 0.05f32.max(1.0 / pixels);
+            let c = coverage(level);
+            assert!((c - base).abs() <= tolerance, "第 {} 层 {} 和 {}", i, c, base);
+        }
+    }
+}
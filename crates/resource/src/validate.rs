@@ -0,0 +1,212 @@
+// 资源目录的检查, 把所有问题一次性列出来, 而不是遇到第一个就停
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::*;
+
+use crate::{
+    import::is_importable,
+    pack::{PackRegistry, MANIFEST_FILE_NAME},
+    ImageMipMap, MIP_FILE_EXTENSION,
+};
+
+const IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "bmp", "tga"];
+
+#[derive(Debug, Default)]
+pub struct Report {
+    // 检查过的 mipmap 目录和贴图文件数, 检查 shader 时是 shader 的变体数
+    pub checked: usize,
+    pub errors: Vec<(PathBuf, Error)>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub(crate) fn check(&mut self, path: &Path, f: impl FnOnce() -> Result<()>) {
+        if let Err(e) = f() {
+            self.errors.push((path.to_path_buf(), e));
+        }
+    }
+}
+
+// 目录下有包就按包检查, 否则把每个含图片的目录当作 mipmap 目录检查
+pub fn validate_tree(root: impl AsRef<Path>) -> Result<Report> {
+    let root = root.as_ref();
+    let mut report = Report::default();
+    if has_packs(root)? {
+        validate_packs(root, &mut report);
+    } else {
+        validate_mip_dirs(root, &mut report)?;
+    }
+    Ok(report)
+}
+
+fn has_packs(root: &Path) -> Result<bool> {
+    for e in fs::read_dir(root).with_context(|| format!("读取 {:?} 失败", root))? {
+        if e?.path().join(MANIFEST_FILE_NAME).is_file() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// 检查注册表, 每张贴图, 以及所有贴图能否放进同一个 texture array
+fn validate_packs(root: &Path, report: &mut Report) {
+    let registry = match PackRegistry::load_dir(root) {
+        std::result::Result::Ok(r) => r,
+        Err(e) => {
+            report.errors.push((root.to_path_buf(), e));
+            return;
+        }
+    };
+    let mut layers = Vec::new();
+    for t in registry.textures.iter() {
+        report.checked += 1;
+        match ImageMipMap::load(&t.path, t.srgb) {
+            std::result::Result::Ok(m) => layers.push(m),
+            Err(e) => report
+                .errors
+                .push((t.path.clone(), e.context(format!("贴图 {:?}", t.id)))),
+        }
+    }
+    if layers.len() == registry.textures.len() {
+        report.check(root, || ImageMipMap::validate_array(&layers));
+    }
+}
+
+fn validate_mip_dirs(dir: &Path, report: &mut Report) -> Result<()> {
+    let mut has_image = false;
+    for e in fs::read_dir(dir).with_context(|| format!("读取 {:?} 失败", dir))? {
+        let p = e?.path();
+        if p.is_dir() {
+            validate_mip_dirs(&p, report)?;
+        } else if p.extension().is_some_and(|e| e == MIP_FILE_EXTENSION) || is_importable(&p) {
+            report.checked += 1;
+            report.check(&p, || {
+                ImageMipMap::validate_array(&ImageMipMap::load_layers(&p, true)?)
+            });
+        } else if is_image(&p) {
+            has_image = true;
+        }
+    }
+    if has_image {
+        report.checked += 1;
+        report.check(dir, || ImageMipMap::from_path(dir, true).map(|_| ()));
+    }
+    Ok(())
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| IMAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MipMapError;
+    use image::RgbaImage;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("resource_validate_test_{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn save(dir: &Path, name: &str, size: u32) {
+        RgbaImage::new(size, size).save(dir.join(name)).unwrap();
+    }
+
+    fn error_of(dir: &Path) -> MipMapError {
+        let e = ImageMipMap::from_path(dir, true).unwrap_err();
+        match e.downcast::<MipMapError>() {
+            std::result::Result::Ok(e) => e,
+            Err(e) => panic!("不是 MipMapError: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn mip_map_errors() {
+        let dir = temp_dir("ok");
+        save(&dir, "0.png", 8);
+        save(&dir, "1.png", 4);
+        assert_eq!(
+            ImageMipMap::from_path(&dir, true).unwrap().mip_map_count(),
+            2
+        );
+
+        let dir = temp_dir("missing");
+        save(&dir, "0.png", 8);
+        save(&dir, "2.png", 2);
+        assert!(matches!(
+            error_of(&dir),
+            MipMapError::MissingLevel { level: 1, .. }
+        ));
+
+        let dir = temp_dir("no_base");
+        save(&dir, "1.png", 4);
+        assert!(matches!(
+            error_of(&dir),
+            MipMapError::MissingLevel { level: 0, .. }
+        ));
+
+        let dir = temp_dir("size");
+        save(&dir, "0.png", 8);
+        save(&dir, "1.png", 2);
+        assert!(matches!(
+            error_of(&dir),
+            MipMapError::WrongSize {
+                expected: (4, 4),
+                ..
+            }
+        ));
+
+        let dir = temp_dir("range");
+        save(&dir, "0.png", 8);
+        save(&dir, "4000000000.png", 1);
+        assert!(matches!(
+            error_of(&dir),
+            MipMapError::LevelOutOfRange {
+                level: 4000000000,
+                max_level_count: 4,
+                ..
+            }
+        ));
+
+        let dir = temp_dir("name");
+        save(&dir, "0.png", 8);
+        save(&dir, "level1.png", 4);
+        assert!(matches!(error_of(&dir), MipMapError::NonNumericName { .. }));
+
+        let dir = temp_dir("empty");
+        assert!(matches!(error_of(&dir), MipMapError::Empty { .. }));
+    }
+
+    #[test]
+    fn tree_report() {
+        let root = temp_dir("tree");
+        let good = root.join("good");
+        let bad = root.join("a/bad");
+        fs::create_dir_all(&good).unwrap();
+        fs::create_dir_all(&bad).unwrap();
+        save(&good, "0.png", 4);
+        save(&bad, "1.png", 4);
+        let report = validate_tree(&root).unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, bad);
+        assert!(matches!(
+            report.errors[0].1.downcast_ref::<MipMapError>(),
+            Some(MipMapError::MissingLevel { level: 0, .. })
+        ));
+    }
+}
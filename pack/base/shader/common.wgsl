@@ -0,0 +1,174 @@
+// cube 和以后其他管线共用的结构和函数
+
+// 与 cube::CubeVertx, cube::CubeInstance 对应
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+};
+
+struct InstanceInput {
+    @location(2) info: vec4<u32>,
+    @location(3) position: vec3<f32>,
+    @location(4) color: vec3<f32>,
+    // 四个顶点的遮挡程度 0..3
+    @location(5) ao: vec4<u32>,
+    // 四个顶点的方块光照等级 0..MAX_LIGHT
+    @location(6) light: vec4<u32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) color: vec3<f32>,
+    @location(2) @interpolate(flat) tex_idx: i32,
+    @location(3) @interpolate(flat) blend: u32,
+    // 世界空间的面法线
+    @location(4) normal: vec3<f32>,
+    @location(5) world_pos: vec3<f32>,
+    // 到相机的视空间深度, 用来选阴影的级别
+    @location(6) view_depth: f32,
+    // 顶点 AO 插值后的亮度系数
+    @location(7) ao: f32,
+    // 方块光照插值后的亮度
+    @location(8) block_light: f32,
+    @location(9) @interpolate(flat) emission: f32,
+};
+
+fn hsb2rgb(c: vec3<f32>) -> vec3<f32> {
+    let a = ((c.x * 6.0 + vec3<f32>(0.0, 4.0, 2.0)) % 6.0) - 3.0;
+    let b = abs(a) - 1.0 ;
+    let rgb = clamp(b, vec3<f32>(0.0), vec3<f32>(1.0).xxx);
+    //rgb = rgb*rgb*(3.0-2.0*rgb);
+    return c.z * mix(vec3<f32>(1.0, 1.0, 1.0), rgb, c.y);
+}
+
+struct Info {
+    exp: u32,
+    rot_flip: u32,
+    material: u32,
+}
+
+fn get_info(c: vec4<u32>) -> Info {
+    var info: Info;
+    info.exp = c.x;
+    info.rot_flip = c.y;
+    info.material = c.z | (c.w << 8u);
+    return info;
+}
+
+// instance 的缩放, 旋转 (rot), 平移, 得到世界坐标
+fn instance_world_pos(model: VertexInput, instance: InstanceInput, info: Info, rot: mat4x4<f32>) -> vec3<f32> {
+    let s = exp2(f32(info.exp));
+    let pos = rot * vec4<f32>(s * model.position, 1.0);
+    return pos.xyz + instance.position;
+}
+
+// 与 cube::MaterialUv 对应
+struct MaterialUv {
+    uv: vec4<f32>,
+    layer: u32,
+    blend: u32,
+    // 0..1
+    emission: f32,
+}
+
+// 按 instance 的翻转位镜像, 再映射到材质在 texture array 中的子矩形
+fn material_tex_coords(tex_coords: vec2<f32>, info: Info, material: MaterialUv) -> vec2<f32> {
+    let flip = f32(info.rot_flip & 1u);
+    let x = flip + (1.0 - flip * 2.0) * tex_coords.x;
+    return mix(material.uv.xy, material.uv.zw, vec2<f32>(x, tex_coords.y));
+}
+
+// 遮挡程度换成亮度系数, 3 个格子都有方块时最暗
+fn ao_factor(occlusion: u32) -> f32 {
+    return 1.0 - 0.2 * f32(occlusion);
+}
+
+// 与 pack::MAX_LIGHT 对应
+let MAX_LIGHT: u32 = 15u;
+
+// 方块光照的等级换成亮度, 每低一级暗 20%, 0 级完全没有
+fn block_light_factor(level: u32) -> f32 {
+    if (level == 0u) {
+        return 0.0;
+    }
+    return pow(0.8, f32(MAX_LIGHT - min(level, MAX_LIGHT)));
+}
+
+// 灯光偏暖
+fn block_light_color(factor: f32) -> vec3<f32> {
+    return vec3<f32>(1.0, 0.85, 0.6) * factor;
+}
+
+// 最亮的自发光材质是贴图颜色的几倍, 超过 1 的部分在后处理里泛光
+let EMISSION_STRENGTH: f32 = 4.0;
+
+// 与 pack::BlendMode 对应
+let BLEND_OPAQUE: u32 = 0u;
+let BLEND_CUTOUT: u32 = 1u;
+let BLEND_TRANSLUCENT: u32 = 2u;
+
+// 与 light::LightUniform 对应
+struct Light {
+    // 从地面指向太阳
+    direction: vec3<f32>,
+    color: vec3<f32>,
+    ambient: vec3<f32>,
+}
+
+// Lambert 漫反射加环境光, visibility 是阴影里的可见比例, 只影响漫反射
+fn lambert(light: Light, normal: vec3<f32>, visibility: f32) -> vec3<f32> {
+    let diffuse = max(dot(normalize(normal), light.direction), 0.0);
+    return light.ambient + light.color * diffuse * visibility;
+}
+
+// 与 shadow::ShadowUniform 对应, 最多 4 级
+struct Shadow {
+    view_proj: array<mat4x4<f32>, 4>,
+    // 每级覆盖到的视空间深度
+    splits: vec4<f32>,
+    // 每级一个 texel 在世界空间的大小, 用来沿法线偏移
+    texel_world: vec4<f32>,
+    count: u32,
+    // 一个 texel 在贴图坐标里的大小
+    texel: f32,
+}
+
+// 与 environment::EnvironmentUniform 对应
+struct Environment {
+    inv_view_proj: mat4x4<f32>,
+    camera_pos: vec3<f32>,
+    // 0..24 小时
+    time: f32,
+    zenith: vec3<f32>,
+    // 世界空间的距离
+    fog_start: f32,
+    horizon: vec3<f32>,
+    fog_end: f32,
+}
+
+// dir 方向上天空的颜色, 不含太阳, 地平线以下和地平线一样
+fn sky_color(env: Environment, dir: vec3<f32>) -> vec3<f32> {
+    let up = clamp(normalize(dir).y, 0.0, 1.0);
+    return mix(env.horizon, env.zenith, sqrt(up));
+}
+
+// 0 是没有雾, 1 是完全看不见
+fn fog_factor(env: Environment, world_pos: vec3<f32>) -> f32 {
+    return smoothstep(env.fog_start, env.fog_end, length(world_pos - env.camera_pos));
+}
+
+// 按到相机的距离混进视线方向上天空的颜色
+fn apply_fog(env: Environment, color: vec3<f32>, world_pos: vec3<f32>) -> vec3<f32> {
+    return mix(color, sky_color(env, world_pos - env.camera_pos), fog_factor(env, world_pos));
+}
+
+fn luminance(c: vec3<f32>) -> f32 {
+    return dot(c, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// 三个顶点 (-1, -1), (3, -1), (-1, 3) 的三角形盖住整个屏幕, 不用顶点 buffer
+fn fullscreen_ndc(vertex: u32) -> vec2<f32> {
+    let uv = vec2<f32>(f32((vertex << 1u) & 2u), f32(vertex & 2u));
+    return uv * 2.0 - 1.0;
+}
@@ -0,0 +1,130 @@
+#include "common.wgsl"
+
+@group(2) @binding(0)
+var<uniform> rot_mat_array: array<mat4x4<f32>, 48>; 
+@group(2) @binding(3)
+var<storage, read> material_uv_array: array<MaterialUv>;
+
+@group(0) @binding(0)
+var<uniform> view_mat: mat4x4<f32>; 
+@group(0) @binding(1)
+var<uniform> proj_mat: mat4x4<f32>; 
+
+@group(1) @binding(0)
+var<uniform> light: Light;
+@group(1) @binding(1)
+var<uniform> shadow: Shadow;
+@group(1) @binding(2)
+var shadow_map: texture_depth_2d_array;
+@group(1) @binding(3)
+var shadow_samp: sampler_comparison;
+@group(1) @binding(4)
+var<uniform> environment: Environment;
+
+@vertex
+fn vertex_main(
+    model: VertexInput,
+    instance: InstanceInput,
+    // 没有 base_vertex, 就是四边形的第几个顶点
+    @builtin(vertex_index) vertex: u32,
+) -> VertexOutput {
+    let info = get_info(instance.info);
+    let rot = rot_mat_array[info.rot_flip >> 1u];
+    let pos = instance_world_pos(model, instance, info, rot);
+    let view_pos = view_mat * vec4<f32>(pos, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = proj_mat * view_pos;
+    out.color = instance.color;
+    // 面的模型是 +x 面, 旋转后就是法线
+    out.normal = (rot * vec4<f32>(1.0, 0.0, 0.0, 0.0)).xyz;
+    out.world_pos = pos;
+    out.view_depth = -view_pos.z;
+    out.ao = ao_factor(instance.ao[vertex]);
+    out.block_light = block_light_factor(instance.light[vertex]);
+
+    // 材质在 texture array 中的层和子矩形
+    let material = material_uv_array[info.material];
+    out.tex_coords = material_tex_coords(model.tex_coords, info, material);
+    out.tex_idx = i32(material.layer);
+    out.blend = material.blend;
+    out.emission = material.emission;
+    return out;
+}
+
+// Fragment shader
+
+@group(2) @binding(1)
+var tex_arr_samp : sampler;
+@group(2) @binding(2)
+var tex_arr: texture_2d_array<f32>;
+
+// 太阳光的可见比例, 超出阴影范围的算完全可见
+fn shadow_visibility(world_pos: vec3<f32>, normal: vec3<f32>, view_depth: f32) -> f32 {
+    // 第一个覆盖到这个深度的级别
+    var cascade = shadow.count;
+    for (var i = 0u; i < shadow.count; i = i + 1u) {
+        if (view_depth < shadow.splits[i]) {
+            cascade = i;
+            break;
+        }
+    }
+    if (cascade >= shadow.count) {
+        return 1.0;
+    }
+    // 沿法线偏移一个多 texel, 减少斜面上的自阴影条纹
+    let offset = normalize(normal) * shadow.texel_world[cascade] * 1.5;
+    let p = shadow.view_proj[cascade] * vec4<f32>(world_pos + offset, 1.0);
+    let ndc = p.xyz / p.w;
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || ndc.z > 1.0) {
+        return 1.0;
+    }
+    // 3x3 PCF, 每次比较本身还有硬件的双线性过滤
+    var sum = 0.0;
+    for (var x = -1; x <= 1; x = x + 1) {
+        for (var y = -1; y <= 1; y = y + 1) {
+            let d = vec2<f32>(f32(x), f32(y)) * shadow.texel;
+            sum = sum + textureSampleCompareLevel(shadow_map, shadow_samp, uv + d, i32(cascade), ndc.z);
+        }
+    }
+    return sum / 9.0;
+}
+
+// 变体: OPAQUE, TRANSPARENT, WIREFRAME, 见 cube::Variant
+@fragment
+fn fragment_main(in: VertexOutput) -> @location(0) vec4<f32> {
+#ifdef WIREFRAME
+    return vec4<f32>(in.color, 0.0);
+#else
+
+    let color = hsb2rgb(vec3<f32>(in.tex_coords.x, 1.0, 1.0));
+    let color = pow(color, 2.2 * vec3<f32>(1.0, 1.0, 1.0));
+
+    let tx = textureSample(tex_arr, tex_arr_samp, in.tex_coords, in.tex_idx);
+
+    let color = in.color * color;
+    let color = vec4<f32>(color, 1.0) * tx ;
+
+    if (in.blend == BLEND_CUTOUT && tx.a < 0.5) {
+        discard;
+    }
+    let visibility = shadow_visibility(in.world_pos, in.normal, in.view_depth);
+    // 太阳和环境光之外再加上方块的光, 没有灯的室内只剩被阴影和 AO 压暗的环境光
+    let lit = tx.rgb * (lambert(light, in.normal, visibility) + block_light_color(in.block_light)) * in.ao;
+    // 自发光不受阴影和 AO 影响
+    let emissive = tx.rgb * in.emission * EMISSION_STRENGTH;
+    let lit = lit + emissive;
+    // 远处融进天空, 看不出视距的边
+    let fog = fog_factor(environment, in.world_pos);
+    let lit = apply_fog(environment, lit, in.world_pos);
+#ifdef TRANSPARENT
+    // alpha 用来混合, 下面的自发光比例按不透明度变淡, 见 cube::render_desc
+    return vec4<f32>(lit, tx.a);
+#else
+    // 不透明和 cutout 的 alpha 不参与混合, 用来记下自发光在颜色里占的比例, 泛光只取这部分
+    let glow = luminance(emissive) * (1.0 - fog) / max(luminance(lit), 0.0001);
+    return vec4<f32>(lit, clamp(glow, 0.0, 1.0));
+#endif
+#endif
+}
@@ -0,0 +1,89 @@
+#include "common.wgsl"
+
+// 后处理, 见 post::Post, 每个变体是一步:
+// BRIGHT 取出自发光的亮部, BLUR_H 和 BLUR_V 横竖模糊, TONEMAP 加上泛光后色调映射到 surface
+
+// 与 post::PostUniform 对应
+struct Post {
+    exposure: f32,
+    // 与 post::Tonemap 对应
+    tonemap: u32,
+    bloom_threshold: f32,
+    // 不开泛光时是 0
+    bloom_intensity: f32,
+}
+
+let TONEMAP_REINHARD: u32 = 0u;
+
+@group(0) @binding(0)
+var<uniform> post: Post;
+@group(0) @binding(1)
+var samp: sampler;
+@group(0) @binding(2)
+var src: texture_2d<f32>;
+@group(0) @binding(3)
+var bloom: texture_2d<f32>;
+
+struct PostOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vertex_main(@builtin(vertex_index) vertex: u32) -> PostOutput {
+    let ndc = fullscreen_ndc(vertex);
+    var out: PostOutput;
+    out.clip_position = vec4<f32>(ndc, 0.0, 1.0);
+    out.uv = ndc * vec2<f32>(0.5, -0.5) + 0.5;
+    return out;
+}
+
+fn tonemap_reinhard(x: vec3<f32>) -> vec3<f32> {
+    return x / (1.0 + x);
+}
+
+// Narkowicz 对 ACES 的拟合
+fn tonemap_aces(x: vec3<f32>) -> vec3<f32> {
+    let y = (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+    return clamp(y, vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+// 9 个采样的高斯模糊, dir 是一个 texel 的方向
+fn blur(uv: vec2<f32>, dir: vec2<f32>) -> vec3<f32> {
+    let texel = dir / vec2<f32>(textureDimensions(src));
+    var weights = array<f32, 5>(0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+    var sum = textureSampleLevel(src, samp, uv, 0.0).rgb * weights[0];
+    for (var i = 1; i < 5; i = i + 1) {
+        let d = texel * f32(i);
+        let pair = textureSampleLevel(src, samp, uv + d, 0.0).rgb + textureSampleLevel(src, samp, uv - d, 0.0).rgb;
+        sum = sum + pair * weights[i];
+    }
+    return sum;
+}
+
+@fragment
+fn fragment_main(in: PostOutput) -> @location(0) vec4<f32> {
+#ifdef BRIGHT
+    // 只有自发光泛光, alpha 是它在颜色里占的比例, 见 cube_shader.wgsl
+    // 再按亮度减掉阈值, 颜色不变
+    let s = textureSampleLevel(src, samp, in.uv, 0.0);
+    let c = s.rgb * s.a;
+    let l = luminance(c);
+    return vec4<f32>(c * max(l - post.bloom_threshold, 0.0) / max(l, 0.0001), 1.0);
+#endif
+#ifdef BLUR_H
+    return vec4<f32>(blur(in.uv, vec2<f32>(1.0, 0.0)), 1.0);
+#endif
+#ifdef BLUR_V
+    return vec4<f32>(blur(in.uv, vec2<f32>(0.0, 1.0)), 1.0);
+#endif
+#ifdef TONEMAP
+    let b = textureSampleLevel(bloom, samp, in.uv, 0.0).rgb;
+    let hdr = textureSampleLevel(src, samp, in.uv, 0.0).rgb + b * post.bloom_intensity;
+    let x = hdr * post.exposure;
+    if (post.tonemap == TONEMAP_REINHARD) {
+        return vec4<f32>(tonemap_reinhard(x), 1.0);
+    }
+    return vec4<f32>(tonemap_aces(x), 1.0);
+#endif
+}
@@ -0,0 +1,50 @@
+#include "common.wgsl"
+
+// 太阳的阴影 pass, 只写深度, 见 shadow::Shadow
+// 每级画一遍, 用 dynamic offset 选这一级的矩阵
+
+@group(0) @binding(0)
+var<uniform> cascade_view_proj: mat4x4<f32>;
+
+// 和 cube_shader 用同一个 cube 绑定组
+@group(1) @binding(0)
+var<uniform> rot_mat_array: array<mat4x4<f32>, 48>;
+@group(1) @binding(1)
+var tex_arr_samp: sampler;
+@group(1) @binding(2)
+var tex_arr: texture_2d_array<f32>;
+@group(1) @binding(3)
+var<storage, read> material_uv_array: array<MaterialUv>;
+
+struct ShadowOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) @interpolate(flat) tex_idx: i32,
+    @location(2) @interpolate(flat) blend: u32,
+}
+
+@vertex
+fn vertex_main(model: VertexInput, instance: InstanceInput) -> ShadowOutput {
+    let info = get_info(instance.info);
+    let rot = rot_mat_array[info.rot_flip >> 1u];
+    let pos = instance_world_pos(model, instance, info, rot);
+
+    var out: ShadowOutput;
+    out.clip_position = cascade_view_proj * vec4<f32>(pos, 1.0);
+    let material = material_uv_array[info.material];
+    out.tex_coords = material_tex_coords(model.tex_coords, info, material);
+    out.tex_idx = i32(material.layer);
+    out.blend = material.blend;
+    return out;
+}
+
+// cutout 镂空的地方不投影
+@fragment
+fn fragment_main(in: ShadowOutput) {
+    if (in.blend == BLEND_CUTOUT) {
+        let a = textureSampleLevel(tex_arr, tex_arr_samp, in.tex_coords, in.tex_idx, 0.0).a;
+        if (a < 0.5) {
+            discard;
+        }
+    }
+}
@@ -0,0 +1,36 @@
+#include "common.wgsl"
+
+// 天空, 见 sky::Pipeline, 只用全局的绑定组
+
+@group(1) @binding(0)
+var<uniform> light: Light;
+@group(1) @binding(4)
+var<uniform> environment: Environment;
+
+struct SkyOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+}
+
+// 深度是最远的 1
+@vertex
+fn vertex_main(@builtin(vertex_index) vertex: u32) -> SkyOutput {
+    let ndc = fullscreen_ndc(vertex);
+    var out: SkyOutput;
+    out.clip_position = vec4<f32>(ndc, 1.0, 1.0);
+    out.ndc = ndc;
+    return out;
+}
+
+@fragment
+fn fragment_main(in: SkyOutput) -> @location(0) vec4<f32> {
+    // 同一个像素在近平面和远平面上的两点, 连起来就是视线
+    let near = environment.inv_view_proj * vec4<f32>(in.ndc, 0.0, 1.0);
+    let far = environment.inv_view_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+    let dir = normalize(far.xyz / far.w - near.xyz / near.w);
+    // 太阳的圆盘, 边缘柔和一点, 夜里 light.color 是 0 就没有了
+    let sun = smoothstep(0.9990, 0.9996, dot(dir, light.direction));
+    let color = sky_color(environment, dir) + light.color * sun * 4.0;
+    // alpha 是自发光的比例, 天空和太阳都不泛光
+    return vec4<f32>(color, 0.0);
+}
@@ -0,0 +1,218 @@
+// 方块光照: 每个格子一个 0..=MAX_LIGHT 的亮度, 发光的方块向周围的空格子逐格减一地扩散
+//
+// 按 CHUNK_SIZE³ 分块存, 没有光的块不占内存. 加减方块时只更新受影响的范围:
+// 先把从那里扩散出去的光清掉 (比它暗的邻居只可能是它照亮的), 再让边界上更亮的格子重新扩散.
+use std::collections::{HashMap, VecDeque};
+
+use resource::pack::MAX_LIGHT;
+
+pub type Cell = [i32; 3];
+
+pub const CHUNK_SIZE: i32 = 16;
+const CHUNK_VOLUME: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+const NEIGHBORS: [Cell; 6] = [
+    [1, 0, 0],
+    [-1, 0, 0],
+    [0, 1, 0],
+    [0, -1, 0],
+    [0, 0, 1],
+    [0, 0, -1],
+];
+
+type Chunk = Box<[u8; CHUNK_VOLUME]>;
+
+#[derive(Debug, Default)]
+pub struct BlockLight {
+    chunks: HashMap<Cell, Chunk>,
+    // 有方块的格子都挡光, 值是方块的发光强度
+    blocks: HashMap<Cell, u8>,
+}
+
+// (块坐标, 块内下标)
+fn split(cell: Cell) -> (Cell, usize) {
+    let chunk = cell.map(|c| c.div_euclid(CHUNK_SIZE));
+    let [x, y, z] = cell.map(|c| c.rem_euclid(CHUNK_SIZE));
+    (chunk, ((x * CHUNK_SIZE + y) * CHUNK_SIZE + z) as usize)
+}
+
+fn offset(cell: Cell, d: Cell) -> Cell {
+    [cell[0] + d[0], cell[1] + d[1], cell[2] + d[2]]
+}
+
+impl BlockLight {
+    // 方块所在的格子是它自己的发光强度
+    pub fn level(&self, cell: Cell) -> u8 {
+        let (chunk, i) = split(cell);
+        self.chunks.get(&chunk).map_or(0, |c| c[i])
+    }
+
+    pub fn is_block(&self, cell: Cell) -> bool {
+        self.blocks.contains_key(&cell)
+    }
+
+    fn set_level(&mut self, cell: Cell, level: u8) {
+        let (chunk, i) = split(cell);
+        match self.chunks.get_mut(&chunk) {
+            Some(c) => c[i] = level,
+            None if level > 0 => {
+                let mut c: Chunk = Box::new([0; CHUNK_VOLUME]);
+                c[i] = level;
+                self.chunks.insert(chunk, c);
+            }
+            None => {}
+        }
+    }
+
+    // 已经有方块时替换掉, light 超过 MAX_LIGHT 的按 MAX_LIGHT 算
+    pub fn add_block(&mut self, cell: Cell, light: u8) {
+        let light = light.min(MAX_LIGHT);
+        self.blocks.insert(cell, light);
+        // 原来经过这个格子的光被挡住了
+        let mut queue = self.remove_light(cell);
+        if light > 0 {
+            self.set_level(cell, light);
+            queue.push_back(cell);
+        }
+        self.propagate(queue);
+    }
+
+    pub fn remove_block(&mut self, cell: Cell) {
+        if self.blocks.remove(&cell).is_none() {
+            return;
+        }
+        let mut queue = self.remove_light(cell);
+        // 变成空格子, 从有光的邻居重新照进来
+        queue.extend(
+            NEIGHBORS
+                .iter()
+                .map(|d| offset(cell, *d))
+                .filter(|n| self.level(*n) > 0),
+        );
+        self.propagate(queue);
+    }
+
+    // 清掉 cell 和从它扩散出去的光, 返回清掉的范围边上要重新扩散的格子
+    fn remove_light(&mut self, cell: Cell) -> VecDeque<Cell> {
+        let mut relight = VecDeque::new();
+        let mut queue = VecDeque::from([(cell, self.level(cell))]);
+        self.set_level(cell, 0);
+        while let Some((c, level)) = queue.pop_front() {
+            for d in NEIGHBORS {
+                let n = offset(c, d);
+                let nl = self.level(n);
+                if nl == 0 {
+                    continue;
+                }
+                if nl < level && !self.is_block(n) {
+                    self.set_level(n, 0);
+                    queue.push_back((n, nl));
+                } else {
+                    // 别的光源照亮的, 或者是发光的方块
+                    relight.push_back(n);
+                }
+            }
+        }
+        relight
+    }
+
+    fn propagate(&mut self, mut queue: VecDeque<Cell>) {
+        while let Some(c) = queue.pop_front() {
+            let level = self.level(c);
+            if level <= 1 {
+                continue;
+            }
+            for d in NEIGHBORS {
+                let n = offset(c, d);
+                if !self.is_block(n) && self.level(n) + 1 < level {
+                    self.set_level(n, level - 1);
+                    queue.push_back(n);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 只用 blocks 从头算一遍, 和增量更新的结果比较
+    fn rebuild(light: &BlockLight) -> BlockLight {
+        let mut fresh = BlockLight {
+            chunks: HashMap::new(),
+            blocks: light.blocks.clone(),
+        };
+        let sources: VecDeque<Cell> = light
+            .blocks
+            .iter()
+            .filter(|(_, l)| **l > 0)
+            .map(|(c, _)| *c)
+            .collect();
+        for (c, l) in light.blocks.iter() {
+            fresh.set_level(*c, *l);
+        }
+        fresh.propagate(sources);
+        fresh
+    }
+
+    fn assert_same(a: &BlockLight, b: &BlockLight) {
+        for x in -20..20 {
+            for y in -4..4 {
+                for z in -20..20 {
+                    let c = [x, y, z];
+                    assert_eq!(a.level(c), b.level(c), "{:?}", c);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flood_and_remove() {
+        let mut light = BlockLight::default();
+        light.add_block([0, 0, 0], 15);
+        assert_eq!(light.level([0, 0, 0]), 15);
+        assert_eq!(light.level([3, 0, 0]), 12);
+        // 跨块
+        assert_eq!(light.level([-17, 0, 0]), 0);
+        assert_eq!(light.level([-14, 0, 0]), 1);
+
+        // 墙挡住以后要绕过去
+        for y in -1..=1 {
+            for z in -1..=1 {
+                light.add_block([2, y, z], 0);
+            }
+        }
+        assert_eq!(light.level([2, 0, 0]), 0);
+        // 从 y = 2 绕过去, 走 7 格
+        assert_eq!(light.level([3, 0, 0]), 8);
+        assert_same(&light, &rebuild(&light));
+
+        // 伪随机地加减, 每一步都和从头算的一样
+        let mut seed = 7u32;
+        let mut next = |n: i32| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as i32 % n
+        };
+        for _ in 0..60 {
+            let c = [next(16) - 8, next(3) - 1, next(16) - 8];
+            match next(3) {
+                0 => light.add_block(c, 0),
+                1 => light.add_block(c, next(16) as u8),
+                _ => light.remove_block(c),
+            }
+            assert_same(&light, &rebuild(&light));
+        }
+
+        light.remove_block([0, 0, 0]);
+        for x in -8..8 {
+            for z in -8..8 {
+                light.remove_block([x, 0, z]);
+                light.remove_block([x, 1, z]);
+                light.remove_block([x, -1, z]);
+            }
+        }
+        assert_eq!(light.level([0, 0, 0]), 0);
+        assert_eq!(light.level([3, 0, 0]), 0);
+    }
+}
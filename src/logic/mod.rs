@@ -1 +1,2 @@
-pub mod orient;
\ No newline at end of file
+pub mod block_light;
+pub mod orient;
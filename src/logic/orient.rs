@@ -71,8 +71,52 @@ impl Orient<Data> {
 
         Matrix3::from(m)
     }
+
+    // flip 的定义: 先沿 z 轴镜像, 再旋转.
+    // z 轴镜像不改变 +x 面(正方形)的形状, 只是把贴图的 u 翻转, 所以 shader 里只需翻转 u.
+    pub fn to_matrix(&self) -> Matrix3<f32> {
+        let m = self.to_matrix_without_flip();
+        if self.flip == 0 {
+            m
+        } else {
+            m * Self::flip_matrix()
+        }
+    }
+
+    fn flip_matrix() -> Matrix3<f32> {
+        Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, -1.0))
+    }
+
+    // to_matrix 的逆, m 必须是轴对齐的正交矩阵, 否则返回 None
+    pub fn from_matrix(m: &Matrix3<f32>) -> Option<Orient<Data>> {
+        let (m, flip) = if m.determinant() < 0.0 {
+            (m * Self::flip_matrix(), 1)
+        } else {
+            (*m, 0)
+        };
+        for code in 0..ORIENT_CODE_COUNT {
+            let o = Orient::<CompressedData>::decode(code << 1).uncompress();
+            if (o.to_matrix_without_flip() - m).abs().max() < 1e-4 {
+                return Some(Orient { flip, ..o });
+            }
+        }
+        None
+    }
+
+    // 先做 other 的变换, 再做 self 的变换
+    pub fn compose(&self, other: &Orient<Data>) -> Orient<Data> {
+        let m = self.to_matrix() * other.to_matrix();
+        Self::from_matrix(&m).expect("两个方向的组合一定还是方向")
+    }
+
+    pub fn identity() -> Orient<Data> {
+        Orient::<CompressedData>::decode(0).uncompress()
+    }
 }
 
+// 不含 flip 的编码个数, 即 (code >> 1) 的取值范围
+pub const ORIENT_CODE_COUNT: u8 = 24;
+
 fn axis_add(axis: i32, d: i32, n: i32) -> i32 {
     let res = axis + d;
     if res < 0 {
@@ -170,6 +214,29 @@ mod tests {
         }
     }
 
+    #[test]
+    fn matrix_round_trip() {
+        for code in 0..ORIENT_CODE_COUNT * 2 {
+            let o = Orient::<CompressedData>::decode(code).uncompress();
+            let back = Orient::from_matrix(&o.to_matrix()).unwrap();
+            assert_eq!(back.compressed().encode(), code);
+        }
+    }
+
+    #[test]
+    fn compose_flip() {
+        let id = Orient::identity();
+        for code in 0..ORIENT_CODE_COUNT * 2 {
+            let o = Orient::<CompressedData>::decode(code).uncompress();
+            assert_eq!(id.compose(&o).compressed().encode(), code);
+            assert_eq!(o.compose(&id).compressed().encode(), code);
+            // 两次镜像抵消
+            let f = Orient { flip: 1, ..Orient::identity() };
+            assert_eq!(f.compose(&f).compressed().encode(), 0);
+            assert_eq!(o.compose(&f).flip, 1 - o.flip);
+        }
+    }
+
     #[test]
     fn show_to_mat() {
         let codes = MAIN_DIR;
@@ -6,13 +6,15 @@ use std::{
     iter::once,
     mem::{replace, size_of},
     num::{NonZeroI64, NonZeroU32, NonZeroU64},
+    path::{Path, PathBuf},
     rc::Rc,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use bytemuck::cast_slice;
 use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel, Rgba};
 use memoffset::offset_of;
+use log::warn;
 use nalgebra::{Affine3, Isometry3, Matrix4, Perspective3, Point3, Projective3, Vector3};
 use once_cell::sync::OnceCell;
 use wgpu::{
@@ -23,13 +25,18 @@ use winit::window::Window;
 
 use crate::{
     logic::{
-        orient::{CompressedData, Orient},
+        block_light::BlockLight,
+        orient::{Code, CompressedData, Data, Orient},
         *,
     },
     render::texture::format_info::TextureFormatPixelInfo,
     utils::*,
 };
-use resource::*;
+use resource::{
+    atlas::{self, AtlasTable},
+    pack::*,
+    *,
+};
 
 use super::super::*;
 
@@ -53,7 +60,18 @@ pub const TEST_VERTICES: &[CubeVertx] = &[
     },
 ];
 
-pub const TEST_INDICES: &[u16] = &[2, 3, 0, 0, 1, 2];
+// 前一半沿 0-2 对角线分成两个三角形, 后一半沿 1-3, 见 flip_diagonal
+pub const TEST_INDICES: &[u16] = &[2, 3, 0, 0, 1, 2, 3, 0, 1, 1, 2, 3];
+
+// 方块局部坐标系下六个面的方向编码, 顺序和 pack::FACE_NAMES 一致
+pub const FACE_ORIENT_CODES: [Code; 6] = [
+    0b000000, // +x
+    0b001000, // -x
+    0b010000, // +y
+    0b011000, // -y
+    0b100000, // +z
+    0b101000, // -z
+];
 
 pub const TEST_INSTANCES: &[CubeInstance] = &[
     // // 参考 黑
@@ -67,36 +85,48 @@ pub const TEST_INSTANCES: &[CubeInstance] = &[
         info: [0, 0b001000, 0, 0],
         position: [0.0, 0.0, 0.0],
         color: [1.0, 0.01, 0.01],
+        ao: [0; 4],
+        light: [0; 4],
     },
     // +x 红
     CubeInstance {
         info: [0, 0b000000, 0, 0],
         position: [0.0, 0.0, 0.0],
         color: [1.0, 0.01, 0.01],
+        ao: [0; 4],
+        light: [0; 4],
     },
     // -y 绿
     CubeInstance {
         info: [0, 0b011000, 0, 0],
         position: [0.0, 0.0, 0.0],
         color: [0.01, 1.0, 0.01],
+        ao: [0; 4],
+        light: [0; 4],
     },
     // +y 绿
     CubeInstance {
         info: [0, 0b010000, 0, 0],
         position: [0.0, 0.0, 0.0],
         color: [0.01, 1.0, 0.01],
+        ao: [0; 4],
+        light: [0; 4],
     },
     // -z 蓝
     CubeInstance {
         info: [0, 0b101000, 0, 0],
         position: [0.0, 0.0, 0.0],
         color: [0.01, 0.01, 1.0],
+        ao: [0; 4],
+        light: [0; 4],
     },
     // +z 蓝
     CubeInstance {
         info: [0, 0b100000, 0, 0],
         position: [0.0, 0.0, 0.0],
         color: [0.01, 0.01, 1.0],
+        ao: [0; 4],
+        light: [0; 4],
     },
 ];
 
@@ -107,57 +137,103 @@ pub struct CubeVertx {
     tex_coords: [f32; 2],
 }
 
-impl CubeVertx {
-    pub fn attr_desc() -> VertexAttributeLayoutOwner {
+impl VertexLayout for CubeVertx {
+    fn attributes() -> Vec<VertexAttribute> {
         let attributes = vertex_attribute_layout!(
             Self, struct, {
                 0;position ; Float32x3,
                 1;tex_coords ; Float32x2,
             }
         );
-        VertexAttributeLayoutOwner {
-            attributes: attributes.into(),
-        }
-    }
-    pub fn desc(attr_lay: &VertexAttributeLayoutOwner) -> VertexBufferLayout<'_> {
-        vertex_buffer_layout!(CubeVertx, Vertex, &attr_lay.attributes[..])
+        attributes.into()
     }
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
 pub struct CubeInstance {
-    pub info: [u8; 4], // [指数(2的几次方, 缩放用), 旋转id(0..48), 贴图id与偏移0(低位), 贴图id与偏移1(高位)]
+    pub info: [u8; 4], // [指数(2的几次方, 缩放用), 旋转id(0..48), 材质id(低位), 材质id(高位)]
     pub position: [f32; 3], // 先做info里的rotation_id, 再做这里的position
     pub color: [f32; 3],
+    pub ao: [u8; 4], // 四个顶点被周围方块遮挡的程度 0..=3, 顺序和 TEST_VERTICES 一致, 见 Mesh::update_lighting
+    pub light: [u8; 4], // 四个顶点的方块光照等级 0..=MAX_LIGHT, 顺序同上
 }
 
 impl CubeInstance {
-    pub fn attr_desc() -> VertexAttributeLayoutOwner {
+    pub fn material(&self) -> MaterialId {
+        u16::from_le_bytes([self.info[2], self.info[3]]) as MaterialId
+    }
+    pub fn set_material(&mut self, material: MaterialId) {
+        let [lo, hi] = (material as u16).to_le_bytes();
+        self.info[2] = lo;
+        self.info[3] = hi;
+    }
+}
+
+impl VertexLayout for CubeInstance {
+    fn attributes() -> Vec<VertexAttribute> {
         let attributes = vertex_attribute_layout!(Self, struct, {
             2;info ; Uint8x4,
             3;position ; Float32x3,
             4;color ; Float32x3,
+            5;ao ; Uint8x4,
+            6;light ; Uint8x4,
         });
-        VertexAttributeLayoutOwner {
-            attributes: attributes.into(),
-        }
-    }
-    pub fn desc(attr_lay: &VertexAttributeLayoutOwner) -> VertexBufferLayout<'_> {
-        vertex_buffer_layout!(CubeInstance, Instance, &attr_lay.attributes[..])
+        attributes.into()
     }
 }
 
 const ORIENT_COUNT: usize = 24;
 type MATRIX = [[f32; 4]; 4];
+
+// 材质在 texture array 中的位置, shader 里按 instance 的材质 id 索引
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct MaterialUv {
+    pub uv: [f32; 4], // [u0, v0, u1, v1]
+    pub layer: u32,
+    pub blend: u32, // BlendMode
+    // 自发光 0..1, 发光强度 light / MAX_LIGHT, 画出来超过 1 的部分会泛光
+    pub emission: f32,
+    _pad: u32,
+}
+
+impl MaterialUv {
+    pub const FULL: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+    pub fn new(layer: u32, uv: [f32; 4], material: &MaterialEntry) -> Self {
+        Self {
+            uv,
+            layer,
+            blend: material.blend as u32,
+            emission: material.light as f32 / MAX_LIGHT as f32,
+            _pad: Default::default(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ConstResource {
     pub rot_mat: [MATRIX; ORIENT_COUNT],
-    pub paths: Vec<String>,
+    // texture array 每层的 mipmap 目录或 .mip 文件
+    pub paths: Vec<PathBuf>,
+    pub is_srgb: bool,
+    // 按 MaterialId 排列
+    pub material_uv: Vec<MaterialUv>,
+    // 按 MaterialId 排列, 给 Mesh 用
+    pub materials: Rc<[MaterialProps]>,
+}
+
+// Mesh 按 MaterialId 查的材质属性
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MaterialProps {
+    // 半透明的面放到 Mesh 的另一个列表里
+    pub translucent: bool,
+    // 发光强度, 用这个材质的方块是 BlockLight 的光源
+    pub light: u8,
 }
 
 impl ConstResource {
-    pub fn init() -> Self {
+    pub fn init(registry: &PackRegistry) -> Result<Self> {
         let rot: MATRIX = Default::default();
         let mut rot_mat: [MATRIX; ORIENT_COUNT] = [rot; ORIENT_COUNT];
         for code in 0..ORIENT_COUNT as u8 {
@@ -165,37 +241,93 @@ impl ConstResource {
             let mat = orint.to_matrix_without_flip();
             rot_mat[code as usize] = mat.to_homogeneous().into();
         }
-        let paths = vec![
-            "image/cube_test".to_string(),
-            "image/cube_test_2".to_string(),
-        ];
-        Self { rot_mat, paths }
+        if registry.textures.is_empty() {
+            bail!("没有任何贴图, 至少需要 {:?} 包", BASE_PACK_ID);
+        }
+        if registry.materials.len() > MaterialId::from(u16::MAX) as usize + 1 {
+            bail!("材质数量 {} 超过了 instance 能表示的范围", registry.materials.len());
+        }
+        // texture array 的每层格式必须一致
+        let is_srgb = registry.textures.get(0).unwrap().srgb;
+        if let Some(t) = registry.textures.iter().find(|t| t.srgb != is_srgb) {
+            bail!("贴图 {:?} 的 srgb 设置与其他贴图不一致", t.id);
+        }
+
+        // 有打好的图集就用图集, 否则每张贴图单独一层
+        let atlas_dir = Path::new(ATLAS_DIR);
+        let (paths, material_uv) = if atlas_dir.join(atlas::TABLE_FILE_NAME).is_file() {
+            let table = AtlasTable::from_path(atlas_dir)?;
+            table.check_registry(registry).with_context(|| {
+                format!("图集 {:?} 和现在的资源包不一致, 需要用 gen_atlas 重新打包", atlas_dir)
+            })?;
+            let paths = (0..table.page_count)
+                .map(|i| AtlasTable::page_dir(atlas_dir, i))
+                .collect();
+            let mut material_uv = Vec::new();
+            for m in registry.materials.iter() {
+                let e = table
+                    .materials
+                    .get(&m.id)
+                    .ok_or(anyhow!("图集 {:?} 中没有材质 {:?}, 需要重新打包", atlas_dir, m.id))?;
+                material_uv.push(MaterialUv::new(e.layer, e.uv, m));
+            }
+            (paths, material_uv)
+        } else {
+            let paths = registry.textures.iter().map(|t| t.path.clone()).collect();
+            let material_uv = registry
+                .materials
+                .iter()
+                .map(|m| MaterialUv::new(m.texture, MaterialUv::FULL, m))
+                .collect();
+            (paths, material_uv)
+        };
+        let materials = registry
+            .materials
+            .iter()
+            .map(|m| MaterialProps {
+                translucent: m.blend == BlendMode::Translucent,
+                light: m.light,
+            })
+            .collect();
+        Ok(Self {
+            rot_mat,
+            paths,
+            is_srgb,
+            material_uv,
+            materials,
+        })
     }
-    pub fn create_bind(&self, device: &Device, queue: &Queue) -> Result<ConstResourceBind> {
+    // 贴图经过 registry 读取, 这样资源包里的也能找到
+    pub fn create_bind(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        registry: &PackRegistry,
+    ) -> Result<ConstResourceBind> {
         let rot_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: Some("Cube Resource Rot Matrix"),
             contents: cast_slice(&self.rot_mat),
             usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
         });
-        let format = TextureFormat::Rgba8UnormSrgb;
+        let material_uv_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Cube Resource Material Uv"),
+            contents: cast_slice(&self.material_uv),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        });
         let len = self.paths.len();
+        let mut image_array = Vec::new();
+        for path in self.paths.iter() {
+            image_array.push(self.load_layer(device, registry, path)?);
+        }
+        ImageMipMap::validate_array(&image_array)?;
+        let layer = LayerInfo::of(&image_array[0]);
         let texture_array = {
-            let mut image_array = Vec::new();
-            let is_srgb = true;
-            for path in self.paths.iter() {
-                let img = ImageMipMap::from_path(path, is_srgb)?;
-                image_array.push(img);
-            }
             let mut desc = TextureArgs::texture_array();
-            desc.format = if is_srgb {
-                TextureFormat::Rgba8UnormSrgb
-            } else {
-                TextureFormat::Rgba8Unorm
-            };
-            desc.width = image_array[0].width;
-            desc.height = image_array[0].height;
+            desc.format = layer.format;
+            desc.width = layer.width;
+            desc.height = layer.height;
             desc.depth = len as u32;
-            desc.mip_level_count = image_array[0].mip_map_count();
+            desc.mip_level_count = layer.mip_level_count;
             let tx = device.create_texture(&desc.into_desc(Some("CubeTex")));
             for i in 0..len {
                 image_array[i].write_into_texture(queue, &tx, i as u32);
@@ -230,13 +362,30 @@ impl ConstResource {
 
         Ok(ConstResourceBind {
             rot_mat: rot_buffer,
+            material_uv: material_uv_buffer,
             texture: texture_array,
+            layer,
             array_view: view,
             sampler: sampler,
         })
     }
 
-    pub fn get_layout_args() -> Result<[BindGroupLayoutEntryArgs; 3]> {
+    // 设备不支持 BC 压缩时解压成 rgba8
+    pub fn load_layer(
+        &self,
+        device: &Device,
+        registry: &PackRegistry,
+        path: &Path,
+    ) -> Result<ImageMipMap> {
+        let mut img = registry.load_texture(path, self.is_srgb)?;
+        if img.is_compressed() && !device.features().contains(Features::TEXTURE_COMPRESSION_BC) {
+            warn!("设备不支持 BC 压缩, {:?} 解压成 rgba8", path);
+            img = img.decompress()?;
+        }
+        Ok(img)
+    }
+
+    pub fn get_layout_args() -> Result<[BindGroupLayoutEntryArgs; 4]> {
         let rot_mat = BindGroupLayoutEntryArgs {
             count: None,
             visibility: ShaderStages::VERTEX,
@@ -260,61 +409,197 @@ impl ConstResource {
                 multisampled: false,
             },
         };
-        Ok([rot_mat, sampler, texture])
+        let material_uv = BindGroupLayoutEntryArgs {
+            count: None,
+            visibility: ShaderStages::VERTEX,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+        };
+        Ok([rot_mat, sampler, texture, material_uv])
+    }
+}
+
+// texture array 每层的格式和尺寸, 重新上传某一层时不能变
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerInfo {
+    pub format: TextureFormat,
+    pub width: u32,
+    pub height: u32,
+    pub mip_level_count: u32,
+}
+
+impl LayerInfo {
+    pub fn of(img: &ImageMipMap) -> Self {
+        Self {
+            format: img.format,
+            width: img.width,
+            height: img.height,
+            mip_level_count: img.mip_map_count(),
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct ConstResourceBind {
     pub rot_mat: Buffer,
+    pub material_uv: Buffer,
     pub texture: Texture,
+    pub layer: LayerInfo,
     pub array_view: TextureView,
     pub sampler: Sampler,
 }
 
 impl ConstResourceBind {
-    pub fn get_bind_resource(&self) -> Result<[BindingResource; 3]> {
+    pub fn get_bind_resource(&self) -> Result<[BindingResource; 4]> {
         Ok([
             self.rot_mat.as_entire_binding(),
             BindingResource::Sampler(&self.sampler),
             BindingResource::TextureView(&self.array_view),
+            self.material_uv.as_entire_binding(),
         ])
     }
     pub fn write(&self, queue: &Queue, data: &ConstResource) {
         queue.write_buffer(&self.rot_mat, 0, cast_slice(&data.rot_mat));
+        queue.write_buffer(&self.material_uv, 0, cast_slice(&data.material_uv));
     }
 }
 
-pub struct PipelinePreparer {
-    pub vs: Shader,
-    pub fs: Shader,
+// 同一份 shader 生成的几种管线, shader 里用 #ifdef 区分
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Opaque,
+    Transparent,
+    // 需要设备支持 POLYGON_MODE_LINE
+    Wireframe,
 }
 
-impl PipelinePreparer {
-    pub fn init() -> Result<Self> {
-        let vs = Shader::from_path(
-            get_abs_path(VS_PATH)?,
-            ShaderType::Wgsl,
-            Shader::VS_FUNC_NAME.to_string(),
-        )?;
-        let fs = Shader::from_path(
-            get_abs_path(FS_PATH)?,
-            ShaderType::Wgsl,
-            Shader::FS_FUNC_NAME.to_string(),
-        )?;
-        Ok(Self { vs, fs })
+impl Variant {
+    pub const ALL: [Variant; 3] = [Variant::Opaque, Variant::Transparent, Variant::Wireframe];
+
+    pub fn define(&self) -> &'static str {
+        match self {
+            Variant::Opaque => "OPAQUE",
+            Variant::Transparent => "TRANSPARENT",
+            Variant::Wireframe => "WIREFRAME",
+        }
     }
 
-    pub fn create_pipeline<'a, I>(
-        &'a self,
-        device: &'a Device,
-        queue: &'a Queue,
+    // 画半透明材质的第二遍用的变体, 线框模式下也画成线框
+    pub fn translucent_pass(&self) -> Variant {
+        match self {
+            Variant::Wireframe => Variant::Wireframe,
+            _ => Variant::Transparent,
+        }
+    }
+}
+
+pub type CubePipeline = InstancedPipeline<CubeVertx, CubeInstance>;
+
+// 各变体只有混合, 深度写入, 多边形模式不同
+fn render_desc(variant: Variant, target: RenderTargetFormat) -> InstancedDesc {
+    InstancedDesc {
+        label: format!("Cube Pipeline {:?}", variant),
+        shader: SHADER_ID,
+        define: Some(variant.define()),
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: Some(Face::Back),
+            polygon_mode: match variant {
+                Variant::Wireframe => PolygonMode::Line,
+                _ => PolygonMode::Fill,
+            },
+            unclipped_depth: false,
+            conservative: false,
+        },
+        color: Some(ColorTargetState {
+            format: target.color,
+            blend: Some(match variant {
+                // alpha 通道里是自发光的比例, 被半透明的挡住时按不透明度变淡
+                Variant::Transparent => BlendState {
+                    color: BlendState::ALPHA_BLENDING.color,
+                    alpha: BlendComponent {
+                        src_factor: BlendFactor::Zero,
+                        dst_factor: BlendFactor::OneMinusSrcAlpha,
+                        operation: BlendOperation::Add,
+                    },
+                },
+                _ => BlendState::REPLACE,
+            }),
+            write_mask: ColorWrites::ALL,
+        }),
+        depth: Some(DepthStencilState {
+            format: target.depth,
+            depth_write_enabled: variant != Variant::Transparent,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: target.multisample(),
+    }
+}
+
+// 从太阳方向只画深度, 没有颜色输出, fs 只负责 cutout 的镂空
+fn shadow_desc() -> InstancedDesc {
+    InstancedDesc {
+        label: "Cube Shadow Pipeline".to_string(),
+        shader: SHADOW_SHADER_ID,
+        define: None,
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            // 面是单独的四边形, 背对太阳的也要投影
+            cull_mode: None,
+            polygon_mode: PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        color: None,
+        depth: Some(DepthStencilState {
+            format: shadow::SHADOW_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: CompareFunction::LessEqual,
+            stencil: StencilState::default(),
+            // 和 shader 里沿法线的偏移一起减少自阴影
+            bias: DepthBiasState {
+                constant: 2,
+                slope_scale: 2.0,
+                clamp: 0.0,
+            },
+        }),
+        multisample: MultisampleState::default(),
+    }
+}
+
+pub struct PipelinePreparer<'a> {
+    pub registry: &'a PackRegistry,
+    pub variant: Variant,
+}
+
+impl<'a> PipelinePreparer<'a> {
+    pub fn init(registry: &'a PackRegistry) -> Self {
+        Self::init_variant(registry, Variant::Opaque)
+    }
+
+    pub fn init_variant(registry: &'a PackRegistry, variant: Variant) -> Self {
+        Self { registry, variant }
+    }
+
+    pub fn create_pipeline<'b, I>(
+        &'b self,
+        device: &'b Device,
+        queue: &'b Queue,
         group_layouts: I,
-        target_format: TextureFormat,
-        depth_format: TextureFormat,
+        shadow_layout: &BindGroupLayout,
+        target: RenderTargetFormat,
     ) -> Result<Pipeline>
     where
-        I: IntoIterator<Item = &'a BindGroupLayout>,
+        I: IntoIterator<Item = &'b BindGroupLayout>,
     {
         // 绑定组
         let const_layout = create_bind_group_layout(
@@ -322,94 +607,46 @@ impl PipelinePreparer {
             Some("Cube Const Resource Group Layout"),
             &ConstResource::get_layout_args()?,
         )?;
+        // 热重载贴图时还要用, 所以留在 Pipeline 里
+        let const_resource = ConstResource::init(self.registry)?;
+        let const_bind = const_resource.create_bind(device, queue, self.registry)?;
         let const_group = {
-            let const_bind = {
-                let res = ConstResource::init();
-                res.create_bind(device, queue)?
-            };
             let binding = const_bind.get_bind_resource()?;
             create_bind_group(device, Some("Const Group"), &const_layout, &binding)?
         };
 
-        // 创建管线
-        let extend = [&const_layout];
-        let pipe_layout = {
-            let group_layouts = group_layouts.into_iter().map(|l| l).chain(extend);
-            create_pipeline_layout(device, Some("Cube Pipeline Layout"), group_layouts)?
-        };
-        let vs = create_shader_module(device, Some("Cube VS"), self.vs.data.as_shader_source())?;
-        let fs = create_shader_module(device, Some("Cube FS"), self.fs.data.as_shader_source())?;
-        let v = CubeVertx::attr_desc();
-        let i = CubeInstance::attr_desc();
-        let vbl = {
-            let v = CubeVertx::desc(&v);
-            let i = CubeInstance::desc(&i);
-            [v, i]
-        };
-        // 不透明的管线, 后面透明管线应该会有很多重复的参数, 再想办法
-        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
-            label: Some("Cube Pipeline"),
-            layout: Some(&pipe_layout),
-            vertex: VertexState {
-                module: &vs,
-                entry_point: self.vs.enter_point(),
-                buffers: &vbl,
-            },
-            fragment: Some(FragmentState {
-                module: &fs,
-                entry_point: self.fs.enter_point(),
-                targets: &[Some(ColorTargetState {
-                    format: target_format,
-                    blend: Some(BlendState::REPLACE),
-                    write_mask: ColorWrites::ALL,
-                })],
-            }),
-            primitive: PrimitiveState {
-                topology: PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: FrontFace::Ccw,
-                cull_mode: Some(Face::Back),
-                polygon_mode: PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: Some(DepthStencilState {
-                format: depth_format,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: StencilState::default(),
-                bias: DepthBiasState::default(),
-            }),
-            multisample: MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-        });
-
-        // 其他东西
-
-        // 共用的 vertex 和 index
-        let vertex = create_buffer(
+        // 创建管线, cube 自己的组排在全局的组后面
+        let mut group_layouts: Vec<&BindGroupLayout> = group_layouts.into_iter().collect();
+        let first_group = group_layouts.len() as u32;
+        group_layouts.push(&const_layout);
+        let registry = self.registry;
+        let pipeline = CubePipeline::new(
             device,
-            Some("Cube Vertex"),
-            BufferUsages::VERTEX,
-            TEST_VERTICES,
-        );
-        let index = create_buffer(
+            registry,
+            group_layouts.iter().copied(),
+            render_desc(self.variant, target),
+        )?;
+        let translucent = CubePipeline::new(
             device,
-            Some("Cube Index"),
-            BufferUsages::INDEX,
-            TEST_INDICES,
-        );
+            registry,
+            group_layouts.iter().copied(),
+            render_desc(self.variant.translucent_pass(), target),
+        )?;
+        // 阴影 pass 只用阴影的一组和 cube 自己的一组
+        let shadow_layouts = [shadow_layout, &const_layout];
+        let shadow = CubePipeline::new(device, registry, shadow_layouts, shadow_desc())?;
 
         Ok(Pipeline {
             pipeline,
+            translucent,
+            shadow,
+            variant: self.variant,
+            const_resource,
+            const_bind,
             groups: vec![const_group],
-            vertex,
-            index,
-            index_len: TEST_INDICES.len() as u32,
+            first_group,
+            // 共用的 vertex 和 index
+            geometry: Geometry::new(device, "Cube", TEST_VERTICES, TEST_INDICES),
             mesh_binds: Default::default(),
         })
     }
@@ -418,143 +655,508 @@ impl PipelinePreparer {
 pub type PipelineMeshBindKey = usize;
 #[derive(Debug)]
 pub struct Pipeline {
-    pub pipeline: RenderPipeline,
+    pub pipeline: CubePipeline,
+    // 第二遍画半透明材质用
+    pub translucent: CubePipeline,
+    pub shadow: CubePipeline,
+    pub variant: Variant,
+    pub const_resource: ConstResource,
+    pub const_bind: ConstResourceBind,
     pub groups: Vec<BindGroup>,
-    pub vertex: Buffer,
-    pub index: Buffer,
-    pub index_len: u32,
+    // groups 里第一组在 shader 里的 @group
+    pub first_group: u32,
+    // index buffer 里一个四边形有两种分法, 各占一半
+    pub geometry: Geometry<CubeVertx>,
     pub mesh_binds: HashMap<PipelineMeshBindKey, MeshBind>,
 }
 impl Pipeline {
-    // 一般只调用一次
-    pub fn start_pass<'a>(
-        &'a self,
-        encoder: &'a mut CommandEncoder,
-        target_view: &'a TextureView,
-        depth_view: &'a TextureView,
-        global_groups: impl IntoIterator<Item = &'a BindGroup>,
-    ) -> RenderPass<'a> {
-        // 创建管线
-        let mut render_pass = {
-            let color_att = RenderPassColorAttachment {
-                view: &target_view,
-                resolve_target: None,
-                ops: Operations {
-                    load: LoadOp::Clear(Color::BLACK),
-                    store: true,
-                },
-            };
-            let depth_stencil_att = RenderPassDepthStencilAttachment {
-                view: &depth_view,
-                depth_ops: Some(Operations {
-                    load: LoadOp::Clear(1.0),
-                    store: true,
-                }),
-                stencil_ops: None,
-            };
-            let desc = RenderPassDescriptor {
-                label: Some("Cube Opaque Render Pass"),
-                color_attachments: &[Some(color_att)],
-                depth_stencil_attachment: Some(depth_stencil_att),
-            };
-            encoder.begin_render_pass(&desc)
-        };
+    // 每帧在所有 pass 之前调用, 上传 mesh 的 instance, 放不下时 buffer 会变大
+    // 半透明的面每帧按到 eye 的距离从远到近排序后再上传
+    pub fn prepare(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        mesh: &mut Mesh,
+        eye: &Point3<f32>,
+    ) -> Result<()> {
+        let bind = self
+            .mesh_binds
+            .get_mut(&mesh.id)
+            .ok_or(anyhow!("mesh id 没有对应的 buffer"))?;
+        bind.write(device, queue, mesh);
+        mesh.sort_translucent(eye);
+        bind.write_translucent(device, queue, mesh);
+        Ok(())
+    }
 
-        // 通用资源绑定
-        render_pass.set_pipeline(&self.pipeline);
-        let mut idx = 0;
-        for g in global_groups {
-            render_pass.set_bind_group(idx, g, &[]);
-            idx += 1;
-        }
-        for g in &self.groups {
-            render_pass.set_bind_group(idx, g, &[]);
-            idx += 1;
+    fn mesh_bind(&self, mesh: &Mesh) -> Result<&MeshBind> {
+        self.mesh_binds
+            .get(&mesh.id)
+            .ok_or(anyhow!("mesh id 没有对应的 buffer"))
+    }
+
+    // 一个四边形的 index 数
+    fn index_len(&self) -> u32 {
+        self.geometry.index_count / 2
+    }
+
+    // cube 自己的绑定组, 全局的绑定组由 pass 的主人设置
+    fn set_groups<'a>(&'a self, render_pass: &mut RenderPass<'a>) {
+        for (i, g) in self.groups.iter().enumerate() {
+            render_pass.set_bind_group(self.first_group + i as u32, g, &[]);
         }
-        render_pass.set_vertex_buffer(0, self.vertex.slice(..));
-        render_pass.set_index_buffer(self.index.slice(..), IndexFormat::Uint16);
-        render_pass
     }
 
-    // 可多次调用
-    pub fn draw<'a, 'b>(
+    // 画到主 pass 上, 全局的绑定组要已经设置好, 可多次调用
+    pub fn draw<'a>(&'a self, render_pass: &mut RenderPass<'a>, mesh: &Mesh) -> Result<()> {
+        let bind = self.mesh_bind(mesh)?;
+        self.set_groups(render_pass);
+        // 翻转对角线的面排在后面, 用另一半 index
+        let (n, split, len) = (bind.mesh.instance.len(), mesh.unflipped as u32, self.index_len());
+        let (g, i) = (&self.geometry, &bind.mesh.instance);
+        self.pipeline.draw(render_pass, g, i, 0..len, 0..split);
+        self.pipeline.draw(render_pass, g, i, len..len * 2, split..n);
+        Ok(())
+    }
+
+    // 阴影 pass 里每级调用一次, 半透明的面不投影
+    pub fn draw_shadow<'a>(
         &'a self,
-        queue: &'a Queue,
-        render_pass: &'b mut RenderPass<'a>,
-        mesh: &'a mut Mesh,
+        render_pass: &mut RenderPass<'a>,
+        shadow: &'a Shadow,
+        cascade: usize,
+        mesh: &Mesh,
     ) -> Result<()> {
-        let bind = self
-            .mesh_binds
-            .get(&mesh.id)
-            .ok_or(anyhow!("mesh id 没有对应的 buffer"))?;
-        bind.write(queue, mesh);
-        render_pass.set_vertex_buffer(1, bind.instance_buffer.slice(..));
-        render_pass.draw_indexed(0..self.index_len, 0, 0..mesh.instance.len() as u32);
+        let bind = self.mesh_bind(mesh)?;
+        render_pass.set_bind_group(0, &shadow.cascade_group, &[shadow.cascade_offset(cascade)]);
+        render_pass.set_bind_group(1, &self.groups[0], &[]);
+        let n = bind.mesh.instance.len();
+        let indices = 0..self.index_len();
+        self.shadow
+            .draw(render_pass, &self.geometry, &bind.mesh.instance, indices, 0..n);
+        Ok(())
+    }
+
+    // 第二遍, 和 draw 在同一个 pass 里, 所有 draw 之后调用
+    // 为了排好的顺序不变, 不翻转对角线
+    pub fn draw_translucent<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        mesh: &Mesh,
+    ) -> Result<()> {
+        let bind = self.mesh_bind(mesh)?;
+        if bind.translucent.is_empty() {
+            return Ok(());
+        }
+        self.set_groups(render_pass);
+        let n = bind.translucent.len();
+        let indices = 0..self.index_len();
+        self.translucent
+            .draw(render_pass, &self.geometry, &bind.translucent, indices, 0..n);
+        Ok(())
+    }
+
+    // 重新读取 shader, 只替换 RenderPipeline, 三个都建好了才一起换, 有一个出错就都保持原样
+    pub fn reload_shader(&mut self, device: &Device, registry: &PackRegistry) -> Result<()> {
+        let pipeline = self.pipeline.build(device, registry, self.pipeline.desc.clone())?;
+        let translucent = self
+            .translucent
+            .build(device, registry, self.translucent.desc.clone())?;
+        let shadow = self.shadow.build(device, registry, self.shadow.desc.clone())?;
+        self.pipeline.apply(pipeline);
+        self.translucent.apply(translucent);
+        self.shadow.apply(shadow);
         Ok(())
     }
 
+    // 换了渲染目标的格式或多重采样次数时重建, 阴影管线画到自己的贴图上, 不用动
+    pub fn set_target(
+        &mut self,
+        device: &Device,
+        registry: &PackRegistry,
+        target: RenderTargetFormat,
+    ) -> Result<()> {
+        let pipeline = render_desc(self.variant, target);
+        let pipeline = self.pipeline.build(device, registry, pipeline)?;
+        let translucent = render_desc(self.variant.translucent_pass(), target);
+        let translucent = self.translucent.build(device, registry, translucent)?;
+        self.pipeline.apply(pipeline);
+        self.translucent.apply(translucent);
+        Ok(())
+    }
+
+    // 重新上传 changed 返回 true 的层, 格式和尺寸必须和原来一样, 返回上传的层数
+    pub fn reload_textures(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        registry: &PackRegistry,
+        mut changed: impl FnMut(&Path) -> bool,
+    ) -> Result<usize> {
+        let mut count = 0;
+        for (i, path) in self.const_resource.paths.iter().enumerate() {
+            if !changed(path) {
+                continue;
+            }
+            let img = self.const_resource.load_layer(device, registry, path)?;
+            let layer = LayerInfo::of(&img);
+            if layer != self.const_bind.layer {
+                bail!(
+                    "{:?} 变成了 {:?}, 和原来的 {:?} 不一致, 需要重启",
+                    path,
+                    layer,
+                    self.const_bind.layer
+                );
+            }
+            img.write_into_texture(queue, &self.const_bind.texture, i as u32);
+            count += 1;
+        }
+        Ok(count)
+    }
+
     fn new_cube_mesh_key(&mut self) -> PipelineMeshBindKey {
         1
     }
     pub fn new_cube_mesh(&mut self, device: &Device) -> Result<Mesh> {
         let id = self.new_cube_mesh_key();
-        let mesh = Mesh::empty(id);
-        let bind = Mesh::create_bind(device);
+        let mesh = Mesh::empty(id, self.const_resource.materials.clone());
+        let bind = MeshBind::new(device);
         self.mesh_binds.insert(id, bind);
         Ok(mesh)
     }
 }
 
-const VS_PATH: &'static str = "shader/cube_shader.wgsl";
-const FS_PATH: &'static str = "shader/cube_shader.wgsl";
+// 方块的边长, exp 为 0 时 TEST_VERTICES 是 -1..1
+pub const BLOCK_SIZE: f32 = 2.0;
+
+// 方块中心在 BLOCK_SIZE 的网格上时返回格子坐标, 不在网格上的方块不参与 AO
+pub fn grid_cell(pos: &Vector3<f32>) -> Option<[i32; 3]> {
+    let c = pos / BLOCK_SIZE;
+    let r = c.map(f32::round);
+    if (c - r).amax() > 1e-4 {
+        return None;
+    }
+    Some([r.x as i32, r.y as i32, r.z as i32])
+}
+
+// 面前方 (法线一侧) 的格子, 以及每个顶点往两个切线方向各走一格的偏移, 顺序和 TEST_VERTICES 一致
+// 不在网格上或者缩放过的面返回 None
+type FaceNeighbors = (Vector3<i32>, [(Vector3<i32>, Vector3<i32>); 4]);
+fn face_neighbors(ins: &CubeInstance) -> Option<FaceNeighbors> {
+    let cell = match grid_cell(&ins.position.into()) {
+        Some(c) if ins.info[0] == 0 => Vector3::from(c),
+        _ => return None,
+    };
+    let rot = Orient::<CompressedData>::decode(ins.info[1])
+        .uncompress()
+        .to_matrix_without_flip();
+    let to_grid = |v: Vector3<f32>| (rot * v).map(|x| x.round() as i32);
+    let normal = to_grid(Vector3::x());
+    let mut tangents = [(Vector3::zeros(), Vector3::zeros()); 4];
+    for (t, v) in tangents.iter_mut().zip(TEST_VERTICES) {
+        // 顶点相对方块中心的方向, 去掉法线后是两个切线方向的和
+        let tangent = to_grid(v.position.into()) - normal;
+        let axis = tangent.iamax();
+        let mut t1 = Vector3::zeros();
+        t1[axis] = tangent[axis];
+        *t = (t1, tangent - t1);
+    }
+    Some((cell + normal, tangents))
+}
+
+// 经典的体素 AO: 面的每个角看前方那一层的两个边上的格子和一个角上的格子,
+// 两边都有方块时角上那格被挡住, 算作最暗
+pub fn face_occlusion(light: &BlockLight, ins: &CubeInstance) -> [u8; 4] {
+    let (front, tangents) = match face_neighbors(ins) {
+        Some(n) => n,
+        None => return [0; 4],
+    };
+    let occupied = |d: Vector3<i32>| light.is_block((front + d).into());
+    tangents.map(|(t1, t2)| {
+        let (side1, side2) = (occupied(t1), occupied(t2));
+        if side1 && side2 {
+            3
+        } else {
+            side1 as u8 + side2 as u8 + occupied(t1 + t2) as u8
+        }
+    })
+}
+
+// 每个顶点取前方那一层围着它的四个格子里空格子的平均亮度, 相邻的面之间就是平滑的
+// 不在网格上的面是 0
+pub fn face_light(light: &BlockLight, ins: &CubeInstance) -> [u8; 4] {
+    let (front, tangents) = match face_neighbors(ins) {
+        Some(n) => n,
+        None => return [0; 4],
+    };
+    tangents.map(|(t1, t2)| {
+        let (sum, count) = [Vector3::zeros(), t1, t2, t1 + t2]
+            .iter()
+            .map(|d| <[i32; 3]>::from(front + d))
+            .filter(|c| !light.is_block(*c))
+            .fold((0u32, 0u32), |(s, n), c| (s + light.level(c) as u32, n + 1));
+        // 四个都是方块时没有光
+        (sum + count / 2).checked_div(count).unwrap_or(0) as u8
+    })
+}
+
+// 默认沿 0-2 对角线分, 0 和 2 更暗时暗的部分会沿对角线拉成一条, 这时改沿 1-3 分
+pub fn flip_diagonal(ao: &[u8; 4]) -> bool {
+    ao[0] + ao[2] > ao[1] + ao[3]
+}
+
+// vs, fs 在同一个文件里
+const SHADER_ID: &'static str = "base:cube";
+const SHADOW_SHADER_ID: &'static str = "base:cube_shadow";
+// gen_atlas 的输出目录
+pub const ATLAS_DIR: &'static str = "atlas";
 
 pub struct Mesh {
     id: PipelineMeshBindKey,
     changed: bool,
+    // 不透明和 cutout 的面, 上传前的 update_lighting 会把要翻转对角线的排到后面
     pub instance: Vec<CubeInstance>,
+    // instance 里不翻转对角线的个数
+    unflipped: usize,
+    // 半透明的面, 见 Pipeline::draw_translucent
+    pub translucent_instance: Vec<CubeInstance>,
+    materials: Rc<[MaterialProps]>,
+    // 网格上的方块和它们照亮的范围, 算 AO 和光照用, 见 grid_cell
+    pub block_light: BlockLight,
+}
+
+impl InstancedMesh for Mesh {
+    type Vertex = CubeVertx;
+    type Instance = CubeInstance;
+
+    // 上传前统一算 AO 和光照, 半透明的面每帧都传, 不走这里
+    fn take_changed(&mut self) -> Option<&[CubeInstance]> {
+        if !self.changed {
+            return None;
+        }
+        self.update_lighting();
+        self.changed = false;
+        Some(&self.instance)
+    }
 }
 
 impl Mesh {
-    pub const CAPABILITIES: u64 = 205;
-    pub(self) fn empty(id: PipelineMeshBindKey) -> Mesh {
+    pub(self) fn empty(id: PipelineMeshBindKey, materials: Rc<[MaterialProps]>) -> Mesh {
         Mesh {
             id,
             changed: false,
             instance: Vec::new(),
+            unflipped: 0,
+            translucent_instance: Vec::new(),
+            materials,
+            block_light: BlockLight::default(),
+        }
+    }
+    pub fn add_cube(&mut self, pos: Vector3<f32>, material: MaterialId) {
+        self.add_block(pos, &[material; 6], &Orient::identity());
+    }
+
+    // faces 是方块局部坐标系下各面的材质, 旋转(镜像)后贴图跟着面一起转
+    pub fn add_block(&mut self, pos: Vector3<f32>, faces: &[MaterialId; 6], orient: &Orient<Data>) {
+        for (code, material) in FACE_ORIENT_CODES.iter().zip(faces) {
+            let face = Orient::<CompressedData>::decode(*code).uncompress();
+            let world = orient.compose(&face);
+            let mut ins = CubeInstance {
+                info: [0, world.compressed().encode(), 0, 0],
+                position: pos.into(),
+                color: [1.0, 1.0, 1.0],
+                ao: [0; 4],
+                light: [0; 4],
+            };
+            ins.set_material(*material);
+            if self.material(*material).translucent {
+                self.translucent_instance.push(ins);
+            } else {
+                self.instance.push(ins);
+            }
+        }
+        if let Some(cell) = grid_cell(&pos) {
+            let light = faces.iter().map(|m| self.material(*m).light).max();
+            self.block_light.add_block(cell, light.unwrap_or(0));
         }
+        self.changed = true;
     }
-    pub fn add_cube(&mut self, pos: Vector3<f32>) {
-        for ins in TEST_INSTANCES {
-            let mut ins = ins.clone();
-            ins.position = (Vector3::from(ins.position) + pos).into();
-            self.instance.push(ins);
+
+    // 去掉中心在 pos 的方块的所有面, 没有这个方块时返回 false
+    pub fn remove_block(&mut self, pos: Vector3<f32>) -> bool {
+        let pos: [f32; 3] = pos.into();
+        let count = self.instance.len() + self.translucent_instance.len();
+        self.instance.retain(|ins| ins.position != pos);
+        self.translucent_instance.retain(|ins| ins.position != pos);
+        if count == self.instance.len() + self.translucent_instance.len() {
+            return false;
+        }
+        if let Some(cell) = grid_cell(&pos.into()) {
+            self.block_light.remove_block(cell);
         }
         self.changed = true;
+        true
     }
 
-    pub fn create_bind(device: &Device) -> MeshBind {
-        let instance_buffer = device.create_buffer(&BufferDescriptor {
-            label: Some("Mesh Instance Buffer"),
-            size: size_of::<CubeInstance>() as u64 * Self::CAPABILITIES * TEST_INSTANCES.len() as u64,
-            usage: BufferUsages::COPY_DST | BufferUsages::VERTEX,
-            mapped_at_creation: false,
-        });
-        MeshBind { instance_buffer }
+    fn material(&self, material: MaterialId) -> MaterialProps {
+        self.materials
+            .get(material as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // 后加减的方块会影响已有的面, 所以在上传前统一算 AO 和光照
+    pub fn update_lighting(&mut self) {
+        let block_light = &self.block_light;
+        let materials = &self.materials;
+        for ins in self
+            .instance
+            .iter_mut()
+            .chain(self.translucent_instance.iter_mut())
+        {
+            ins.ao = face_occlusion(block_light, ins);
+            // 发光的面至少有自己的亮度
+            let own = materials
+                .get(ins.material() as usize)
+                .map_or(0, |m| m.light);
+            ins.light = face_light(block_light, ins).map(|l| l.max(own));
+        }
+        self.instance.sort_by_key(|ins| flip_diagonal(&ins.ao));
+        self.unflipped = self
+            .instance
+            .partition_point(|ins| !flip_diagonal(&ins.ao));
+    }
+
+    // 从远到近, 按方块中心排序
+    // 同一个方块的几个面距离相同, 但开了背面剔除, 凸的方块正面之间不会互相遮挡, 所以顺序无所谓
+    pub fn sort_translucent(&mut self, eye: &Point3<f32>) {
+        let dist = |ins: &CubeInstance| (Point3::from(ins.position) - eye).norm_squared();
+        self.translucent_instance
+            .sort_by(|a, b| dist(b).total_cmp(&dist(a)));
     }
 }
 
+// 开始时每个 buffer 能放的面数, 不够时会变大
+const INITIAL_INSTANCES: usize = 1024;
+
 #[derive(Debug)]
 pub struct MeshBind {
-    instance_buffer: Buffer,
+    // 不透明和 cutout 的面
+    mesh: MeshBuffer<Mesh>,
+    translucent: InstanceBuffer<CubeInstance>,
 }
 
 impl MeshBind {
-    // todo 这里 data 不应该mut? 可以在最后统一设置 changed.
-    pub fn write(&self, queue: &Queue, data: &mut Mesh) {
-        if !data.changed {
-            return;
+    pub fn new(device: &Device) -> Self {
+        Self {
+            mesh: MeshBuffer::new(device, "Mesh Instance Buffer", INITIAL_INSTANCES),
+            translucent: InstanceBuffer::new(
+                device,
+                "Mesh Translucent Instance Buffer",
+                INITIAL_INSTANCES,
+            ),
+        }
+    }
+
+    // mesh 变了才上传
+    pub fn write(&mut self, device: &Device, queue: &Queue, data: &mut Mesh) {
+        self.mesh.write(device, queue, data);
+    }
+
+    // 排序后顺序每帧都可能变, 所以总是上传
+    pub fn write_translucent(&mut self, device: &Device, queue: &Queue, data: &Mesh) {
+        self.translucent
+            .write(device, queue, &data.translucent_instance);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 每个变体和阴影的 shader 都要能预处理, 且 vertex 输入和 CubeVertx, CubeInstance 一致
+    #[test]
+    fn shader_layouts() {
+        let registry = PackRegistry::load_dir("pack").unwrap();
+        let target = RenderTargetFormat {
+            color: TextureFormat::Rgba16Float,
+            depth: TextureFormat::Depth32Float,
+            sample_count: 1,
+        };
+        let attrs = vertex_attrs::<CubeVertx, CubeInstance>();
+        let descs = Variant::ALL.map(|v| render_desc(v, target));
+        for desc in descs.iter().chain([&shadow_desc()]) {
+            load_shader(&registry, desc.shader, desc.define, &attrs)
+                .unwrap_or_else(|e| panic!("{}: {:?}", desc.label, e));
+        }
+    }
+
+    #[test]
+    fn ao_of_top_face() {
+        let mut mesh = Mesh::empty(1, Rc::from(vec![MaterialProps::default()]));
+        // 上面一层的 +x 和 +z 各有一个方块, 上表面的 +x+z 角两边都被挡住
+        for pos in [[0.0, 0.0, 0.0], [2.0, 2.0, 0.0], [0.0, 2.0, 2.0]] {
+            mesh.add_cube(Vector3::from(pos), 0);
         }
-        queue.write_buffer(&self.instance_buffer, 0, cast_slice(&data.instance[..]));
-        data.changed = true;
+        // 不在网格上的不参与
+        mesh.add_cube(Vector3::new(-1.0, 2.0, 0.0), 0);
+        mesh.update_lighting();
+
+        let top = |mesh: &Mesh| {
+            *mesh
+                .instance
+                .iter()
+                .find(|ins| ins.position == [0.0; 3] && ins.info[1] == FACE_ORIENT_CODES[2])
+                .unwrap()
+        };
+        let ins = top(&mesh);
+        let mut sorted = ins.ao;
+        sorted.sort();
+        assert_eq!(sorted, [0, 1, 1, 3]);
+        // 最暗和最亮的角在同一条对角线上
+        let dark = ins.ao.iter().position(|&a| a == 3).unwrap();
+        assert_eq!(ins.ao[(dark + 2) % 4], 0);
+        assert_eq!(flip_diagonal(&ins.ao), dark % 2 == 0);
+        // 翻转的都在后面
+        assert!(mesh.instance[..mesh.unflipped]
+            .iter()
+            .all(|ins| !flip_diagonal(&ins.ao)));
+        assert!(mesh.instance[mesh.unflipped..]
+            .iter()
+            .all(|ins| flip_diagonal(&ins.ao)));
+        // 下表面没有邻居
+        assert!(mesh
+            .instance
+            .iter()
+            .filter(|ins| ins.position == [0.0; 3] && ins.info[1] == FACE_ORIENT_CODES[3])
+            .all(|ins| ins.ao == [0; 4]));
+    }
+
+    #[test]
+    fn lamp_lights_neighbor() {
+        let lamp = MaterialProps {
+            translucent: false,
+            light: 15,
+        };
+        let mut mesh = Mesh::empty(1, Rc::from(vec![MaterialProps::default(), lamp]));
+        mesh.add_cube(Vector3::new(0.0, 0.0, 0.0), 1);
+        mesh.add_cube(Vector3::new(4.0, 0.0, 0.0), 0);
+        mesh.update_lighting();
+        let face = |mesh: &Mesh, pos: [f32; 3], code: usize| {
+            mesh.instance
+                .iter()
+                .find(|ins| ins.position == pos && ins.info[1] == FACE_ORIENT_CODES[code])
+                .map(|ins| ins.light)
+        };
+        // 中间隔一格, 那一格是 14, 四周的 13 和 12, 平均是 13
+        assert_eq!(face(&mesh, [4.0, 0.0, 0.0], 1), Some([13; 4]));
+        assert_eq!(face(&mesh, [4.0, 0.0, 0.0], 0), Some([11; 4]));
+        assert_eq!(face(&mesh, [0.0, 0.0, 0.0], 3), Some([15; 4]));
+
+        assert!(mesh.remove_block(Vector3::new(0.0, 0.0, 0.0)));
+        assert!(!mesh.remove_block(Vector3::new(0.0, 0.0, 0.0)));
+        mesh.update_lighting();
+        assert_eq!(face(&mesh, [4.0, 0.0, 0.0], 1), Some([0; 4]));
+        assert_eq!(face(&mesh, [0.0, 0.0, 0.0], 3), None);
     }
 }
@@ -1 +1,2 @@
-pub mod cube;
\ No newline at end of file
+pub mod cube;
+pub mod sky;
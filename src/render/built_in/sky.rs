@@ -0,0 +1,103 @@
+// 天空: 一个盖满屏幕的三角形, 放在最远处
+//
+// 在不透明的方块之后画, 深度测试只让它落在深度还是清空值的地方, 被挡住的像素不用算.
+use anyhow::{bail, Result};
+use resource::pack::PackRegistry;
+use wgpu::*;
+
+use super::super::*;
+
+const SHADER_ID: &'static str = "base:sky";
+
+#[derive(Debug)]
+pub struct Pipeline {
+    pub pipeline: RenderPipeline,
+    pub layout: PipelineLayout,
+    pub target: RenderTargetFormat,
+}
+
+impl Pipeline {
+    // 只用全局的绑定组, 没有自己的
+    pub fn new<'a>(
+        device: &'a Device,
+        registry: &PackRegistry,
+        group_layouts: impl IntoIterator<Item = &'a BindGroupLayout>,
+        target: RenderTargetFormat,
+    ) -> Result<Self> {
+        let layout = create_pipeline_layout(device, Some("Sky Pipeline Layout"), group_layouts)?;
+        let pipeline = Self::create_render_pipeline(device, registry, &layout, target)?;
+        Ok(Self {
+            pipeline,
+            layout,
+            target,
+        })
+    }
+
+    fn create_render_pipeline(
+        device: &Device,
+        registry: &PackRegistry,
+        layout: &PipelineLayout,
+        target: RenderTargetFormat,
+    ) -> Result<RenderPipeline> {
+        // 没有顶点 buffer, 顶点位置由 vertex_index 算
+        let (vs, fs) = load_shader(registry, SHADER_ID, None, &[])?;
+        let vs_module = create_shader_module(device, Some("Sky VS"), &vs)?;
+        let fs_module = create_shader_module(device, Some("Sky FS"), &fs)?;
+        device.push_error_scope(ErrorFilter::Validation);
+        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("Sky Pipeline"),
+            layout: Some(layout),
+            vertex: VertexState {
+                module: &vs_module,
+                entry_point: vs.enter_point(),
+                buffers: &[],
+            },
+            fragment: Some(FragmentState {
+                module: &fs_module,
+                entry_point: fs.enter_point(),
+                targets: &[Some(ColorTargetState {
+                    format: target.color,
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: Some(DepthStencilState {
+                format: target.depth,
+                depth_write_enabled: false,
+                // 深度是 1, 和清空值相等时通过
+                depth_compare: CompareFunction::LessEqual,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: target.multisample(),
+            multiview: None,
+        });
+        if let Some(e) = pollster::block_on(device.pop_error_scope()) {
+            bail!("创建天空管线失败:\n{}", e);
+        }
+        Ok(pipeline)
+    }
+
+    // 全局绑定组要已经设置好, 之后画别的要重新 set_pipeline
+    pub fn draw<'a>(&'a self, render_pass: &mut RenderPass<'a>) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    // 出错时保持原样
+    pub fn reload_shader(&mut self, device: &Device, registry: &PackRegistry) -> Result<()> {
+        self.set_target(device, registry, self.target)
+    }
+
+    pub fn set_target(
+        &mut self,
+        device: &Device,
+        registry: &PackRegistry,
+        target: RenderTargetFormat,
+    ) -> Result<()> {
+        self.pipeline = Self::create_render_pipeline(device, registry, &self.layout, target)?;
+        self.target = target;
+        Ok(())
+    }
+}
@@ -12,6 +12,17 @@ use crate::utils::builder_set_fn;
 
 use super::*;
 
+// nalgebra 的投影矩阵是 OpenGL 的, 深度 -1..1, wgpu 要 0..1
+#[rustfmt::skip]
+pub fn opengl_to_wgpu() -> Matrix4<f32> {
+    Matrix4::new(
+        1.0, 0.0, 0.0, 0.0,
+        0.0, 1.0, 0.0, 0.0,
+        0.0, 0.0, 0.5, 0.5,
+        0.0, 0.0, 0.0, 1.0,
+    )
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct Camera {
@@ -55,14 +66,7 @@ impl Camera {
 
     pub fn calculate_proj(&mut self) {
         let proj = Perspective3::new(self.aspect, self.fovy, self.znear, self.zfar);
-        #[rustfmt::skip]
-        let OPENGL_TO_WGPU_MATRIX = Matrix4::new(
-            1.0, 0.0, 0.0, 0.0,
-            0.0, 1.0, 0.0, 0.0,
-            0.0, 0.0, 0.5, 0.5,
-            0.0, 0.0, 0.0, 1.0,
-        );
-        self.proj_matrix = (OPENGL_TO_WGPU_MATRIX * proj.into_inner()).into();
+        self.proj_matrix = (opengl_to_wgpu() * proj.into_inner()).into();
     }
 
     pub fn calculate(&mut self) {
@@ -0,0 +1,184 @@
+// 截图和按相机路径录制图片序列, 都是用 render_to_image 画到离屏贴图再读回
+//
+// 录制时每帧的时间是固定步长, 和实际帧率无关, 慢的机器上也能得到均匀的序列.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::*;
+use chrono::Local;
+use nalgebra::{Point3, Vector3};
+
+use super::{camera::Camera, RenderState};
+use crate::scene::Scene;
+
+// 本地时间, 精确到毫秒, 可以直接做文件名
+pub fn timestamp() -> String {
+    Local::now().format("%Y-%m-%d_%H-%M-%S-%3f").to_string()
+}
+
+// 保存成 dir/<时间>.png, 返回文件路径
+pub fn screenshot(
+    render: &mut RenderState,
+    camera: &Camera,
+    scene: &mut Scene,
+    dir: impl AsRef<Path>,
+) -> Result<PathBuf> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir).with_context(|| format!("创建 {:?} 失败", dir))?;
+    let path = dir.join(format!("{}.png", timestamp()));
+    render
+        .render_to_image(camera, scene)?
+        .save(&path)
+        .with_context(|| format!("保存 {:?} 失败", path))?;
+    Ok(path)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CameraKey {
+    // 从路径开始算的秒数
+    pub time: f32,
+    pub position: Point3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+// 按时间排列的关键帧, 中间线性插值
+#[derive(Debug, Clone, Default)]
+pub struct CameraPath {
+    pub keys: Vec<CameraKey>,
+}
+
+impl CameraPath {
+    // time 要比已有的关键帧都晚, 否则忽略
+    pub fn record(&mut self, time: f32, camera: &Camera) {
+        if self.keys.last().is_some_and(|k| k.time >= time) {
+            return;
+        }
+        self.keys.push(CameraKey {
+            time,
+            position: camera.position,
+            direction: camera.direction,
+        });
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keys.last().map(|k| k.time).unwrap_or(0.0)
+    }
+
+    // 把 time 时刻的位置和方向设置到 camera 上, 超出范围的取两端
+    pub fn apply(&self, time: f32, camera: &mut Camera) {
+        let i = self.keys.partition_point(|k| k.time <= time);
+        let (position, direction) = match (i.checked_sub(1), self.keys.get(i)) {
+            (Some(a), Some(b)) => {
+                let (a, b) = (&self.keys[a], b);
+                let t = (time - a.time) / (b.time - a.time);
+                (
+                    a.position + (b.position - a.position) * t,
+                    a.direction.lerp(&b.direction, t),
+                )
+            }
+            (Some(a), None) => (self.keys[a].position, self.keys[a].direction),
+            (None, Some(b)) => (b.position, b.direction),
+            (None, None) => return,
+        };
+        camera.position = position;
+        // 方向相反的两帧之间插值可能接近 0, 这时保持原来的方向
+        if let Some(direction) = direction.try_normalize(1e-6) {
+            camera.direction = direction;
+        }
+        camera.calculate();
+    }
+}
+
+// 把路径按固定步长逐帧画成 dir/00000.png, 00001.png, ...
+pub struct Capture {
+    pub path: CameraPath,
+    pub dir: PathBuf,
+    pub fps: f32,
+    frame: u32,
+}
+
+impl Capture {
+    pub fn new(path: CameraPath, dir: impl AsRef<Path>, fps: f32) -> Result<Self> {
+        if path.keys.is_empty() {
+            bail!("相机路径是空的");
+        }
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).with_context(|| format!("创建 {:?} 失败", dir))?;
+        Ok(Self {
+            path,
+            dir: dir.to_path_buf(),
+            fps,
+            frame: 0,
+        })
+    }
+
+    // 包括开头和结尾两帧
+    pub fn frame_count(&self) -> u32 {
+        (self.path.duration() * self.fps).floor() as u32 + 1
+    }
+
+    // 画下一帧并保存, camera 会被设置成这一帧的位置, 全部画完后返回 false
+    pub fn next_frame(
+        &mut self,
+        render: &mut RenderState,
+        camera: &mut Camera,
+        scene: &mut Scene,
+    ) -> Result<bool> {
+        if self.frame >= self.frame_count() {
+            return Ok(false);
+        }
+        self.path.apply(self.frame as f32 / self.fps, camera);
+        let file = self.dir.join(format!("{:05}.png", self.frame));
+        render
+            .render_to_image(camera, scene)?
+            .save(&file)
+            .with_context(|| format!("保存 {:?} 失败", file))?;
+        self.frame += 1;
+        Ok(self.frame < self.frame_count())
+    }
+
+    // 无窗口时一次画完, 返回帧数
+    pub fn run(
+        mut self,
+        render: &mut RenderState,
+        camera: &mut Camera,
+        scene: &mut Scene,
+    ) -> Result<u32> {
+        while self.next_frame(render, camera, scene)? {}
+        Ok(self.frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camera_path_sample() {
+        let mut camera = Camera::default();
+        let mut path = CameraPath::default();
+        path.record(0.0, &camera);
+        camera.position = Point3::new(10.0, 0.0, 0.0);
+        camera.direction = Vector3::new(1.0, 0.0, 0.0);
+        path.record(2.0, &camera);
+        // 时间倒退的忽略
+        path.record(1.0, &camera);
+        assert_eq!(path.keys.len(), 2);
+
+        path.apply(0.5, &mut camera);
+        assert!((camera.position - Point3::new(2.5, 0.0, 0.0)).norm() < 1e-5);
+        assert!((camera.direction.norm() - 1.0).abs() < 1e-5);
+        path.apply(5.0, &mut camera);
+        assert_eq!(camera.position, Point3::new(10.0, 0.0, 0.0));
+
+        let capture = Capture {
+            path,
+            dir: PathBuf::new(),
+            fps: 30.0,
+            frame: 0,
+        };
+        assert_eq!(capture.frame_count(), 61);
+    }
+}
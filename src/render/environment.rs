@@ -0,0 +1,216 @@
+// 一天中的时刻, 以及跟着时刻变的太阳, 天空和雾
+//
+// 时刻决定太阳的位置, 太阳的高度再决定光和天空的颜色.
+// 雾的颜色取天空在视线方向上的颜色, 远处的方块慢慢融进天空, 视距边上的方块不会突然出现.
+use std::f32::consts::{PI, TAU};
+
+use nalgebra::{Matrix4, Vector3};
+use serde::{Deserialize, Serialize};
+use wgpu::{
+    util::{BufferInitDescriptor, DeviceExt},
+    *,
+};
+
+use super::{camera::Camera, light::Light, *};
+
+pub const DAY_HOURS: f32 = 24.0;
+
+// 正午时太阳偏离天顶的角度, 往 +z 偏
+const SUN_TILT: f32 = 0.5;
+
+// 颜色都是线性空间的
+const SUN_COLOR: [f32; 3] = [1.0, 0.95, 0.85];
+const SUNSET_COLOR: [f32; 3] = [1.0, 0.55, 0.3];
+const DAY_AMBIENT: [f32; 3] = [0.3, 0.32, 0.38];
+const NIGHT_AMBIENT: [f32; 3] = [0.03, 0.04, 0.08];
+const DAY_ZENITH: [f32; 3] = [0.15, 0.35, 0.8];
+const DAY_HORIZON: [f32; 3] = [0.6, 0.72, 0.9];
+const NIGHT_ZENITH: [f32; 3] = [0.005, 0.008, 0.02];
+const NIGHT_HORIZON: [f32; 3] = [0.02, 0.03, 0.06];
+const SUNSET_HORIZON: [f32; 3] = [0.9, 0.45, 0.2];
+
+// 随世界存档保存, 见 scene::save
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Environment {
+    // 0..24 小时, 6 点日出, 12 点太阳最高, 18 点日落
+    pub time: f32,
+    // 现实中多少秒过完一天, 不大于 0 时时间停住
+    pub day_length: f32,
+    // 雾开始和完全看不见的距离, 是相机 zfar 的比例
+    pub fog_start: f32,
+    pub fog_end: f32,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self {
+            time: 9.0,
+            day_length: 1200.0,
+            fog_start: 0.5,
+            fog_end: 0.95,
+        }
+    }
+}
+
+// 与 shader 里的 Environment 对应, vec3 后面紧跟一个 f32 正好 16 字节
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct EnvironmentUniform {
+    // 天空从屏幕坐标反推视线用
+    pub inv_view_proj: [[f32; 4]; 4],
+    pub camera_pos: [f32; 3],
+    pub time: f32,
+    pub zenith: [f32; 3],
+    pub fog_start: f32,
+    pub horizon: [f32; 3],
+    pub fog_end: f32,
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> Vector3<f32> {
+    Vector3::from(a).lerp(&Vector3::from(b), t)
+}
+
+impl Environment {
+    pub fn set_time(&mut self, hours: f32) {
+        self.time = hours.rem_euclid(DAY_HOURS);
+    }
+
+    // 每帧调用, dt 是现实中的秒数
+    pub fn advance(&mut self, dt: f32) {
+        if self.day_length > 0.0 {
+            self.set_time(self.time + dt / self.day_length * DAY_HOURS);
+        }
+    }
+
+    // 从地面指向太阳, 单位向量. 从 +x 升起, 在 -x 落下
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        // 正午是 0, 午夜是 ±π
+        let hour_angle = self.time / DAY_HOURS * TAU - PI;
+        let noon = Vector3::new(0.0, SUN_TILT.cos(), SUN_TILT.sin());
+        let east = Vector3::x();
+        noon * hour_angle.cos() - east * hour_angle.sin()
+    }
+
+    // 0 是夜里, 1 是白天, 日出日落时在中间
+    pub fn daylight(&self) -> f32 {
+        smoothstep(-0.1, 0.2, self.sun_direction().y)
+    }
+
+    // 太阳贴近地平线时天边和阳光发红, 太阳在地平线以下一点也还有余晖
+    fn twilight(&self) -> f32 {
+        1.0 - smoothstep(0.0, 0.35, self.sun_direction().y.abs())
+    }
+
+    // 按时刻设置太阳的方向和颜色, 夜里只剩很暗的环境光
+    pub fn apply(&self, light: &mut Light) {
+        let (day, twilight) = (self.daylight(), self.twilight());
+        light.set_direction(&self.sun_direction());
+        light.color = mix(SUN_COLOR, SUNSET_COLOR, twilight) * day;
+        light.ambient = mix(NIGHT_AMBIENT, DAY_AMBIENT, day);
+    }
+
+    // (天顶, 地平线) 的颜色
+    pub fn sky_colors(&self) -> (Vector3<f32>, Vector3<f32>) {
+        let (day, twilight) = (self.daylight(), self.twilight());
+        let zenith = mix(NIGHT_ZENITH, DAY_ZENITH, day);
+        let horizon = mix(NIGHT_HORIZON, DAY_HORIZON, day)
+            .lerp(&Vector3::from(SUNSET_HORIZON), twilight * 0.7);
+        (zenith, horizon)
+    }
+
+    pub fn to_uniform(&self, camera: &Camera) -> EnvironmentUniform {
+        let (zenith, horizon) = self.sky_colors();
+        let inv_view_proj = (camera.proj_matrix * camera.view_matrix)
+            .try_inverse()
+            .unwrap_or_else(Matrix4::identity);
+        EnvironmentUniform {
+            inv_view_proj: inv_view_proj.into(),
+            camera_pos: camera.position.coords.into(),
+            time: self.time,
+            zenith: zenith.into(),
+            fog_start: self.fog_start * camera.zfar,
+            horizon: horizon.into(),
+            fog_end: self.fog_end * camera.zfar,
+        }
+    }
+
+    pub fn create_binding(&self, device: &Device, camera: &Camera) -> EnvironmentBind {
+        let buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Environment"),
+            contents: bytemuck::cast_slice(&[self.to_uniform(camera)]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+        EnvironmentBind { buffer }
+    }
+
+    pub fn get_layout_args() -> [BindGroupLayoutEntryArgs; 1] {
+        let environment_desc = BindGroupLayoutEntryArgs {
+            visibility: ShaderStages::FRAGMENT,
+            count: None,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+        };
+        [environment_desc]
+    }
+}
+
+#[derive(Debug)]
+pub struct EnvironmentBind {
+    pub buffer: Buffer,
+}
+
+impl EnvironmentBind {
+    pub fn write(&self, queue: &Queue, environment: &Environment, camera: &Camera) {
+        let uniform = environment.to_uniform(camera);
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    pub fn get_bind_resource(&self) -> [BindingResource<'_>; 1] {
+        [self.buffer.as_entire_binding()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sun_follows_time() {
+        let mut env = Environment {
+            day_length: 240.0,
+            ..Default::default()
+        };
+        let at = |env: &mut Environment, hours: f32| {
+            env.set_time(hours);
+            env.sun_direction()
+        };
+        assert!((at(&mut env, 6.0) - Vector3::x()).norm() < 1e-5);
+        assert!((at(&mut env, 18.0) + Vector3::x()).norm() < 1e-5);
+        assert!((at(&mut env, 12.0).y - SUN_TILT.cos()).abs() < 1e-5);
+        assert!(at(&mut env, 0.0).y < -0.8);
+        assert_eq!(env.daylight(), 0.0);
+
+        // 240 秒一天, 30 秒是 3 小时, 过了午夜回到 0
+        env.set_time(23.0);
+        env.advance(30.0);
+        assert!((env.time - 2.0).abs() < 1e-4);
+
+        let mut light = Light::default();
+        env.set_time(12.0);
+        env.apply(&mut light);
+        assert!((light.direction() - env.sun_direction()).norm() < 1e-5);
+        assert!((light.color - Vector3::from(SUN_COLOR)).norm() < 1e-5);
+        env.set_time(0.0);
+        env.apply(&mut light);
+        assert_eq!(light.color, Vector3::zeros());
+    }
+}
@@ -0,0 +1,526 @@
+// 渲染图: pass 声明画到哪些贴图上, 采样哪些贴图, 贴图统一在这里创建, 跟着 surface 的尺寸重建
+//
+// 先 declare 贴图, 按执行顺序 add_pass, 再 create. 贴图的 usage 和附件画完要不要保存都由声明推出来:
+// 之后没有 pass 采样或接着画的附件不保存. 每帧按顺序 begin 每个 pass, 管线只往给它的 RenderPass 里画.
+use std::collections::HashSet;
+
+use anyhow::*;
+use wgpu::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PassId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextureSize {
+    // surface 的尺寸除以 divisor, resize 时重建
+    Surface { divisor: u32 },
+    Fixed { width: u32, height: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextureDesc {
+    pub label: &'static str,
+    pub format: TextureFormat,
+    pub size: TextureSize,
+    // 为 Some 时是贴图数组, 采样用的 view 是 D2Array, 当附件时用 Target::Layer 选一层
+    pub layers: Option<u32>,
+    pub sample_count: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    // 每帧 begin 时给的, 一般是 surface, 尺寸和 surface 一样
+    Output,
+    Texture(TextureId),
+    Layer(TextureId, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorAttachment {
+    pub target: Target,
+    // 多重采样的附件画完 resolve 到这里
+    pub resolve: Option<Target>,
+    // None 时接着上一个 pass 画的内容
+    pub clear: Option<Color>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthAttachment {
+    pub target: Target,
+    pub clear: Option<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PassDesc<K> {
+    pub label: &'static str,
+    // 录制时用来区分 pass, 见 passes
+    pub kind: K,
+    pub color: Vec<ColorAttachment>,
+    pub depth: Option<DepthAttachment>,
+    // 采样的贴图, 必须是前面的 pass 画过的
+    pub reads: Vec<TextureId>,
+}
+
+impl<K> PassDesc<K> {
+    // 画到的贴图, 包括 resolve 的目标
+    fn writes(&self) -> impl Iterator<Item = Target> + '_ {
+        self.color
+            .iter()
+            .flat_map(|c| [Some(c.target), c.resolve])
+            .chain([self.depth.map(|d| d.target)])
+            .flatten()
+    }
+
+    // 接着画, 需要之前的内容
+    fn loads(&self, id: TextureId) -> bool {
+        let color = self
+            .color
+            .iter()
+            .any(|c| c.clear.is_none() && c.target.texture() == Some(id));
+        let depth = self
+            .depth
+            .is_some_and(|d| d.clear.is_none() && d.target.texture() == Some(id));
+        color || depth
+    }
+}
+
+impl Target {
+    fn texture(&self) -> Option<TextureId> {
+        match self {
+            Target::Output => None,
+            Target::Texture(id) | Target::Layer(id, _) => Some(*id),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct GraphTexture {
+    desc: TextureDesc,
+    // create 之后才有: (贴图, 采样用的 view, 每层的 view)
+    allocated: Option<(Texture, TextureView, Vec<TextureView>)>,
+}
+
+#[derive(Debug)]
+pub struct RenderGraph<K> {
+    width: u32,
+    height: u32,
+    textures: Vec<GraphTexture>,
+    passes: Vec<PassDesc<K>>,
+}
+
+impl<K> RenderGraph<K> {
+    // 尺寸是 surface 的
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            textures: Vec::new(),
+            passes: Vec::new(),
+        }
+    }
+
+    pub fn declare(&mut self, desc: TextureDesc) -> TextureId {
+        self.textures.push(GraphTexture {
+            desc,
+            allocated: None,
+        });
+        TextureId(self.textures.len() - 1)
+    }
+
+    // 按执行顺序加, 附件的尺寸和采样次数要一致
+    pub fn add_pass(&mut self, desc: PassDesc<K>) -> Result<PassId> {
+        let label = &desc.label;
+        let mut attachment: Option<(TextureSize, u32)> = None;
+        for target in desc
+            .color
+            .iter()
+            .map(|c| c.target)
+            .chain(desc.depth.map(|d| d.target))
+        {
+            let info = self.target_info(target)?;
+            match attachment {
+                Some(a) if a != info => bail!("pass {} 的附件尺寸或采样次数不一致", label),
+                _ => attachment = Some(info),
+            }
+        }
+        for c in desc.color.iter() {
+            if let Some(resolve) = c.resolve {
+                let (size, samples) = self.target_info(c.target)?;
+                if samples == 1 || self.target_info(resolve)? != (size, 1) {
+                    bail!(
+                        "pass {} 只能从多重采样的附件 resolve 到一样大的普通贴图",
+                        label
+                    );
+                }
+            }
+        }
+        let written: HashSet<TextureId> = self
+            .passes
+            .iter()
+            .flat_map(|p| p.writes())
+            .filter_map(|t| t.texture())
+            .collect();
+        for id in desc.reads.iter() {
+            self.texture(*id)?;
+            if !written.contains(id) {
+                bail!(
+                    "pass {} 采样的 {} 前面没有 pass 画过",
+                    label,
+                    self.textures[id.0].desc.label
+                );
+            }
+            if desc.writes().any(|t| t.texture() == Some(*id)) {
+                bail!(
+                    "pass {} 不能同时画到和采样 {}",
+                    label,
+                    self.textures[id.0].desc.label
+                );
+            }
+        }
+        for t in desc.writes().filter_map(|t| t.texture()) {
+            if desc.loads(t) && !written.contains(&t) {
+                bail!(
+                    "pass {} 接着画的 {} 前面没有 pass 画过",
+                    label,
+                    self.textures[t.0].desc.label
+                );
+            }
+        }
+        self.passes.push(desc);
+        Ok(PassId(self.passes.len() - 1))
+    }
+
+    // (尺寸, 采样次数)
+    fn target_info(&self, target: Target) -> Result<(TextureSize, u32)> {
+        let (id, layer) = match target {
+            Target::Output => return Ok((TextureSize::Surface { divisor: 1 }, 1)),
+            Target::Texture(id) => (id, None),
+            Target::Layer(id, layer) => (id, Some(layer)),
+        };
+        let desc = &self.texture(id)?.desc;
+        match (desc.layers, layer) {
+            (None, None) => {}
+            (Some(n), Some(l)) if l < n => {}
+            (Some(_), None) => bail!("贴图数组 {} 要选一层当附件", desc.label),
+            _ => bail!("{} 没有第 {:?} 层", desc.label, layer),
+        }
+        Ok((desc.size, desc.sample_count))
+    }
+
+    fn texture(&self, id: TextureId) -> Result<&GraphTexture> {
+        self.textures
+            .get(id.0)
+            .ok_or(anyhow!("渲染图里没有贴图 {:?}", id))
+    }
+
+    // 当附件的要 RENDER_ATTACHMENT, 被采样的要 TEXTURE_BINDING
+    fn usage(&self, id: TextureId) -> TextureUsages {
+        let mut usage = TextureUsages::empty();
+        for pass in self.passes.iter() {
+            if pass.writes().any(|t| t.texture() == Some(id)) {
+                usage |= TextureUsages::RENDER_ATTACHMENT;
+            }
+            if pass.reads.contains(&id) {
+                usage |= TextureUsages::TEXTURE_BINDING;
+            }
+        }
+        usage
+    }
+
+    // 第 pass 个 pass 画完 target 后要不要保存, 画到 Output 上的总是保存
+    fn stores(&self, pass: usize, target: Target) -> bool {
+        let id = match target.texture() {
+            Some(id) => id,
+            None => return true,
+        };
+        self.passes[pass + 1..]
+            .iter()
+            .any(|p| p.reads.contains(&id) || p.loads(id))
+    }
+
+    fn extent(&self, desc: &TextureDesc) -> Extent3d {
+        let (width, height) = match desc.size {
+            TextureSize::Surface { divisor } => (self.width / divisor, self.height / divisor),
+            TextureSize::Fixed { width, height } => (width, height),
+        };
+        Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: desc.layers.unwrap_or(1),
+        }
+    }
+
+    // 所有 pass 加完以后调用, 创建声明的贴图
+    pub fn create(&mut self, device: &Device) -> Result<()> {
+        for i in 0..self.textures.len() {
+            self.allocate(device, i)?;
+        }
+        Ok(())
+    }
+
+    fn allocate(&mut self, device: &Device, i: usize) -> Result<()> {
+        let usage = self.usage(TextureId(i));
+        let desc = &self.textures[i].desc;
+        if usage.is_empty() {
+            bail!("没有 pass 用到 {}", desc.label);
+        }
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some(desc.label),
+            size: self.extent(desc),
+            mip_level_count: 1,
+            sample_count: desc.sample_count,
+            dimension: TextureDimension::D2,
+            format: desc.format,
+            usage,
+        });
+        // 只有一层时默认的 view 是 D2, 所以要指定
+        let view = texture.create_view(&TextureViewDescriptor {
+            dimension: desc.layers.map(|_| TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let layers = (0..desc.layers.unwrap_or(0))
+            .map(|layer| {
+                texture.create_view(&TextureViewDescriptor {
+                    dimension: Some(TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        self.textures[i].allocated = Some((texture, view, layers));
+        Ok(())
+    }
+
+    // 重建和 surface 一样大的贴图, 之后用到它们的绑定组也要重建
+    pub fn resize(&mut self, device: &Device, width: u32, height: u32) -> Result<()> {
+        (self.width, self.height) = (width, height);
+        for i in 0..self.textures.len() {
+            let t = &self.textures[i];
+            if t.allocated.is_some() && matches!(t.desc.size, TextureSize::Surface { .. }) {
+                self.allocate(device, i)?;
+            }
+        }
+        Ok(())
+    }
+
+    // 采样用的 view
+    pub fn view(&self, id: TextureId) -> Result<&TextureView> {
+        let t = self.texture(id)?;
+        match &t.allocated {
+            Some((_, view, _)) => Ok(view),
+            None => bail!("{} 还没有 create", t.desc.label),
+        }
+    }
+
+    fn target_view<'a>(
+        &'a self,
+        target: Target,
+        output: &'a TextureView,
+    ) -> Result<&'a TextureView> {
+        match target {
+            Target::Output => Ok(output),
+            Target::Texture(id) => self.view(id),
+            Target::Layer(id, layer) => {
+                self.view(id)?;
+                let (_, _, layers) = self.textures[id.0].allocated.as_ref().unwrap();
+                Ok(&layers[layer as usize])
+            }
+        }
+    }
+
+    // 按执行顺序
+    pub fn passes(&self) -> impl Iterator<Item = (PassId, &K)> {
+        self.passes
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (PassId(i), &p.kind))
+    }
+
+    // 开始一个 pass, 按声明清空附件, 绑定组和管线由画的一方设置
+    pub fn begin<'a>(
+        &'a self,
+        encoder: &'a mut CommandEncoder,
+        pass: PassId,
+        output: &'a TextureView,
+    ) -> Result<RenderPass<'a>> {
+        let desc = self
+            .passes
+            .get(pass.0)
+            .ok_or(anyhow!("渲染图里没有 pass {:?}", pass))?;
+        let mut color = Vec::new();
+        for c in desc.color.iter() {
+            color.push(Some(RenderPassColorAttachment {
+                view: self.target_view(c.target, output)?,
+                resolve_target: match c.resolve {
+                    Some(r) => Some(self.target_view(r, output)?),
+                    None => None,
+                },
+                ops: Operations {
+                    load: c.clear.map_or(LoadOp::Load, LoadOp::Clear),
+                    store: self.stores(pass.0, c.target),
+                },
+            }));
+        }
+        let depth = match desc.depth {
+            Some(d) => Some(RenderPassDepthStencilAttachment {
+                view: self.target_view(d.target, output)?,
+                depth_ops: Some(Operations {
+                    load: d.clear.map_or(LoadOp::Load, LoadOp::Clear),
+                    store: self.stores(pass.0, d.target),
+                }),
+                stencil_ops: None,
+            }),
+            None => None,
+        };
+        Ok(encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some(desc.label),
+            color_attachments: &color,
+            depth_stencil_attachment: depth,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(
+        label: &'static str,
+        size: TextureSize,
+        layers: Option<u32>,
+        sample_count: u32,
+    ) -> TextureDesc {
+        TextureDesc {
+            label,
+            format: TextureFormat::Rgba16Float,
+            size,
+            layers,
+            sample_count,
+        }
+    }
+
+    fn pass(
+        label: &'static str,
+        color: Vec<ColorAttachment>,
+        reads: Vec<TextureId>,
+    ) -> PassDesc<()> {
+        PassDesc {
+            label,
+            kind: (),
+            color,
+            depth: None,
+            reads,
+        }
+    }
+
+    fn clear(target: Target) -> ColorAttachment {
+        ColorAttachment {
+            target,
+            resolve: None,
+            clear: Some(Color::BLACK),
+        }
+    }
+
+    #[test]
+    fn declare_and_check() {
+        let full = TextureSize::Surface { divisor: 1 };
+        let mut graph = RenderGraph::new(64, 32);
+        let shadow = graph.declare(desc(
+            "shadow",
+            TextureSize::Fixed {
+                width: 16,
+                height: 16,
+            },
+            Some(2),
+            1,
+        ));
+        let msaa = graph.declare(desc("msaa", full, None, 4));
+        let hdr = graph.declare(desc("hdr", full, None, 1));
+        let half = graph.declare(desc("half", TextureSize::Surface { divisor: 2 }, None, 1));
+
+        // 还没画过就采样
+        assert!(graph
+            .add_pass(pass(
+                "main",
+                vec![clear(Target::Texture(hdr))],
+                vec![shadow]
+            ))
+            .is_err());
+        // 数组要选一层, 层数不能越界
+        assert!(graph
+            .add_pass(pass("shadow", vec![clear(Target::Texture(shadow))], vec![]))
+            .is_err());
+        assert!(graph
+            .add_pass(pass(
+                "shadow",
+                vec![clear(Target::Layer(shadow, 2))],
+                vec![]
+            ))
+            .is_err());
+        for layer in 0..2 {
+            graph
+                .add_pass(pass(
+                    "shadow",
+                    vec![clear(Target::Layer(shadow, layer))],
+                    vec![],
+                ))
+                .unwrap();
+        }
+        // 尺寸和采样次数不一致
+        let mixed = vec![clear(Target::Texture(hdr)), clear(Target::Texture(half))];
+        assert!(graph.add_pass(pass("main", mixed, vec![shadow])).is_err());
+        let resolve = |resolve| ColorAttachment {
+            resolve: Some(resolve),
+            ..clear(Target::Texture(msaa))
+        };
+        assert!(graph
+            .add_pass(pass(
+                "main",
+                vec![resolve(Target::Texture(half))],
+                vec![shadow]
+            ))
+            .is_err());
+        graph
+            .add_pass(pass(
+                "main",
+                vec![resolve(Target::Texture(hdr))],
+                vec![shadow],
+            ))
+            .unwrap();
+        // 不能接着画没画过的
+        let load = ColorAttachment {
+            clear: None,
+            ..clear(Target::Texture(half))
+        };
+        assert!(graph
+            .add_pass(pass("bloom", vec![load], vec![hdr]))
+            .is_err());
+        graph
+            .add_pass(pass("bloom", vec![clear(Target::Texture(half))], vec![hdr]))
+            .unwrap();
+        graph
+            .add_pass(pass(
+                "tonemap",
+                vec![clear(Target::Output)],
+                vec![hdr, half],
+            ))
+            .unwrap();
+
+        assert_eq!(
+            graph.usage(shadow),
+            TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING
+        );
+        assert_eq!(graph.usage(msaa), TextureUsages::RENDER_ATTACHMENT);
+        // 阴影后面要采样, resolve 之前的多重采样贴图用不到了
+        assert!(graph.stores(0, Target::Layer(shadow, 0)));
+        assert!(!graph.stores(2, Target::Texture(msaa)));
+        assert!(graph.stores(3, Target::Texture(half)));
+        assert!(graph.stores(4, Target::Output));
+        assert_eq!(graph.extent(&graph.textures[half.0].desc).width, 32);
+        assert_eq!(graph.passes().count(), 5);
+    }
+}
@@ -0,0 +1,148 @@
+// 无窗口渲染: 画到离屏贴图上再读回成图片, 用于对比图片的回归测试和批量生成缩略图
+use image::RgbaImage;
+use log::info;
+use resource::pack::PackRegistry;
+use wgpu::*;
+
+use anyhow::*;
+
+use super::{
+    camera::Camera,
+    post::HDR_FORMAT,
+    supported_sample_counts,
+    texture::{read_to_image, TextureArgs},
+    RenderState,
+};
+use crate::scene::Scene;
+
+// 读回时不用换通道顺序
+pub const HEADLESS_FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+
+impl RenderState {
+    // 没有显卡时退回到 wgpu 的软件 adapter
+    // force_fallback_adapter 为 true 时总是用软件的, 回归测试用这个, 结果不受显卡影响
+    pub async fn init_headless(
+        width: u32,
+        height: u32,
+        camera: &Camera,
+        registry: &PackRegistry,
+        force_fallback_adapter: bool,
+    ) -> Result<RenderState> {
+        let instance = Instance::new(Backends::all());
+        let request = |force_fallback_adapter| {
+            instance.request_adapter(&RequestAdapterOptions {
+                power_preference: Default::default(),
+                force_fallback_adapter,
+                compatible_surface: None,
+            })
+        };
+        let adapter = match request(force_fallback_adapter).await {
+            Some(a) => a,
+            None if !force_fallback_adapter => request(true).await.ok_or(anyhow!(
+                "没有可用的 adapter, 软件渲染的也没有"
+            ))?,
+            None => bail!("没有软件渲染的 adapter"),
+        };
+        info!("无窗口渲染使用 {:?}", adapter.get_info());
+        let (device, queue) = Self::request_device(&adapter).await?;
+        let surface_config = SurfaceConfiguration {
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            format: HEADLESS_FORMAT,
+            width,
+            height,
+            present_mode: PresentMode::Fifo,
+            alpha_mode: CompositeAlphaMode::Auto,
+        };
+        // 回归测试的结果不受多重采样影响, 要开用 set_sample_count
+        let sample_counts =
+            supported_sample_counts(&[HDR_FORMAT, TextureArgs::depth_texture().format]);
+        Self::init_with(
+            device,
+            queue,
+            None,
+            surface_config,
+            (sample_counts, 1),
+            camera,
+            registry,
+        )
+    }
+
+    // 尺寸是 surface_config 的, 改尺寸用 resize, camera.aspect 要和尺寸一致
+    pub fn render_to_image(&mut self, camera: &Camera, scene: &mut Scene) -> Result<RgbaImage> {
+        let (width, height, format) = (
+            self.surface_config.width,
+            self.surface_config.height,
+            self.surface_config.format,
+        );
+        let texture = self.device.create_texture(&TextureDescriptor {
+            label: Some("Offscreen Target"),
+            size: Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        self.render_into(camera, scene, &view)?;
+        read_to_image(&self.device, &self.queue, &texture, width, height, format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Point3;
+
+    // 软件渲染在不同驱动上边缘的光栅化会有差别, 允许少量像素差得多一些
+    const GOLDEN: &str = "tests/golden/render_base_pack.png";
+    const CHANNEL_TOLERANCE: i32 = 16;
+    const MAX_DIFFERENT_PIXELS: f32 = 0.02;
+
+    // 需要软件渲染的 adapter, 没有显示服务器时 GL 后端可以用
+    // EGL_PLATFORM=surfaceless, 设置 UPDATE_GOLDEN=1 时重新生成对比图
+    #[test]
+    #[ignore = "需要软件渲染的 adapter, 用 cargo test -- --ignored 运行"]
+    fn render_base_pack() {
+        let registry = PackRegistry::load_dir("pack").unwrap();
+        let mut camera = Camera {
+            aspect: 1.0,
+            position: Point3::new(0.0, 0.0, 12.0),
+            ..Default::default()
+        };
+        camera.calculate();
+        let render = RenderState::init_headless(64, 64, &camera, &registry, true);
+        let mut render = pollster::block_on(render).expect("没有软件渲染的 adapter");
+        let mut scene = Scene::init(&mut render, &registry).unwrap();
+        let img = render.render_to_image(&camera, &mut scene).unwrap();
+        assert_eq!(img.dimensions(), (64, 64));
+        // 左上角没有方块, 是偏蓝的天空
+        let sky = img.get_pixel(0, 0).0;
+        assert!(sky[2] > sky[0] + 32, "左上角应该是天空, 实际是 {:?}", sky);
+
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            img.save(GOLDEN).unwrap();
+            return;
+        }
+        let golden = image::open(GOLDEN).unwrap().into_rgba8();
+        assert_eq!(golden.dimensions(), img.dimensions());
+        let different = img
+            .pixels()
+            .zip(golden.pixels())
+            .filter(|(a, b)| {
+                (0..3).any(|c| (a.0[c] as i32 - b.0[c] as i32).abs() > CHANNEL_TOLERANCE)
+            })
+            .count();
+        let ratio = different as f32 / (64 * 64) as f32;
+        assert!(
+            ratio <= MAX_DIFFERENT_PIXELS,
+            "{:.1}% 的像素和 {} 不一致",
+            ratio * 100.0,
+            GOLDEN
+        );
+    }
+}
@@ -0,0 +1,137 @@
+// 开发时的热重载: 监视包目录和图集目录, 改了 shader 就重建各个管线, 改了贴图就重新上传对应的层
+//
+// 编辑器保存一次可能产生好几个事件, 贴图目录里的各级 mipmap 也是一个个写的,
+// 所以等一段时间没有新的事件之后才一起处理.
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver},
+    time::{Duration, Instant},
+};
+
+use anyhow::*;
+use log::{error, info};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use resource::pack::PackRegistry;
+
+use super::RenderState;
+
+const DEBOUNCE: Duration = Duration::from_millis(200);
+const SHADER_EXTENSIONS: [&str; 2] = ["wgsl", "spv"];
+
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub shader: bool,
+    // 改动的其他文件, 监视的目录 canonicalize 过, 所以都是绝对路径
+    pub textures: BTreeSet<PathBuf>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        !self.shader && self.textures.is_empty()
+    }
+}
+
+pub struct HotReload {
+    // drop 之后就不再监视了
+    _watcher: RecommendedWatcher,
+    rx: Receiver<notify::Result<notify::Event>>,
+    pending: Changes,
+    last_event: Option<Instant>,
+}
+
+impl HotReload {
+    // 不存在的目录跳过, 例如从资源包加载时没有包目录
+    pub fn new(dirs: &[&Path]) -> Result<Self> {
+        let (tx, rx) = channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        for dir in dirs.iter().filter(|d| d.is_dir()) {
+            // 这样事件里的路径都是绝对路径
+            let dir = canonical(dir);
+            watcher
+                .watch(&dir, RecursiveMode::Recursive)
+                .with_context(|| format!("监视 {:?} 失败", dir))?;
+            info!("热重载: 监视 {:?}", dir);
+        }
+        Ok(Self {
+            _watcher: watcher,
+            rx,
+            pending: Default::default(),
+            last_event: None,
+        })
+    }
+
+    // 每帧调用, 有改动并且已经稳定下来时返回
+    pub fn poll(&mut self) -> Option<Changes> {
+        for event in self.rx.try_iter() {
+            let event = match event {
+                std::result::Result::Ok(e) => e,
+                Err(e) => {
+                    error!("热重载: 监视出错 {}", e);
+                    continue;
+                }
+            };
+            if matches!(event.kind, EventKind::Access(_)) {
+                continue;
+            }
+            for path in event.paths {
+                let is_shader = path
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| SHADER_EXTENSIONS.contains(&e));
+                if is_shader {
+                    self.pending.shader = true;
+                } else {
+                    self.pending.textures.insert(path);
+                }
+            }
+            self.last_event = Some(Instant::now());
+        }
+        if self.pending.is_empty() || self.last_event.is_some_and(|t| t.elapsed() < DEBOUNCE) {
+            return None;
+        }
+        Some(std::mem::take(&mut self.pending))
+    }
+}
+
+impl RenderState {
+    // 出错时只记日志, 旧的管线和贴图继续用
+    pub fn hot_reload(&mut self, registry: &PackRegistry, changes: &Changes) {
+        if changes.shader {
+            match self.cube_pipeline.reload_shader(&self.device, registry) {
+                std::result::Result::Ok(()) => info!("热重载: cube 管线已重建"),
+                Err(e) => error!("热重载: shader 有错误, 继续用旧的管线\n{:?}", e),
+            }
+            match self.sky_pipeline.reload_shader(&self.device, registry) {
+                std::result::Result::Ok(()) => info!("热重载: 天空管线已重建"),
+                Err(e) => error!("热重载: 天空 shader 有错误, 继续用旧的管线\n{:?}", e),
+            }
+            match self.post.reload_shader(&self.device, registry) {
+                std::result::Result::Ok(()) => info!("热重载: 后处理管线已重建"),
+                Err(e) => error!("热重载: 后处理 shader 有错误, 继续用旧的管线\n{:?}", e),
+            }
+        }
+        if !changes.textures.is_empty() {
+            let result = self.cube_pipeline.reload_textures(
+                &self.device,
+                &self.queue,
+                registry,
+                |layer| {
+                    let layer = canonical(layer);
+                    changes.textures.iter().any(|p| p.starts_with(&layer))
+                },
+            );
+            match result {
+                std::result::Result::Ok(0) => {}
+                std::result::Result::Ok(n) => info!("热重载: 重新上传了 {} 层贴图", n),
+                Err(e) => error!("热重载: 贴图加载失败, 继续用旧的\n{:?}", e),
+            }
+        }
+    }
+}
+
+// 和 notify 给的路径比较前要先 canonicalize
+pub fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
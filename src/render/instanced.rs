@@ -0,0 +1,372 @@
+// 实例化管线: 所有 instance 共用一份顶点和 index, 每个 instance 再带一组自己的属性
+//
+// 顶点和 instance 的类型实现 VertexLayout 就行, buffer 布局, shader 输入的检查,
+// instance buffer 的创建和增长, 画的调用都在这里. 方块, 四边形, 线段, 点和模型的管线只给出 shader 和管线状态.
+// mesh 实现 InstancedMesh, 每个 mesh 一个 MeshBuffer, 只在变了时上传.
+use std::{fmt, marker::PhantomData, mem::size_of, ops::Range};
+
+use anyhow::{bail, Result};
+use resource::pack::PackRegistry;
+use wgpu::*;
+
+use super::pipeline::*;
+
+// 顶点 buffer 里的一项, attributes 的 offset 是字段在 Self 里的偏移
+pub trait VertexLayout: bytemuck::Pod {
+    fn attributes() -> Vec<VertexAttribute>;
+}
+
+fn buffer_layout<T: VertexLayout>(
+    attributes: &[VertexAttribute],
+    step_mode: VertexStepMode,
+) -> VertexBufferLayout<'_> {
+    VertexBufferLayout {
+        array_stride: size_of::<T>() as BufferAddress,
+        step_mode,
+        attributes,
+    }
+}
+
+// shader 在创建管线前按这个检查 vertex 输入, 顶点的在前
+pub fn vertex_attrs<V: VertexLayout, I: VertexLayout>() -> Vec<VertexAttribute> {
+    [V::attributes(), I::attributes()].concat()
+}
+
+// index buffer 里的一项
+pub trait IndexType: bytemuck::Pod {
+    const FORMAT: IndexFormat;
+}
+
+impl IndexType for u16 {
+    const FORMAT: IndexFormat = IndexFormat::Uint16;
+}
+
+impl IndexType for u32 {
+    const FORMAT: IndexFormat = IndexFormat::Uint32;
+}
+
+// 共用的顶点和 index, 一个管线的几个变体可以共用一份, 顶点多的模型用 u32 的 index
+#[derive(Debug)]
+pub struct Geometry<V, Ix = u16> {
+    pub vertex: Buffer,
+    pub index: Buffer,
+    pub index_count: u32,
+    _types: PhantomData<(V, Ix)>,
+}
+
+impl<V: VertexLayout, Ix: IndexType> Geometry<V, Ix> {
+    pub fn new(device: &Device, label: &str, vertices: &[V], indices: &[Ix]) -> Self {
+        let vertex_label = format!("{} Vertex", label);
+        let index_label = format!("{} Index", label);
+        Self {
+            vertex: create_buffer(device, Some(&vertex_label), BufferUsages::VERTEX, vertices),
+            index: create_buffer(device, Some(&index_label), BufferUsages::INDEX, indices),
+            index_count: indices.len() as u32,
+            _types: PhantomData,
+        }
+    }
+}
+
+// InstanceBuffer 的容量和个数, 不碰 buffer
+#[derive(Debug, Clone, Copy)]
+struct Capacity {
+    capacity: usize,
+    // 上次 write 的个数
+    len: usize,
+}
+
+impl Capacity {
+    // 记下新的个数, 放不下时返回要新建的 buffer 的容量, 是 2 的幂
+    fn resize(&mut self, len: usize) -> Option<usize> {
+        self.len = len;
+        if len <= self.capacity {
+            return None;
+        }
+        self.capacity = len.next_power_of_two();
+        Some(self.capacity)
+    }
+}
+
+// 一般每个 mesh 一个, 放不下时换成 2 的幂大小的新 buffer
+#[derive(Debug)]
+pub struct InstanceBuffer<I> {
+    buffer: Buffer,
+    label: String,
+    size: Capacity,
+    _instance: PhantomData<I>,
+}
+
+impl<I: VertexLayout> InstanceBuffer<I> {
+    pub fn new(device: &Device, label: &str, capacity: usize) -> Self {
+        Self {
+            buffer: Self::create(device, label, capacity),
+            label: label.to_string(),
+            size: Capacity { capacity, len: 0 },
+            _instance: PhantomData,
+        }
+    }
+
+    fn create(device: &Device, label: &str, capacity: usize) -> Buffer {
+        device.create_buffer(&BufferDescriptor {
+            label: Some(label),
+            size: (size_of::<I>() * capacity.max(1)) as u64,
+            usage: BufferUsages::COPY_DST | BufferUsages::VERTEX,
+            mapped_at_creation: false,
+        })
+    }
+
+    // 整个替换掉原来的内容, 要在开始 pass 之前调用
+    pub fn write(&mut self, device: &Device, queue: &Queue, data: &[I]) {
+        if let Some(capacity) = self.size.resize(data.len()) {
+            self.buffer = Self::create(device, &self.label, capacity);
+        }
+        if !data.is_empty() {
+            queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(data));
+        }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.size.len as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.size.capacity
+    }
+}
+
+// 设计文档里的 Mesh: 顶点和 index 由管线的 Geometry 共用, 每个 mesh 只有自己的 instance
+pub trait InstancedMesh {
+    type Vertex: VertexLayout;
+    type Instance: VertexLayout;
+
+    // 变了才返回要上传的全部 instance, 返回后当作已经上传了
+    fn take_changed(&mut self) -> Option<&[Self::Instance]>;
+}
+
+// 一个 mesh 在显卡上的 instance
+pub struct MeshBuffer<M: InstancedMesh> {
+    pub instance: InstanceBuffer<M::Instance>,
+}
+
+// mesh 本身不需要实现 Debug
+impl<M: InstancedMesh> fmt::Debug for MeshBuffer<M>
+where
+    M::Instance: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MeshBuffer")
+            .field("instance", &self.instance)
+            .finish()
+    }
+}
+
+impl<M: InstancedMesh> MeshBuffer<M> {
+    pub fn new(device: &Device, label: &str, capacity: usize) -> Self {
+        Self {
+            instance: InstanceBuffer::new(device, label, capacity),
+        }
+    }
+
+    // 要在开始 pass 之前调用, mesh 没变时什么都不做
+    pub fn write(&mut self, device: &Device, queue: &Queue, mesh: &mut M) {
+        if let Some(data) = mesh.take_changed() {
+            self.instance.write(device, queue, data);
+        }
+    }
+}
+
+// 一个管线里除了顶点布局以外的部分
+#[derive(Debug, Clone)]
+pub struct InstancedDesc {
+    pub label: String,
+    // PackRegistry 里 shader 的 id, vs 和 fs 在同一个文件里
+    pub shader: &'static str,
+    // shader 的变体, 见 load_shader
+    pub define: Option<&'static str>,
+    pub primitive: PrimitiveState,
+    // 为空时只画深度
+    pub color: Option<ColorTargetState>,
+    pub depth: Option<DepthStencilState>,
+    pub multisample: MultisampleState,
+}
+
+#[derive(Debug)]
+pub struct InstancedPipeline<V, I> {
+    pub pipeline: RenderPipeline,
+    pub layout: PipelineLayout,
+    pub desc: InstancedDesc,
+    _types: PhantomData<(V, I)>,
+}
+
+impl<V: VertexLayout, I: VertexLayout> InstancedPipeline<V, I> {
+    pub fn new<'a>(
+        device: &'a Device,
+        registry: &PackRegistry,
+        group_layouts: impl IntoIterator<Item = &'a BindGroupLayout>,
+        desc: InstancedDesc,
+    ) -> Result<Self> {
+        let group_layouts: Vec<&BindGroupLayout> = group_layouts.into_iter().collect();
+        let label = format!("{} Layout", desc.label);
+        let layout = create_pipeline_layout(device, Some(&label), group_layouts)?;
+        let pipeline = Self::create(device, registry, &layout, &desc)?;
+        Ok(Self {
+            pipeline,
+            layout,
+            desc,
+            _types: PhantomData,
+        })
+    }
+
+    fn create(
+        device: &Device,
+        registry: &PackRegistry,
+        layout: &PipelineLayout,
+        desc: &InstancedDesc,
+    ) -> Result<RenderPipeline> {
+        let (vs, fs) = load_shader(registry, desc.shader, desc.define, &vertex_attrs::<V, I>())?;
+        let vs_module = create_shader_module(device, Some(&format!("{} VS", desc.label)), &vs)?;
+        let fs_module = create_shader_module(device, Some(&format!("{} FS", desc.label)), &fs)?;
+        let (v, i) = (V::attributes(), I::attributes());
+        let buffers = [
+            buffer_layout::<V>(&v, VertexStepMode::Vertex),
+            buffer_layout::<I>(&i, VertexStepMode::Instance),
+        ];
+        let targets: Vec<_> = desc.color.iter().cloned().map(Some).collect();
+        if desc.primitive.polygon_mode == PolygonMode::Line
+            && !device.features().contains(Features::POLYGON_MODE_LINE)
+        {
+            bail!("设备不支持 POLYGON_MODE_LINE, 不能创建 {}", desc.label);
+        }
+        // 管线的验证错误默认会 panic, 这里改成返回错误, 热重载时旧管线还能继续用
+        device.push_error_scope(ErrorFilter::Validation);
+        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some(&desc.label),
+            layout: Some(layout),
+            vertex: VertexState {
+                module: &vs_module,
+                entry_point: vs.enter_point(),
+                buffers: &buffers,
+            },
+            fragment: Some(FragmentState {
+                module: &fs_module,
+                entry_point: fs.enter_point(),
+                targets: &targets,
+            }),
+            primitive: desc.primitive,
+            depth_stencil: desc.depth.clone(),
+            multisample: desc.multisample,
+            multiview: None,
+        });
+        if let Some(e) = pollster::block_on(device.pop_error_scope()) {
+            bail!("创建 {} 失败:\n{}", desc.label, e);
+        }
+        Ok(pipeline)
+    }
+
+    // 按 desc 建一个新管线但不替换, 几个管线要一起换时先都建好, 见 apply
+    pub fn build(
+        &self,
+        device: &Device,
+        registry: &PackRegistry,
+        desc: InstancedDesc,
+    ) -> Result<BuiltPipeline> {
+        let pipeline = Self::create(device, registry, &self.layout, &desc)?;
+        Ok(BuiltPipeline { pipeline, desc })
+    }
+
+    pub fn apply(&mut self, built: BuiltPipeline) {
+        self.pipeline = built.pipeline;
+        self.desc = built.desc;
+    }
+
+    // 重新读取 shader, 出错时保持原样
+    pub fn reload_shader(&mut self, device: &Device, registry: &PackRegistry) -> Result<()> {
+        let built = self.build(device, registry, self.desc.clone())?;
+        self.apply(built);
+        Ok(())
+    }
+
+    // 换管线状态, 例如渲染目标的格式或多重采样次数, 出错时保持原样
+    pub fn set_desc(
+        &mut self,
+        device: &Device,
+        registry: &PackRegistry,
+        desc: InstancedDesc,
+    ) -> Result<()> {
+        let built = self.build(device, registry, desc)?;
+        self.apply(built);
+        Ok(())
+    }
+
+    // 绑定组要已经设置好, indices 是 geometry 里的 index 范围
+    pub fn draw<'a, Ix: IndexType>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        geometry: &'a Geometry<V, Ix>,
+        instance: &'a InstanceBuffer<I>,
+        indices: Range<u32>,
+        instances: Range<u32>,
+    ) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_vertex_buffer(0, geometry.vertex.slice(..));
+        render_pass.set_vertex_buffer(1, instance.buffer.slice(..));
+        render_pass.set_index_buffer(geometry.index.slice(..), Ix::FORMAT);
+        render_pass.draw_indexed(indices, 0, instances);
+    }
+
+    // 画整个 mesh, 用 geometry 的全部 index
+    pub fn draw_mesh<'a, M, Ix: IndexType>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        geometry: &'a Geometry<V, Ix>,
+        mesh: &'a MeshBuffer<M>,
+    ) where
+        M: InstancedMesh<Vertex = V, Instance = I>,
+    {
+        let (indices, instances) = (0..geometry.index_count, 0..mesh.instance.len());
+        self.draw(render_pass, geometry, &mesh.instance, indices, instances);
+    }
+}
+
+// InstancedPipeline::build 建好的, 还没换上去
+#[derive(Debug)]
+pub struct BuiltPipeline {
+    pipeline: RenderPipeline,
+    desc: InstancedDesc,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instance_capacity() {
+        let mut size = Capacity {
+            capacity: 4,
+            len: 0,
+        };
+        // 放得下时不用新 buffer, 只记下个数
+        assert_eq!(size.resize(3), None);
+        assert_eq!(size.resize(4), None);
+        assert_eq!(size.len, 4);
+        // 放不下时按 2 的幂变大
+        assert_eq!(size.resize(5), Some(8));
+        assert_eq!(size.resize(9), Some(16));
+        assert_eq!((size.capacity, size.len), (16, 9));
+        // 变少不会缩小, 空的也一样
+        assert_eq!(size.resize(0), None);
+        assert_eq!((size.capacity, size.len), (16, 0));
+
+        let mut empty = Capacity {
+            capacity: 0,
+            len: 0,
+        };
+        assert_eq!(empty.resize(0), None);
+        assert_eq!(empty.resize(1), Some(1));
+        assert_eq!(empty.resize(1000), Some(1024));
+    }
+}
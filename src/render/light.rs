@@ -0,0 +1,107 @@
+use std::f32::consts::TAU;
+
+use nalgebra::Vector3;
+use wgpu::{
+    util::{BufferInitDescriptor, DeviceExt},
+    *,
+};
+
+use super::*;
+
+// 方向光 (太阳) 和环境光, 颜色是线性空间的
+// RenderState 每帧都按 Environment::apply 覆盖, 要转动太阳就改 environment 的时刻
+#[derive(Debug, Clone)]
+pub struct Light {
+    // 绕 y 轴的角度, 0 时太阳在 +x 方向
+    pub azimuth: f32,
+    // 离地平线的高度角, 负的表示在地平线以下
+    pub elevation: f32,
+    pub color: Vector3<f32>,
+    pub ambient: Vector3<f32>,
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self {
+            azimuth: 0.6,
+            elevation: 0.9,
+            color: Vector3::new(1.0, 0.95, 0.85),
+            ambient: Vector3::new(0.3, 0.32, 0.38),
+        }
+    }
+}
+
+// 与 shader 里的 Light 对应, vec3 按 16 字节对齐
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightUniform {
+    pub direction: [f32; 3],
+    _pad0: f32,
+    pub color: [f32; 3],
+    _pad1: f32,
+    pub ambient: [f32; 3],
+    _pad2: f32,
+}
+
+impl Light {
+    // 从地面指向太阳, 单位向量
+    pub fn direction(&self) -> Vector3<f32> {
+        let (s, c) = self.elevation.sin_cos();
+        Vector3::new(c * self.azimuth.cos(), s, c * self.azimuth.sin())
+    }
+
+    // direction() 的反过来, 不需要是单位向量
+    pub fn set_direction(&mut self, direction: &Vector3<f32>) {
+        let d = direction.normalize();
+        self.azimuth = d.z.atan2(d.x).rem_euclid(TAU);
+        self.elevation = d.y.clamp(-1.0, 1.0).asin();
+    }
+
+    pub fn to_uniform(&self) -> LightUniform {
+        LightUniform {
+            direction: self.direction().into(),
+            _pad0: 0.0,
+            color: self.color.into(),
+            _pad1: 0.0,
+            ambient: self.ambient.into(),
+            _pad2: 0.0,
+        }
+    }
+
+    pub fn create_binding(&self, device: &Device) -> LightBind {
+        let buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Light"),
+            contents: bytemuck::cast_slice(&[self.to_uniform()]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+        LightBind { buffer }
+    }
+
+    pub fn get_layout_args() -> [BindGroupLayoutEntryArgs; 1] {
+        let light_desc = BindGroupLayoutEntryArgs {
+            visibility: ShaderStages::FRAGMENT,
+            count: None,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+        };
+        [light_desc]
+    }
+}
+
+#[derive(Debug)]
+pub struct LightBind {
+    pub buffer: Buffer,
+}
+
+impl LightBind {
+    pub fn write(&self, queue: &Queue, light: &Light) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[light.to_uniform()]));
+    }
+
+    pub fn get_bind_resource(&self) -> [BindingResource<'_>; 1] {
+        [self.buffer.as_entire_binding()]
+    }
+}
@@ -17,35 +17,74 @@ use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
     *,
 };
+use resource::pack::PackRegistry;
 use winit::window::Window;
 
 use crate::{scene::Scene, utils::builder_set_fn};
 
 pub mod built_in;
 pub mod camera;
+pub mod capture;
+pub mod environment;
+pub mod graph;
+pub mod headless;
+#[cfg(debug_assertions)]
+pub mod hot_reload;
+pub mod instanced;
+pub mod light;
 pub mod pipeline;
+pub mod post;
+pub mod shadow;
 pub mod texture;
 use built_in::*;
 use camera::*;
+use environment::*;
+use graph::*;
+use instanced::*;
+use light::*;
 use pipeline::*;
+use post::*;
+use shadow::*;
 use texture::*;
 
 #[derive(Debug)]
 pub struct RenderState {
     pub device: Device,
     pub queue: Queue,
-    pub surface: Surface,
+    // 无窗口渲染时为空
+    pub surface: Option<Surface>,
     pub surface_config: SurfaceConfiguration,
 
+    // 下标和 shader 里的 @group 一致: 0 相机, 1 光照, 阴影和环境
     pub bind_groups: Vec<BindGroup>,
+    bind_group_layouts: Vec<BindGroupLayout>,
     pub camera_bind: CameraBind,
-    pub depth_texture_bind: TextureBind,
+    // 每帧上传, 太阳的方向和颜色由 environment 的时刻决定, 环境光也是
+    pub light: Light,
+    pub light_bind: LightBind,
+    pub shadow: Shadow,
+    // 时刻, 天空和雾, 直接改就行, 随世界存档保存
+    pub environment: Environment,
+    pub environment_bind: EnvironmentBind,
+    // 阴影, 主 pass 和后处理的各个 pass, 以及它们之间的贴图
+    graph: RenderGraph<Pass>,
+    graph_textures: GraphTextures,
+    // 主 pass 画到 HDR 贴图上, 再由它写到 surface
+    pub post: Post,
+    target: RenderTargetFormat,
+    // adapter 支持的多重采样次数, 从小到大
+    sample_counts: Vec<u32>,
 
     pub cube_pipeline: cube::Pipeline,
+    pub sky_pipeline: sky::Pipeline,
 }
 
 impl RenderState {
-    pub async fn init(window: &Window, camera: &Camera) -> Result<RenderState> {
+    pub async fn init(
+        window: &Window,
+        camera: &Camera,
+        registry: &PackRegistry,
+    ) -> Result<RenderState> {
         // 创建 surface(交换链)
         let instance = Instance::new(Backends::all());
         let surface = unsafe { instance.create_surface(window) };
@@ -60,22 +99,9 @@ impl RenderState {
             instance
                 .request_adapter(&opt)
                 .await
-                .expect("request adapter 失败")
-        };
-
-        let (device, queue) = {
-            let mut limits = Limits::default();
-            limits.min_uniform_buffer_offset_alignment = 64;
-            let desc = DeviceDescriptor {
-                label: None,
-                features: Features::BUFFER_BINDING_ARRAY,
-                limits: limits,
-            };
-            adapter
-                .request_device(&desc, None)
-                .await
-                .expect("request device 失败")
+                .ok_or(anyhow!("request adapter 失败"))?
         };
+        let (device, queue) = Self::request_device(&adapter).await?;
 
         // 后面是渲染相关了
 
@@ -88,6 +114,62 @@ impl RenderState {
             con
         };
 
+        let depth_format = TextureArgs::depth_texture().format;
+        let sample_counts = supported_sample_counts(&[HDR_FORMAT, depth_format]);
+        let sample_count = match sample_counts.contains(&DEFAULT_SAMPLE_COUNT) {
+            true => DEFAULT_SAMPLE_COUNT,
+            false => 1,
+        };
+        Self::init_with(
+            device,
+            queue,
+            Some(surface),
+            surface_config,
+            (sample_counts, sample_count),
+            camera,
+            registry,
+        )
+    }
+
+    pub(crate) async fn request_device(adapter: &Adapter) -> Result<(Device, Queue)> {
+        let mut limits = Limits::default();
+        // 软件渲染的 adapter 可能达不到 64
+        limits.min_uniform_buffer_offset_alignment =
+            64.max(adapter.limits().min_uniform_buffer_offset_alignment);
+        let desc = DeviceDescriptor {
+            label: None,
+            // 这些都是可选的, 用到时才检查:
+            // BC 压缩不支持时贴图在加载时解压, SPIR-V passthrough 和线框不支持时报错
+            // BUFFER_BINDING_ARRAY 目前没有用到, 软件渲染的 adapter 一般不支持
+            features: adapter.features()
+                & (Features::BUFFER_BINDING_ARRAY
+                    | Features::TEXTURE_COMPRESSION_BC
+                    | Features::SPIRV_SHADER_PASSTHROUGH
+                    | Features::POLYGON_MODE_LINE),
+            limits: limits,
+        };
+        adapter
+            .request_device(&desc, None)
+            .await
+            .context("request device 失败")
+    }
+
+    // 窗口和无窗口共用, surface 为空时只能用 render_to_image
+    // msaa 是 (supported_sample_counts 的结果, 要用的次数)
+    pub(crate) fn init_with(
+        device: Device,
+        queue: Queue,
+        surface: Option<Surface>,
+        surface_config: SurfaceConfiguration,
+        msaa: (Vec<u32>, u32),
+        camera: &Camera,
+        registry: &PackRegistry,
+    ) -> Result<RenderState> {
+        let (sample_counts, sample_count) = msaa;
+        if !sample_counts.contains(&sample_count) {
+            bail!("不支持 {} 倍多重采样, 可以用 {:?}", sample_count, sample_counts);
+        }
+
         // 存所有的 bind group, bind group layout
         let mut bind_group_layouts = Vec::new();
         let mut bind_groups = Vec::new();
@@ -106,26 +188,43 @@ impl RenderState {
         bind_group_layouts.push(lay);
         bind_groups.push(bg);
 
-        // 深度图
-        let mut desc = TextureArgs::depth_texture();
-        desc.width = surface_config.width;
-        desc.height = surface_config.height;
+        // 光照 bind, 阴影贴图和环境也在这一组
+        let environment = Environment::default();
+        let environment_bind = environment.create_binding(&device, camera);
+        let mut light = Light::default();
+        environment.apply(&mut light);
+        let light_bind = light.create_binding(&device);
+        let shadow = Shadow::new(&device, ShadowConfig::default())?;
+        let lay = {
+            let (l, s) = (Light::get_layout_args(), Shadow::get_layout_args());
+            let e = Environment::get_layout_args();
+            create_bind_group_layout(
+                &device,
+                Some("Light Bind Group Layout"),
+                l.iter().chain(s.iter()).chain(e.iter()),
+            )?
+        };
+        // 阴影贴图在渲染图里, 建好图以后由 bind_graph 创建这一组
+        bind_group_layouts.push(lay);
 
-        let depth_format = desc.format;
-        let depth_texture_bind = {
-            let texture = device.create_texture(&desc.into_desc(Some("Depth Texture")));
-            let view = texture.create_view(&TextureViewDescriptor::default());
-            TextureBind { texture, view }
+        // 主 pass 的颜色是 HDR 的, 后处理再转到 surface 的格式
+        let target = RenderTargetFormat {
+            color: HDR_FORMAT,
+            depth: TextureArgs::depth_texture().format,
+            sample_count,
         };
+        let (graph, graph_textures) = Self::build_graph(&device, &surface_config, target, &shadow)?;
 
         // cube 管线
-        let cube_pipeline = cube::PipelinePreparer::init()?.create_pipeline(
+        let cube_pipeline = cube::PipelinePreparer::init(registry).create_pipeline(
             &device,
             &queue,
             &bind_group_layouts,
-            surface_config.format,
-            depth_format,
+            &shadow.cascade_layout,
+            target,
         )?;
+        let sky_pipeline = sky::Pipeline::new(&device, registry, &bind_group_layouts, target)?;
+        let post = Post::new(&device, registry, surface_config.format)?;
 
         let mut ret = Self {
             device,
@@ -133,61 +232,250 @@ impl RenderState {
             surface,
             surface_config,
             cube_pipeline,
+            sky_pipeline,
             camera_bind,
+            light,
+            light_bind,
+            shadow,
+            environment,
+            environment_bind,
             bind_groups,
-            depth_texture_bind,
+            bind_group_layouts,
+            graph,
+            graph_textures,
+            post,
+            target,
+            sample_counts,
         };
+        ret.bind_graph()?;
         Ok(ret)
     }
 
+    // 按执行顺序: 阴影的各级, 主 pass, 后处理的几步
+    // 改了多重采样次数或阴影的设置要重建, 之后调用 bind_graph
+    fn build_graph(
+        device: &Device,
+        surface_config: &SurfaceConfiguration,
+        target: RenderTargetFormat,
+        shadow: &Shadow,
+    ) -> Result<(RenderGraph<Pass>, GraphTextures)> {
+        let mut graph = RenderGraph::new(surface_config.width, surface_config.height);
+        let shadow_map = shadow.declare(&mut graph);
+        let post = Post::declare(&mut graph);
+        let surface = |label, format| TextureDesc {
+            label,
+            format,
+            size: TextureSize::Surface { divisor: 1 },
+            layers: None,
+            sample_count: target.sample_count,
+        };
+        let depth = graph.declare(surface("Depth Texture", target.depth));
+        // 多重采样时先画到这上面, 再 resolve 到 post.hdr, 不开时直接画到 post.hdr 上
+        let color = match target.sample_count {
+            1 => ColorAttachment {
+                target: Target::Texture(post.hdr),
+                resolve: None,
+                clear: Some(Color::BLACK),
+            },
+            _ => ColorAttachment {
+                target: Target::Texture(graph.declare(surface("MSAA Color Texture", target.color))),
+                resolve: Some(Target::Texture(post.hdr)),
+                clear: Some(Color::BLACK),
+            },
+        };
+
+        shadow.add_passes(&mut graph, shadow_map, Pass::Shadow)?;
+        graph.add_pass(PassDesc {
+            label: "Main Pass",
+            kind: Pass::Main,
+            color: vec![color],
+            depth: Some(DepthAttachment {
+                target: Target::Texture(depth),
+                clear: Some(1.0),
+            }),
+            reads: vec![shadow_map],
+        })?;
+        Post::add_passes(&mut graph, post, Pass::Post)?;
+        graph.create(device)?;
+        Ok((graph, GraphTextures { shadow_map, post }))
+    }
+
+    // 重建用到渲染图里贴图的绑定组
+    fn bind_graph(&mut self) -> Result<()> {
+        let light_group = Self::create_light_group(
+            &self.device,
+            &self.bind_group_layouts[LIGHT_GROUP],
+            &self.light_bind,
+            &self.shadow,
+            self.graph.view(self.graph_textures.shadow_map)?,
+            &self.environment_bind,
+        )?;
+        match self.bind_groups.get_mut(LIGHT_GROUP) {
+            Some(g) => *g = light_group,
+            None => self.bind_groups.push(light_group),
+        }
+        self.post
+            .bind(&self.device, &self.graph, self.graph_textures.post)?;
+        Ok(())
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.target.sample_count
+    }
+
+    pub fn supported_sample_counts(&self) -> &[u32] {
+        &self.sample_counts
+    }
+
+    // 运行时改多重采样次数, 要重建管线, 出错时保持原来的设置.
+    // 只能是 supported_sample_counts 里的, 受 wgpu 0.14 限制最多是 SAMPLE_COUNTS 的 1 和 4
+    pub fn set_sample_count(&mut self, registry: &PackRegistry, sample_count: u32) -> Result<()> {
+        if !self.sample_counts.contains(&sample_count) {
+            bail!("不支持 {} 倍多重采样, 可以用 {:?}", sample_count, self.sample_counts);
+        }
+        let target = RenderTargetFormat {
+            sample_count,
+            ..self.target
+        };
+        // 先建图, 失败时管线和图都还是原来的
+        let graph = Self::build_graph(&self.device, &self.surface_config, target, &self.shadow)?;
+        self.cube_pipeline.set_target(&self.device, registry, target)?;
+        if let Err(e) = self.sky_pipeline.set_target(&self.device, registry, target) {
+            self.cube_pipeline
+                .set_target(&self.device, registry, self.target)?;
+            return Err(e);
+        }
+        self.target = target;
+        (self.graph, self.graph_textures) = graph;
+        self.bind_graph()
+    }
+
+    fn create_light_group(
+        device: &Device,
+        layout: &BindGroupLayout,
+        light_bind: &LightBind,
+        shadow: &Shadow,
+        shadow_map: &TextureView,
+        environment_bind: &EnvironmentBind,
+    ) -> Result<BindGroup> {
+        let res: Vec<_> = light_bind
+            .get_bind_resource()
+            .into_iter()
+            .chain(shadow.get_bind_resource(shadow_map))
+            .chain(environment_bind.get_bind_resource())
+            .collect();
+        create_bind_group(device, Some("Light Bind Group"), layout, &res)
+    }
+
+    // 运行时改阴影的分辨率和级数, 出错时保持原来的设置
+    pub fn set_shadow_config(&mut self, config: ShadowConfig) -> Result<()> {
+        let old = self.shadow.config;
+        self.shadow.set_config(&self.device, config)?;
+        match Self::build_graph(&self.device, &self.surface_config, self.target, &self.shadow) {
+            std::result::Result::Ok(g) => (self.graph, self.graph_textures) = g,
+            Err(e) => {
+                self.shadow.config = old;
+                return Err(e);
+            }
+        }
+        self.bind_graph()
+    }
+
     pub fn redraw(&mut self, camera: &Camera, scene: &mut Scene) -> anyhow::Result<()> {
+        let texture = self
+            .surface
+            .as_ref()
+            .ok_or(anyhow!("没有 surface, 无窗口时用 render_to_image"))?
+            .get_current_texture()?;
+        let main_surface_view = texture.texture.create_view(&Default::default());
+        self.render_into(camera, scene, &main_surface_view)?;
+        texture.present();
+        Ok(())
+    }
+
+    // 画到 target_view 上, 格式和尺寸要和 surface_config 一致
+    pub fn render_into(
+        &mut self,
+        camera: &Camera,
+        scene: &mut Scene,
+        target_view: &TextureView,
+    ) -> Result<()> {
+        self.environment.apply(&mut self.light);
         self.camera_bind.write(&mut self.queue, camera);
+        self.light_bind.write(&self.queue, &self.light);
+        self.environment_bind
+            .write(&self.queue, &self.environment, camera);
 
-        let texture = self.surface.get_current_texture()?;
         let mut encoder = {
             let desc = CommandEncoderDescriptor {
                 label: Some("主要 Command Encoder"),
             };
             self.device.create_command_encoder(&desc)
         };
-        let main_surface_view = texture.texture.create_view(&Default::default());
 
-        {
-            let mut rp = self.cube_pipeline.start_pass(
-                &mut encoder,
-                &main_surface_view,
-                &self.depth_texture_bind.view,
-                &self.bind_groups,
-            );
-            self.cube_pipeline
-                .draw(&self.queue, &mut rp, &mut scene.cubes)?;
+        self.shadow
+            .update(&self.queue, camera, &self.light.direction());
+        self.post.write(&self.queue);
+        self.cube_pipeline
+            .prepare(&self.device, &self.queue, &mut scene.cubes, &camera.position)?;
+
+        for (pass, kind) in self.graph.passes() {
+            if matches!(kind, Pass::Post(stage) if stage.is_bloom() && !self.post.config.bloom) {
+                continue;
+            }
+            let mut rp = self.graph.begin(&mut encoder, pass, target_view)?;
+            match *kind {
+                // 从太阳方向画各级的深度, 主 pass 里采样
+                Pass::Shadow(cascade) => {
+                    self.cube_pipeline
+                        .draw_shadow(&mut rp, &self.shadow, cascade, &scene.cubes)?
+                }
+                Pass::Main => {
+                    for (i, g) in self.bind_groups.iter().enumerate() {
+                        rp.set_bind_group(i as u32, g, &[]);
+                    }
+                    self.cube_pipeline.draw(&mut rp, &scene.cubes)?;
+                    // 天空只填没被不透明方块挡住的地方, 半透明的面要透出天空, 所以在它们之前
+                    self.sky_pipeline.draw(&mut rp);
+                    self.cube_pipeline.draw_translucent(&mut rp, &scene.cubes)?;
+                }
+                Pass::Post(stage) => self.post.draw(&mut rp, stage),
+            }
         }
 
         let command_buffer = encoder.finish();
         self.queue.submit(once(command_buffer));
-        texture.present();
         Ok(())
     }
 
     pub fn resize(&mut self, camera: &mut Camera, width: u32, height: u32) -> Result<()> {
         self.surface_config.width = width;
         self.surface_config.height = height;
-        self.surface.configure(&self.device, &self.surface_config);
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.surface_config);
+        }
         camera.aspect = width as f32 / height as f32;
-        let mut desc = TextureArgs::depth_texture();
-        desc.width = self.surface_config.width;
-        desc.height = self.surface_config.height;
-        let tx = self
-            .device
-            .create_texture(&desc.into_desc(Some("Depth Texture (resized)")));
-        self.depth_texture_bind = TextureBind {
-            view: tx.create_view(&TextureViewDescriptor::default()),
-            texture: tx,
-        };
-        Ok(())
+        self.graph.resize(&self.device, width, height)?;
+        self.bind_graph()
     }
 }
 
+// 渲染图里的 pass, 录制时按它交给对应的管线
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pass {
+    Shadow(usize),
+    // 不透明的方块, 天空, 半透明的方块
+    Main,
+    Post(Stage),
+}
+
+// 绑定组里用到的渲染图贴图
+#[derive(Debug, Clone, Copy)]
+struct GraphTextures {
+    shadow_map: TextureId,
+    post: PostTextures,
+}
+
 pub fn ttt<'a, 'b>(p: &'b cube::Pipeline, rp: &'b mut RenderPass<'a>) {}
 
 impl RenderState {
@@ -210,4 +498,32 @@ impl RenderState {
 }
 
 
+// 能用的多重采样次数, formats 是要多重采样的颜色和深度格式
+// wgpu 0.14 只能查格式支不支持多重采样, 查不到具体几倍, 创建贴图和管线时也只检查是不是 2 的幂,
+// 试着创建也探测不出来. 所以只用 WebGPU 保证的 4 倍, 2 倍和 8 倍等 wgpu 能查的时候再加.
+// 没有请求 TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES, device 按格式保证的特性检查, 这里也一样
+pub(crate) fn supported_sample_counts(formats: &[TextureFormat]) -> Vec<u32> {
+    let msaa = formats.iter().all(|f| {
+        let info = f.describe();
+        let flags = info.guaranteed_format_features.flags;
+        // 深度不用 resolve
+        let resolve = info.sample_type == TextureSampleType::Depth
+            || flags.contains(TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE);
+        flags.contains(TextureFormatFeatureFlags::MULTISAMPLE) && resolve
+    });
+    SAMPLE_COUNTS
+        .into_iter()
+        .filter(|c| *c == 1 || msaa)
+        .collect()
+}
+
+// 支持的多重采样次数. wgpu 0.14 查不到 adapter 支持几倍, 只有 WebGPU 保证的 1 倍和 4 倍,
+// 2 倍和 8 倍要等升级 wgpu 后再加
+pub const SAMPLE_COUNTS: [u32; 2] = [1, 4];
+// 有窗口时默认用的, 不支持时不开
+pub const DEFAULT_SAMPLE_COUNT: u32 = 4;
+
+// 光照在全局绑定组里的下标
+const LIGHT_GROUP: usize = 1;
+
 const EMPTY_KEY: &'static str = "Key 不存在于字典中";
@@ -19,6 +19,9 @@ use wgpu::{
 use winit::window::Window;
 
 use crate::utils::*;
+use resource::{
+    pack::PackRegistry, preprocess::preprocess, shader_check, Shader, ShaderData, ShaderType,
+};
 
 use super::*;
 
@@ -79,12 +82,106 @@ where
     Ok(group)
 }
 
+// SPIR-V 直接交给驱动, 需要设备支持 SPIRV_SHADER_PASSTHROUGH
+// WGSL 的编译错误在这里返回, 经过预处理的会把行号换回原文件
 pub fn create_shader_module(
     device: &Device,
     label: Option<&str>,
-    source: ShaderSource,
+    shader: &Shader,
 ) -> Result<ShaderModule> {
-    Ok(device.create_shader_module(ShaderModuleDescriptor { label, source }))
+    match &shader.data {
+        ShaderData::Wgsl(_) => {
+            device.push_error_scope(ErrorFilter::Validation);
+            let module = device.create_shader_module(ShaderModuleDescriptor {
+                label,
+                source: shader.data.as_shader_source()?,
+            });
+            if let Some(e) = pollster::block_on(device.pop_error_scope()) {
+                let msg = e.to_string();
+                let msg = match &shader.source_map {
+                    Some(map) => map.map_message(&msg),
+                    None => msg,
+                };
+                bail!("shader {:?} 编译失败:\n{}", label, msg);
+            }
+            Ok(module)
+        }
+        ShaderData::SpirV(words) => {
+            if !device
+                .features()
+                .contains(Features::SPIRV_SHADER_PASSTHROUGH)
+            {
+                bail!(
+                    "shader {:?} 是 SPIR-V, 但设备不支持 SPIRV_SHADER_PASSTHROUGH",
+                    label
+                );
+            }
+            // words 已经检查过 magic number, 其余的内容由驱动负责
+            Ok(unsafe {
+                device.create_shader_module_spirv(&ShaderModuleDescriptorSpirV {
+                    label,
+                    source: words.into(),
+                })
+            })
+        }
+    }
+}
+
+// 主 pass 的颜色和深度格式, 以及多重采样次数, 画进去的管线都要和它一致
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderTargetFormat {
+    pub color: TextureFormat,
+    pub depth: TextureFormat,
+    // 1 是不开多重采样
+    pub sample_count: u32,
+}
+
+impl RenderTargetFormat {
+    pub fn multisample(&self) -> MultisampleState {
+        MultisampleState {
+            count: self.sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        }
+    }
+}
+
+// 从包里读 shader, 返回 (vs, fs), define 只对 WGSL 有用
+// attrs 是管线的顶点布局, WGSL 的 vertex 输入要和它一致
+pub fn load_shader(
+    registry: &PackRegistry,
+    id: &str,
+    define: Option<&str>,
+    attrs: &[VertexAttribute],
+) -> Result<(Shader, Shader)> {
+    let path = registry
+        .shader_path(id)
+        .ok_or(anyhow!("shader {:?} 未在任何包中声明", id))?;
+    // 按扩展名区分 WGSL 和 SPIR-V, SPIR-V 是编译好的, 没有变体
+    let (vs, fs) = match ShaderType::from_path(path) {
+        ShaderType::Wgsl => {
+            let defines: Vec<_> = define.into_iter().map(|d| (d, "")).collect();
+            let p = preprocess(path, &defines, |p| {
+                Ok(String::from_utf8(registry.read(p)?.into_owned())?)
+            })?;
+            // 在 wgpu 之前检查, 报错能指到原文件的行, 顶点布局不一致也能提前发现
+            shader_check::check_render_wgsl(&p, attrs)
+                .with_context(|| format!("shader {:?} 变体 {:?}", path, define))?;
+            (
+                Shader::from_preprocessed(p.clone(), Shader::VS_FUNC_NAME.to_string()),
+                Shader::from_preprocessed(p, Shader::FS_FUNC_NAME.to_string()),
+            )
+        }
+        ShaderType::SpirV => {
+            let data = ShaderData::from_bytes(ShaderType::SpirV, registry.read(path)?.into_owned())
+                .with_context(|| format!("shader {:?}", path))?;
+            (
+                Shader::new(data.clone(), Shader::VS_FUNC_NAME.to_string()),
+                Shader::new(data, Shader::FS_FUNC_NAME.to_string()),
+            )
+        }
+    };
+    Ok((vs, fs))
 }
 
 pub fn create_pipeline_layout<'a>(
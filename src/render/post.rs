@@ -0,0 +1,344 @@
+// 后处理: 主 pass 画到 HDR 贴图上, 这里做泛光和色调映射, 结果写到 surface
+//
+// 泛光在一半分辨率上做: 先取出超过阈值的亮部, 再横竖各模糊一次, 最后和原图加在一起色调映射.
+// 自发光材质和太阳画出来会超过 1, 主要是它们在泛光.
+use anyhow::{bail, Result};
+use resource::pack::PackRegistry;
+use wgpu::*;
+
+use super::{graph::*, pipeline::*};
+
+pub const HDR_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+const SHADER_ID: &'static str = "base:post";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tonemap {
+    Reinhard = 0,
+    Aces = 1,
+}
+
+// 每帧上传, 直接改就行
+#[derive(Debug, Clone, Copy)]
+pub struct PostConfig {
+    pub tonemap: Tonemap,
+    // 色调映射前乘上去
+    pub exposure: f32,
+    pub bloom: bool,
+    // 只有自发光泛光, 自发光部分亮度超过这个的才算
+    pub bloom_threshold: f32,
+    pub bloom_intensity: f32,
+}
+
+impl Default for PostConfig {
+    fn default() -> Self {
+        Self {
+            tonemap: Tonemap::Aces,
+            exposure: 1.0,
+            bloom: true,
+            bloom_threshold: 1.0,
+            bloom_intensity: 0.6,
+        }
+    }
+}
+
+// 与 shader 里的 Post 对应
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PostUniform {
+    pub exposure: f32,
+    pub tonemap: u32,
+    pub bloom_threshold: f32,
+    pub bloom_intensity: f32,
+}
+
+impl PostConfig {
+    pub fn to_uniform(&self) -> PostUniform {
+        PostUniform {
+            exposure: self.exposure,
+            tonemap: self.tonemap as u32,
+            bloom_threshold: self.bloom_threshold,
+            // 不开泛光时泛光贴图里是上次的内容, 不能加进来
+            bloom_intensity: if self.bloom {
+                self.bloom_intensity
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+// 后处理的每一步, 对应 post.wgsl 的变体, 在渲染图里各是一个 pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Bright,
+    BlurH,
+    BlurV,
+    Tonemap,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Bright, Stage::BlurH, Stage::BlurV, Stage::Tonemap];
+
+    // 不开泛光时跳过
+    pub fn is_bloom(&self) -> bool {
+        *self != Stage::Tonemap
+    }
+
+    fn define(&self) -> &'static str {
+        match self {
+            Stage::Bright => "BRIGHT",
+            Stage::BlurH => "BLUR_H",
+            Stage::BlurV => "BLUR_V",
+            Stage::Tonemap => "TONEMAP",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Stage::Bright => "Post Bright",
+            Stage::BlurH => "Post Blur H",
+            Stage::BlurV => "Post Blur V",
+            Stage::Tonemap => "Post Tonemap",
+        }
+    }
+}
+
+// 后处理在渲染图里的贴图
+#[derive(Debug, Clone, Copy)]
+pub struct PostTextures {
+    // 主 pass 画到这上面, 多重采样时是 resolve 的目标
+    pub hdr: TextureId,
+    // 一半大小, 泛光的几步来回画
+    pub bloom: [TextureId; 2],
+}
+
+#[derive(Debug)]
+pub struct Post {
+    pub config: PostConfig,
+    sampler: Sampler,
+    uniform_buffer: Buffer,
+    layout: BindGroupLayout,
+    pipe_layout: PipelineLayout,
+    // 下标是 Stage, 绑定组在 bind 之后才有
+    pipelines: Vec<RenderPipeline>,
+    groups: Vec<BindGroup>,
+    output_format: TextureFormat,
+}
+
+impl Post {
+    // output_format 是 surface 的格式
+    pub fn new(
+        device: &Device,
+        registry: &PackRegistry,
+        output_format: TextureFormat,
+    ) -> Result<Self> {
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("Post Sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+        let uniform_buffer = create_buffer(
+            device,
+            Some("Post Uniform"),
+            BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            &[PostConfig::default().to_uniform()],
+        );
+        let layout = create_bind_group_layout(
+            device,
+            Some("Post Bind Group Layout"),
+            &Self::get_layout_args(),
+        )?;
+        let pipe_layout = create_pipeline_layout(device, Some("Post Pipeline Layout"), [&layout])?;
+        let pipelines = Self::create_pipelines(device, registry, &pipe_layout, output_format)?;
+        Ok(Self {
+            config: Default::default(),
+            sampler,
+            uniform_buffer,
+            layout,
+            pipe_layout,
+            pipelines,
+            groups: Vec::new(),
+            output_format,
+        })
+    }
+
+    fn get_layout_args() -> [BindGroupLayoutEntryArgs; 4] {
+        let texture = || BindGroupLayoutEntryArgs {
+            visibility: ShaderStages::FRAGMENT,
+            count: None,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+        };
+        [
+            BindGroupLayoutEntryArgs {
+                visibility: ShaderStages::FRAGMENT,
+                count: None,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+            },
+            BindGroupLayoutEntryArgs {
+                visibility: ShaderStages::FRAGMENT,
+                count: None,
+                ty: BindingType::Sampler(SamplerBindingType::Filtering),
+            },
+            texture(),
+            texture(),
+        ]
+    }
+
+    fn create_pipelines(
+        device: &Device,
+        registry: &PackRegistry,
+        pipe_layout: &PipelineLayout,
+        output_format: TextureFormat,
+    ) -> Result<Vec<RenderPipeline>> {
+        let mut pipelines = Vec::new();
+        for stage in Stage::ALL {
+            let (vs, fs) = load_shader(registry, SHADER_ID, Some(stage.define()), &[])?;
+            let vs_module = create_shader_module(device, Some("Post VS"), &vs)?;
+            let fs_module = create_shader_module(device, Some("Post FS"), &fs)?;
+            let format = match stage {
+                Stage::Tonemap => output_format,
+                _ => HDR_FORMAT,
+            };
+            device.push_error_scope(ErrorFilter::Validation);
+            let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+                label: Some(stage.label()),
+                layout: Some(pipe_layout),
+                vertex: VertexState {
+                    module: &vs_module,
+                    entry_point: vs.enter_point(),
+                    buffers: &[],
+                },
+                fragment: Some(FragmentState {
+                    module: &fs_module,
+                    entry_point: fs.enter_point(),
+                    targets: &[Some(ColorTargetState {
+                        format,
+                        blend: Some(BlendState::REPLACE),
+                        write_mask: ColorWrites::ALL,
+                    })],
+                }),
+                primitive: PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: MultisampleState::default(),
+                multiview: None,
+            });
+            if let Some(e) = pollster::block_on(device.pop_error_scope()) {
+                bail!("创建后处理管线 {:?} 失败:\n{}", stage, e);
+            }
+            pipelines.push(pipeline);
+        }
+        Ok(pipelines)
+    }
+
+    pub fn declare<K>(graph: &mut RenderGraph<K>) -> PostTextures {
+        let mut texture = |label, divisor| {
+            graph.declare(TextureDesc {
+                label,
+                format: HDR_FORMAT,
+                size: TextureSize::Surface { divisor },
+                layers: None,
+                sample_count: 1,
+            })
+        };
+        PostTextures {
+            hdr: texture("HDR Texture", 1),
+            bloom: [texture("Bloom Texture 0", 2), texture("Bloom Texture 1", 2)],
+        }
+    }
+
+    // 画到 hdr 的 pass 之后加, 最后一步写到 Output
+    pub fn add_passes<K>(
+        graph: &mut RenderGraph<K>,
+        textures: PostTextures,
+        kind: impl Fn(Stage) -> K,
+    ) -> Result<()> {
+        let PostTextures { hdr, bloom } = textures;
+        for stage in Stage::ALL {
+            let (target, reads) = match stage {
+                Stage::Bright => (Target::Texture(bloom[0]), vec![hdr]),
+                Stage::BlurH => (Target::Texture(bloom[1]), vec![bloom[0]]),
+                Stage::BlurV => (Target::Texture(bloom[0]), vec![bloom[1]]),
+                Stage::Tonemap => (Target::Output, vec![hdr, bloom[0]]),
+            };
+            graph.add_pass(PassDesc {
+                label: stage.label(),
+                kind: kind(stage),
+                color: vec![ColorAttachment {
+                    target,
+                    resolve: None,
+                    // 每个像素都会画到
+                    clear: Some(Color::BLACK),
+                }],
+                depth: None,
+                reads,
+            })?;
+        }
+        Ok(())
+    }
+
+    // 每一步一组: (输入, 泛光), 泛光那一格在前三步里用不到, 放一张这一步不画的贴图
+    // 渲染图重建贴图以后要重新调用
+    pub fn bind<K>(
+        &mut self,
+        device: &Device,
+        graph: &RenderGraph<K>,
+        textures: PostTextures,
+    ) -> Result<()> {
+        let PostTextures { hdr, bloom } = textures;
+        self.groups = Stage::ALL
+            .iter()
+            .map(|stage| {
+                let (src, extra) = match stage {
+                    Stage::Bright => (hdr, bloom[1]),
+                    Stage::BlurH => (bloom[0], hdr),
+                    Stage::BlurV => (bloom[1], hdr),
+                    Stage::Tonemap => (hdr, bloom[0]),
+                };
+                let res = [
+                    self.uniform_buffer.as_entire_binding(),
+                    BindingResource::Sampler(&self.sampler),
+                    BindingResource::TextureView(graph.view(src)?),
+                    BindingResource::TextureView(graph.view(extra)?),
+                ];
+                create_bind_group(device, Some("Post Bind Group"), &self.layout, &res)
+            })
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    // 出错时保持原样
+    pub fn reload_shader(&mut self, device: &Device, registry: &PackRegistry) -> Result<()> {
+        self.pipelines =
+            Self::create_pipelines(device, registry, &self.pipe_layout, self.output_format)?;
+        Ok(())
+    }
+
+    // 每帧画之前调用
+    pub fn write(&self, queue: &Queue) {
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[self.config.to_uniform()]),
+        );
+    }
+
+    // 画到 add_passes 里 stage 对应的 pass 上
+    pub fn draw<'a>(&'a self, render_pass: &mut RenderPass<'a>, stage: Stage) {
+        render_pass.set_pipeline(&self.pipelines[stage as usize]);
+        render_pass.set_bind_group(0, &self.groups[stage as usize], &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
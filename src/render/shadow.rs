@@ -0,0 +1,401 @@
+// 太阳的阴影: 把相机视锥按深度分成几级, 每级从太阳方向拟合一个正交投影, 画进深度贴图数组的一层
+//
+// 每级的范围是包住这一段视锥的球, 只和相机的投影参数有关, 转动相机时不变,
+// 球心再对齐到 texel, 这样相机移动时阴影的边缘不会闪.
+use std::{mem::size_of, num::NonZeroU64};
+
+use anyhow::*;
+use nalgebra::{Isometry3, Matrix4, Orthographic3, Point3, Vector3, Vector4};
+use wgpu::*;
+
+use super::{camera::*, graph::*, pipeline::BindGroupLayoutEntryArgs};
+
+pub const MAX_CASCADES: usize = 4;
+const PASS_LABELS: [&str; MAX_CASCADES] =
+    ["Shadow Pass 0", "Shadow Pass 1", "Shadow Pass 2", "Shadow Pass 3"];
+pub const SHADOW_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+// 球外朝太阳一侧还要包含的距离, 相机后面的方块也能投影进来
+const CASTER_MARGIN: f32 = 64.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowConfig {
+    // 每级贴图的边长
+    pub resolution: u32,
+    // 级数, 1 到 MAX_CASCADES
+    pub cascades: u32,
+    // 阴影覆盖到的视空间深度, 超过相机的 zfar 时取 zfar
+    pub distance: f32,
+    // 分割方式, 0 是均匀分割, 1 是按对数分割, 中间是两者插值
+    pub split_lambda: f32,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            cascades: 3,
+            distance: 64.0,
+            split_lambda: 0.75,
+        }
+    }
+}
+
+impl ShadowConfig {
+    // 每级的远边界, 第一级从相机的 znear 开始
+    pub fn splits(&self, znear: f32, zfar: f32) -> Vec<f32> {
+        let far = self.distance.min(zfar);
+        let n = self.cascades as f32;
+        (1..=self.cascades)
+            .map(|i| {
+                let f = i as f32 / n;
+                let log = znear * (far / znear).powf(f);
+                let uniform = znear + (far - znear) * f;
+                self.split_lambda * log + (1.0 - self.split_lambda) * uniform
+            })
+            .collect()
+    }
+}
+
+// 与 shader 里的 Shadow 对应
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ShadowUniform {
+    pub view_proj: [[[f32; 4]; 4]; MAX_CASCADES],
+    pub splits: [f32; MAX_CASCADES],
+    pub texel_world: [f32; MAX_CASCADES],
+    pub count: u32,
+    pub texel: f32,
+    _pad: [u32; 2],
+}
+
+#[derive(Debug)]
+pub struct Shadow {
+    pub config: ShadowConfig,
+    pub sampler: Sampler,
+    pub uniform: ShadowUniform,
+    pub uniform_buffer: Buffer,
+    // 每级的 view_proj, 间隔 stride, 阴影 pass 里用 dynamic offset 选
+    pub cascade_buffer: Buffer,
+    pub cascade_layout: BindGroupLayout,
+    pub cascade_group: BindGroup,
+    stride: u32,
+}
+
+impl Shadow {
+    pub fn new(device: &Device, config: ShadowConfig) -> Result<Self> {
+        Self::check_config(device, &config)?;
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("Shadow Sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            compare: Some(CompareFunction::LessEqual),
+            ..Default::default()
+        });
+        let uniform_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Shadow Uniform"),
+            size: size_of::<ShadowUniform>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let matrix_size = size_of::<[[f32; 4]; 4]>() as u32;
+        let align = device.limits().min_uniform_buffer_offset_alignment;
+        let stride = matrix_size.div_ceil(align) * align;
+        let cascade_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Shadow Cascade Matrix"),
+            size: (stride * MAX_CASCADES as u32) as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let cascade_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Shadow Cascade Bind Group Layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: NonZeroU64::new(matrix_size as u64),
+                },
+                count: None,
+            }],
+        });
+        let cascade_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Shadow Cascade Bind Group"),
+            layout: &cascade_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::Buffer(BufferBinding {
+                    buffer: &cascade_buffer,
+                    offset: 0,
+                    size: NonZeroU64::new(matrix_size as u64),
+                }),
+            }],
+        });
+
+        Ok(Self {
+            config,
+            sampler,
+            uniform: bytemuck::Zeroable::zeroed(),
+            uniform_buffer,
+            cascade_buffer,
+            cascade_layout,
+            cascade_group,
+            stride,
+        })
+    }
+
+    // 改分辨率或级数要重建渲染图里的贴图, 之后用到它的绑定组也要重建
+    pub fn set_config(&mut self, device: &Device, config: ShadowConfig) -> Result<()> {
+        Self::check_config(device, &config)?;
+        self.config = config;
+        Ok(())
+    }
+
+    fn check_config(device: &Device, config: &ShadowConfig) -> Result<()> {
+        if !(1..=MAX_CASCADES as u32).contains(&config.cascades) {
+            bail!(
+                "阴影级数 {} 不在 1 到 {} 之间",
+                config.cascades,
+                MAX_CASCADES
+            );
+        }
+        let max = device.limits().max_texture_dimension_2d;
+        if config.resolution == 0 || config.resolution > max {
+            bail!("阴影分辨率 {} 不在 1 到 {} 之间", config.resolution, max);
+        }
+        Ok(())
+    }
+
+    // 深度贴图数组, 每级一层
+    pub fn declare<K>(&self, graph: &mut RenderGraph<K>) -> TextureId {
+        graph.declare(TextureDesc {
+            label: "Shadow Map",
+            format: SHADOW_FORMAT,
+            size: TextureSize::Fixed {
+                width: self.config.resolution,
+                height: self.config.resolution,
+            },
+            layers: Some(self.config.cascades),
+            sample_count: 1,
+        })
+    }
+
+    // 每级一个只有深度的 pass, 要在采样 map 的 pass 之前加
+    pub fn add_passes<K>(
+        &self,
+        graph: &mut RenderGraph<K>,
+        map: TextureId,
+        kind: impl Fn(usize) -> K,
+    ) -> Result<()> {
+        for cascade in 0..self.config.cascades {
+            graph.add_pass(PassDesc {
+                label: PASS_LABELS[cascade as usize],
+                kind: kind(cascade as usize),
+                color: vec![],
+                depth: Some(DepthAttachment {
+                    target: Target::Layer(map, cascade),
+                    clear: Some(1.0),
+                }),
+                reads: vec![],
+            })?;
+        }
+        Ok(())
+    }
+
+    // 放在光照的绑定组里: 各级的矩阵和分割, 深度贴图数组, 比较采样器
+    pub fn get_layout_args() -> [BindGroupLayoutEntryArgs; 3] {
+        [
+            BindGroupLayoutEntryArgs {
+                visibility: ShaderStages::FRAGMENT,
+                count: None,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+            },
+            BindGroupLayoutEntryArgs {
+                visibility: ShaderStages::FRAGMENT,
+                count: None,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Depth,
+                    view_dimension: TextureViewDimension::D2Array,
+                    multisampled: false,
+                },
+            },
+            BindGroupLayoutEntryArgs {
+                visibility: ShaderStages::FRAGMENT,
+                count: None,
+                ty: BindingType::Sampler(SamplerBindingType::Comparison),
+            },
+        ]
+    }
+
+    // map 是渲染图里 declare 的那张贴图
+    pub fn get_bind_resource<'a>(&'a self, map: &'a TextureView) -> [BindingResource<'a>; 3] {
+        [
+            self.uniform_buffer.as_entire_binding(),
+            BindingResource::TextureView(map),
+            BindingResource::Sampler(&self.sampler),
+        ]
+    }
+
+    // 每帧画阴影之前调用, light_dir 从地面指向太阳
+    pub fn update(&mut self, queue: &Queue, camera: &Camera, light_dir: &Vector3<f32>) {
+        self.uniform = self.fit(camera, light_dir);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&self.uniform));
+        for (i, m) in self.uniform.view_proj[..self.uniform.count as usize]
+            .iter()
+            .enumerate()
+        {
+            let offset = self.cascade_offset(i) as u64;
+            queue.write_buffer(&self.cascade_buffer, offset, bytemuck::bytes_of(m));
+        }
+    }
+
+    // 相机的矩阵不可逆时 count 为 0, shader 里就当作没有阴影
+    pub fn fit(&self, camera: &Camera, light_dir: &Vector3<f32>) -> ShadowUniform {
+        let mut uniform: ShadowUniform = bytemuck::Zeroable::zeroed();
+        let corners = match frustum_corners(camera) {
+            Some(c) => c,
+            None => return uniform,
+        };
+        let light_dir = light_dir.normalize();
+        let mut near = camera.znear;
+        for (i, far) in self
+            .config
+            .splits(camera.znear, camera.zfar)
+            .into_iter()
+            .enumerate()
+        {
+            let slice = frustum_slice(&corners, camera, near, far);
+            let (view_proj, texel_world) = fit_cascade(&slice, &light_dir, self.config.resolution);
+            uniform.view_proj[i] = view_proj.into();
+            uniform.splits[i] = far;
+            uniform.texel_world[i] = texel_world;
+            near = far;
+        }
+        uniform.count = self.config.cascades;
+        uniform.texel = 1.0 / self.config.resolution as f32;
+        uniform
+    }
+
+    pub fn cascade_offset(&self, cascade: usize) -> u32 {
+        self.stride * cascade as u32
+    }
+
+}
+
+// 相机视锥的 8 个角, 世界坐标, 前 4 个在近平面, 后 4 个在远平面
+fn frustum_corners(camera: &Camera) -> Option<[Point3<f32>; 8]> {
+    let inv = (camera.proj_matrix * camera.view_matrix).try_inverse()?;
+    let mut corners = [Point3::origin(); 8];
+    for (i, c) in corners.iter_mut().enumerate() {
+        let x = if i & 1 == 0 { -1.0 } else { 1.0 };
+        let y = if i & 2 == 0 { -1.0 } else { 1.0 };
+        let z = if i & 4 == 0 { 0.0 } else { 1.0 };
+        let p = inv * Vector4::new(x, y, z, 1.0);
+        *c = Point3::from(p.xyz() / p.w);
+    }
+    Some(corners)
+}
+
+// 视空间深度 near 到 far 之间的一段, 视锥的棱是直线, 深度沿棱线性变化
+fn frustum_slice(
+    corners: &[Point3<f32>; 8],
+    camera: &Camera,
+    near: f32,
+    far: f32,
+) -> [Point3<f32>; 8] {
+    let t = |d: f32| (d - camera.znear) / (camera.zfar - camera.znear);
+    let mut slice = [Point3::origin(); 8];
+    for i in 0..4 {
+        let (a, b) = (corners[i], corners[i + 4]);
+        slice[i] = a + (b - a) * t(near);
+        slice[i + 4] = a + (b - a) * t(far);
+    }
+    slice
+}
+
+// 返回这一级的 view_proj 和一个 texel 在世界空间的大小
+fn fit_cascade(
+    slice: &[Point3<f32>; 8],
+    light_dir: &Vector3<f32>,
+    resolution: u32,
+) -> (Matrix4<f32>, f32) {
+    let center = slice.iter().map(|p| p.coords).sum::<Vector3<f32>>() / 8.0;
+    let radius = slice
+        .iter()
+        .map(|p| (p.coords - center).norm())
+        .fold(0.0, f32::max);
+    // 四周各留一个 texel, 对齐移动了球心也还能包住
+    let texel = radius * 2.0 / resolution.max(3).saturating_sub(2) as f32;
+    let half = texel * resolution as f32 / 2.0;
+
+    // 只有旋转的光源视空间, 在这里把球心对齐到 texel
+    let up = if light_dir.y.abs() > 0.99 {
+        Vector3::z()
+    } else {
+        Vector3::y()
+    };
+    let view = Isometry3::look_at_rh(&Point3::origin(), &Point3::from(-light_dir), &up);
+    let c = view * Point3::from(center);
+    let (x, y) = ((c.x / texel).floor() * texel, (c.y / texel).floor() * texel);
+    // 视空间看向 -z, 深度是 -z
+    let proj = Orthographic3::new(
+        x - half,
+        x + half,
+        y - half,
+        y + half,
+        -c.z - radius - CASTER_MARGIN,
+        -c.z + radius,
+    );
+    (
+        opengl_to_wgpu() * proj.to_homogeneous() * view.to_homogeneous(),
+        texel,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cascades_cover_frustum() {
+        let mut camera = Camera {
+            position: Point3::new(3.0, 5.0, -2.0),
+            direction: Vector3::new(1.0, -0.3, 0.5).normalize(),
+            ..Default::default()
+        };
+        camera.calculate();
+        let config = ShadowConfig::default();
+        let splits = config.splits(camera.znear, camera.zfar);
+        assert_eq!(splits.len(), 3);
+        assert!(splits.windows(2).all(|w| w[0] < w[1]));
+        assert!((splits[2] - config.distance).abs() < 1e-3);
+
+        let light_dir = Vector3::new(0.3, 0.8, -0.2).normalize();
+        let corners = frustum_corners(&camera).unwrap();
+        let mut near = camera.znear;
+        for far in splits {
+            let slice = frustum_slice(&corners, &camera, near, far);
+            let (m, _) = fit_cascade(&slice, &light_dir, config.resolution);
+            // 每一段视锥都在这一级的裁剪空间里
+            for p in slice {
+                let q = m * p.to_homogeneous();
+                let q = q.xyz() / q.w;
+                assert!(
+                    q.x.abs() <= 1.0 + 1e-3 && q.y.abs() <= 1.0 + 1e-3,
+                    "{:?}",
+                    q
+                );
+                assert!((-1e-3..=1.0 + 1e-3).contains(&q.z), "{:?}", q);
+            }
+            near = far;
+        }
+    }
+}
@@ -8,7 +8,7 @@ use std::{
 };
 
 use anyhow::*;
-use image::DynamicImage;
+use image::{DynamicImage, RgbaImage};
 use memoffset::offset_of;
 use nalgebra::{Isometry3, Matrix4, Perspective3, Point3, Projective3, Vector3};
 use once_cell::sync::OnceCell;
@@ -88,3 +88,79 @@ impl TextureArgs {
         }
     }
 }
+
+// 把 texture 的第 0 级读回内存, 只支持 rgba8 和 bgra8, bgra 会换成 rgba
+// 会等 GPU 执行完, 不要每帧调用
+pub fn read_to_image(
+    device: &Device,
+    queue: &Queue,
+    texture: &Texture,
+    width: u32,
+    height: u32,
+    format: TextureFormat,
+) -> Result<RgbaImage> {
+    let is_bgra = match format {
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => false,
+        TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => true,
+        _ => bail!("不支持读回 {:?} 格式的贴图", format),
+    };
+    // 复制到 buffer 时每行要按 COPY_BYTES_PER_ROW_ALIGNMENT 对齐
+    let row_bytes = width * 4;
+    let padded_row_bytes = (row_bytes + COPY_BYTES_PER_ROW_ALIGNMENT - 1)
+        / COPY_BYTES_PER_ROW_ALIGNMENT
+        * COPY_BYTES_PER_ROW_ALIGNMENT;
+    let buffer = device.create_buffer(&BufferDescriptor {
+        label: Some("Readback Buffer"),
+        size: padded_row_bytes as u64 * height as u64,
+        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("Readback Encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: Origin3d::ZERO,
+            aspect: TextureAspect::All,
+        },
+        ImageCopyBuffer {
+            buffer: &buffer,
+            layout: ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(padded_row_bytes),
+                rows_per_image: None,
+            },
+        },
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+    queue.submit(once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let (tx, rx) = std::sync::mpsc::channel();
+    slice.map_async(MapMode::Read, move |r| {
+        let _ = tx.send(r);
+    });
+    device.poll(Maintain::Wait);
+    rx.recv()?.context("读回贴图失败")?;
+
+    let mut data = Vec::with_capacity((row_bytes * height) as usize);
+    for row in slice
+        .get_mapped_range()
+        .chunks_exact(padded_row_bytes as usize)
+    {
+        data.extend_from_slice(&row[..row_bytes as usize]);
+    }
+    buffer.unmap();
+    if is_bgra {
+        for p in data.chunks_exact_mut(4) {
+            p.swap(0, 2);
+        }
+    }
+    RgbaImage::from_raw(width, height, data).ok_or(anyhow!("读回的数据大小不对"))
+}
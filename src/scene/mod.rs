@@ -1,25 +1,46 @@
 use std::time::Instant;
 
+use crate::logic::orient::{Code, CompressedData, Orient, ORIENT_CODE_COUNT};
 use crate::render::{built_in::cube, camera::Camera, RenderState};
 use anyhow::*;
 use nalgebra::Vector3;
+use resource::pack::{BlockId, PackRegistry};
 use winit::window::Window;
 
+pub mod save;
+
 pub struct Scene {
     pub cubes: cube::Mesh,
 }
 impl Scene {
-    pub fn init(render: &mut RenderState) -> Result<Self> {
+    pub fn init(render: &mut RenderState, registry: &PackRegistry) -> Result<Self> {
         let mut cubes = render.cube_pipeline.new_cube_mesh(&render.device)?;
-        let r = 2;
+        if registry.blocks.is_empty() {
+            bail!("没有任何方块类型");
+        }
+        let blocks = registry.blocks.len() as i32;
+        let r: i32 = 2;
         for x in -r..r {
             for y in -r..r {
                 for z in -r..r {
                     let pos = Vector3::new(x as f32, y as f32, z as f32) * 3f32;
-                    cubes.add_cube(pos);
+                    let block = (x + y + z).rem_euclid(blocks) as BlockId;
+                    let block = registry.blocks.get(block).unwrap();
+                    // 测试用, 各种方向都摆一下
+                    let code = (x * 16 + y * 4 + z).rem_euclid(2 * ORIENT_CODE_COUNT as i32);
+                    let orient = Orient::<CompressedData>::decode(code as Code).uncompress();
+                    cubes.add_block(pos, &block.faces, &orient);
                 }
             }
         }
+        // 地面, 和上面落在网格上的方块挨着, 能看出 AO 和阴影
+        let ground = registry.blocks.get(0).unwrap();
+        for x in -4..4 {
+            for z in -4..4 {
+                let pos = Vector3::new(x as f32, -4.0, z as f32) * cube::BLOCK_SIZE;
+                cubes.add_block(pos, &ground.faces, &Orient::identity());
+            }
+        }
         Ok(Scene { cubes })
     }
 }
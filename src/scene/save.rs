@@ -0,0 +1,67 @@
+// 世界存档, 现在只有环境 (时刻等), 方块和玩家以后再加
+use std::{fs, path::Path};
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+
+use crate::render::environment::Environment;
+
+// 缺的字段用默认值, 老的存档也能读
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldSave {
+    pub environment: Environment,
+}
+
+impl WorldSave {
+    // 文件不存在时是新世界, 返回默认值
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = fs::read_to_string(path).with_context(|| format!("读取 {:?} 失败", path))?;
+        toml::from_str(&text).with_context(|| format!("解析 {:?} 失败", path))
+    }
+
+    // 先写到临时文件再改名, 写到一半退出也不会弄坏原来的存档
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("创建 {:?} 失败", dir))?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, toml::to_string_pretty(self)?)
+            .with_context(|| format!("写入 {:?} 失败", tmp))?;
+        fs::rename(&tmp, path).with_context(|| format!("保存 {:?} 失败", path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join(format!("cubescript2_save_{}", std::process::id()));
+        let path = dir.join("world.toml");
+        assert_eq!(WorldSave::load(&path).unwrap(), WorldSave::default());
+
+        let mut save = WorldSave::default();
+        save.environment.set_time(20.5);
+        save.environment.day_length = 0.0;
+        save.save(&path).unwrap();
+        assert_eq!(WorldSave::load(&path).unwrap(), save);
+
+        // 只写了一部分的也能读
+        fs::write(&path, "[environment]\ntime = 7.0\n").unwrap();
+        let loaded = WorldSave::load(&path).unwrap();
+        assert_eq!(loaded.environment.time, 7.0);
+        assert_eq!(
+            loaded.environment.day_length,
+            Environment::default().day_length
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
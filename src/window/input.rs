@@ -184,10 +184,21 @@ impl Input {
 pub struct InputAction {
     wasd_hold: Vec<KeyInput>,
     pos_move: Vector3<f32>,
+    screenshot: bool,
+    toggle_capture: bool,
+    cycle_msaa: bool,
+    time_scrub: f32,
 }
 
 impl InputAction {
     pub fn update(&mut self, input: &Input) {
+        self.screenshot = input.is_just_pressed(VirtualKeyCode::F2);
+        self.toggle_capture = input.is_just_pressed(VirtualKeyCode::F3);
+        self.cycle_msaa = input.is_just_pressed(VirtualKeyCode::F4);
+        // 左右方向键调时刻, 按住时持续调
+        self.time_scrub = input.is_pressed(VirtualKeyCode::Right) as i32 as f32
+            - input.is_pressed(VirtualKeyCode::Left) as i32 as f32;
+
         // wasd 排序
         let wasd: [KeyInput; 6] = [
             VirtualKeyCode::W.into(),
@@ -260,4 +271,24 @@ impl InputAction {
     pub fn get_move(&self) -> Vector3<f32> {
         self.pos_move
     }
+
+    // F2 截图
+    pub fn screenshot(&self) -> bool {
+        self.screenshot
+    }
+
+    // F3 开始记录相机路径, 再按一次停止, 然后按路径录制图片序列
+    pub fn toggle_capture(&self) -> bool {
+        self.toggle_capture
+    }
+
+    // F4 切换到下一个支持的多重采样次数
+    pub fn cycle_msaa(&self) -> bool {
+        self.cycle_msaa
+    }
+
+    // -1 倒退, 0 不动, 1 快进
+    pub fn get_time_scrub(&self) -> f32 {
+        self.time_scrub
+    }
 }
@@ -1,8 +1,8 @@
-use std::{collections::HashSet, default, ops::ControlFlow, time::*};
+use std::{collections::HashSet, default, ops::ControlFlow, path::Path, time::*};
 
-use anyhow::{Result, Ok};
+use anyhow::Result;
 use bitmaps::Bitmap;
-use log::info;
+use log::{error, info, warn};
 use nalgebra::{Point2, Rotation3, Vector2};
 use winit::{
     dpi::{PhysicalPosition, PhysicalSize},
@@ -11,11 +11,33 @@ use winit::{
     window::{Window, WindowBuilder},
 };
 
-use crate::{render::{camera::Camera, RenderState}, scene::Scene};
+use resource::pack::PackRegistry;
+
+use crate::{
+    render::{
+        built_in::cube,
+        camera::Camera,
+        capture::{self, CameraPath, Capture},
+        RenderState,
+    },
+    scene::{save::WorldSave, Scene},
+};
+#[cfg(debug_assertions)]
+use crate::render::hot_reload::HotReload;
 
 pub mod input;
 use input::*;
 
+// 有包目录就用包目录 (开发时), 否则用打好的资源包文件 (发布时)
+fn load_registry() -> Result<PackRegistry> {
+    if Path::new(PACK_DIR).is_dir() {
+        PackRegistry::load_dir(PACK_DIR)
+    } else {
+        info!("{:?} 不存在, 从 {:?} 加载", PACK_DIR, PACK_BUNDLE);
+        PackRegistry::load_bundle(PACK_BUNDLE)
+    }
+}
+
 pub async fn run() -> Result<()> {
     let event_loop = event_loop::EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop)?;
@@ -25,10 +47,23 @@ pub async fn run() -> Result<()> {
     let mut input = Input::default();
     let mut input_action = InputAction::default();
     let mut camera = create_camera(&window);
-    let mut render = RenderState::init(&window, &camera).await?;
-    let mut scene = Scene::init(&mut render)?;
+    let registry = load_registry()?;
+    let mut render = RenderState::init(&window, &camera, &registry).await?;
+    let mut scene = Scene::init(&mut render, &registry)?;
+    let save = WorldSave::load(WORLD_SAVE)?;
+    render.environment = save.environment;
     let size = window.inner_size();
     render.resize(&mut camera, size.width, size.height)?;
+    // 只在开发时监视, 失败了也不影响运行
+    #[cfg(debug_assertions)]
+    let mut hot_reload = HotReload::new(&[Path::new(PACK_DIR), Path::new(cube::ATLAS_DIR)])
+        .map_err(|e| warn!("热重载不可用: {:?}", e))
+        .ok();
+
+    // 正在记录的相机路径, 和开始记录的时间
+    let mut recording: Option<(Instant, CameraPath)> = None;
+    // 正在按路径录制的图片序列
+    let mut capturing: Option<Capture> = None;
 
     event_loop.run(move |event, target, mut control| {
         match &event {
@@ -36,6 +71,16 @@ pub async fn run() -> Result<()> {
                 winit::event::WindowEvent::CloseRequested => control.set_exit(),
                 _ => {}
             },
+            // 退出前保存, 失败了也只能记日志
+            Event::LoopDestroyed => {
+                let save = WorldSave {
+                    environment: render.environment.clone(),
+                };
+                match save.save(WORLD_SAVE) {
+                    Ok(()) => info!("世界保存到 {:?}", WORLD_SAVE),
+                    Err(e) => error!("保存世界失败: {:?}", e),
+                }
+            }
             _ => {}
         };
 
@@ -70,6 +115,11 @@ pub async fn run() -> Result<()> {
                         .duration_since(last_time.expect("last_time未设置"))
                         .as_secs_f32();
 
+                    #[cfg(debug_assertions)]
+                    if let Some(changes) = hot_reload.as_mut().and_then(|h| h.poll()) {
+                        render.hot_reload(&registry, &changes);
+                    }
+
                     // 更新逻辑输入动作
                     input_action.update(&input);
 
@@ -101,10 +151,72 @@ pub async fn run() -> Result<()> {
                         camera.calculate();
                     }
 
+                    // 时间流逝, 按住方向键快进或倒退
+                    {
+                        let scrub_speed = 3f32;
+                        let hours = dt * scrub_speed * input_action.get_time_scrub();
+                        let env = &mut render.environment;
+                        env.set_time(env.time + hours);
+                        env.advance(dt);
+                    }
+
+                    if input_action.cycle_msaa() {
+                        let counts = render.supported_sample_counts();
+                        let i = counts.iter().position(|c| *c == render.sample_count());
+                        let next = counts[i.map_or(0, |i| (i + 1) % counts.len())];
+                        match render.set_sample_count(&registry, next) {
+                            Ok(()) => info!("多重采样: {} 倍", next),
+                            Err(e) => error!("切换多重采样失败: {:?}", e),
+                        }
+                    }
+
+                    // 记录相机路径, 停止时开始录制
+                    if input_action.toggle_capture() && capturing.is_none() {
+                        match recording.take() {
+                            None => {
+                                info!("开始记录相机路径, 再按一次停止并录制");
+                                recording = Some((Instant::now(), CameraPath::default()));
+                            }
+                            Some((_, path)) => {
+                                let dir = Path::new(CAPTURE_DIR).join(capture::timestamp());
+                                match Capture::new(path, &dir, CAPTURE_FPS) {
+                                    Ok(c) => {
+                                        info!("录制 {} 帧到 {:?}", c.frame_count(), dir);
+                                        capturing = Some(c);
+                                    }
+                                    Err(e) => error!("无法录制: {:?}", e),
+                                }
+                            }
+                        }
+                    }
+                    if let Some((start, path)) = &mut recording {
+                        path.record(start.elapsed().as_secs_f32(), &camera);
+                    }
+
                     window.request_redraw();
                 }
                 Event::RedrawRequested(wid) => {
+                    // 录制时每次重绘画一帧, 相机由路径决定
+                    if let Some(c) = &mut capturing {
+                        match c.next_frame(&mut render, &mut camera, &mut scene) {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                info!("录制完成: {:?}", c.dir);
+                                capturing = None;
+                            }
+                            Err(e) => {
+                                error!("录制失败: {:?}", e);
+                                capturing = None;
+                            }
+                        }
+                    }
                     render.redraw(&camera, &mut scene);
+                    if input_action.screenshot() {
+                        match capture::screenshot(&mut render, &camera, &mut scene, SCREENSHOT_DIR) {
+                            Ok(path) => info!("截图保存到 {:?}", path),
+                            Err(e) => error!("截图失败: {:?}", e),
+                        }
+                    }
                 }
                 Event::RedrawEventsCleared => {}
                 _ => {}
@@ -113,6 +225,14 @@ pub async fn run() -> Result<()> {
     })
 }
 
+const PACK_DIR: &'static str = "pack";
+const PACK_BUNDLE: &'static str = "pack.bundle";
+const SCREENSHOT_DIR: &'static str = "screenshots";
+const WORLD_SAVE: &'static str = "world/world.toml";
+// 每次录制是这里面的一个子目录
+const CAPTURE_DIR: &'static str = "captures";
+const CAPTURE_FPS: f32 = 30.0;
+
 fn create_camera(window: &Window) -> Camera {
     let mut camera = Camera::default();
     let size = window.inner_size();